@@ -0,0 +1,80 @@
+//! Compares fanning messages out over a single broadcast channel, where every
+//! subscriber receives and filters every message, against one channel per
+//! topic, where subscribers only receive the topic they are subscribed to.
+
+use std::collections::HashMap;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tokio::sync::broadcast;
+
+const SUBSCRIBERS: usize = 1000;
+const MESSAGES: usize = 100;
+
+fn topic(i: usize, topics: usize) -> String {
+    format!("topic/{}", i % topics)
+}
+
+/// Returns the number of messages handled by subscribers, matching or not.
+fn single_channel(topics: usize) -> usize {
+    let (tx, _) = broadcast::channel(MESSAGES);
+    let mut subscribers: Vec<_> = (0..SUBSCRIBERS)
+        .map(|i| (topic(i, topics), tx.subscribe()))
+        .collect();
+    for i in 0..MESSAGES {
+        let _ = tx.send(topic(i, topics));
+    }
+    let mut handled = 0;
+    for (topic, receiver) in &mut subscribers {
+        while let Ok(msg) = receiver.try_recv() {
+            handled += 1;
+            criterion::black_box(&msg == topic);
+        }
+    }
+    handled
+}
+
+fn per_topic_channels(topics: usize) -> usize {
+    let channels: HashMap<_, _> = (0..topics)
+        .map(|i| (topic(i, topics), broadcast::channel(MESSAGES).0))
+        .collect();
+    let mut subscribers: Vec<_> = (0..SUBSCRIBERS)
+        .map(|i| channels[&topic(i, topics)].subscribe())
+        .collect();
+    for i in 0..MESSAGES {
+        let topic = topic(i, topics);
+        let _ = channels[&topic].send(topic);
+    }
+    let mut handled = 0;
+    for receiver in &mut subscribers {
+        while let Ok(msg) = receiver.try_recv() {
+            handled += 1;
+            criterion::black_box(msg);
+        }
+    }
+    handled
+}
+
+fn fanout(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fanout");
+    for topics in [1, 10, 100] {
+        println!(
+            "{topics} topics: {} messages handled with a single channel, {} with per-topic channels",
+            single_channel(topics),
+            per_topic_channels(topics)
+        );
+        group.bench_with_input(
+            BenchmarkId::new("single_channel", topics),
+            &topics,
+            |b, &topics| b.iter(|| single_channel(topics)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("per_topic_channels", topics),
+            &topics,
+            |b, &topics| b.iter(|| per_topic_channels(topics)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
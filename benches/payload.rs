@@ -0,0 +1,77 @@
+//! Compares broadcasting a 1 MB payload to 100 subscribers as an owned
+//! `String`, which the channel clones for every receiver, against an
+//! `Arc<str>`, which only clones the pointer.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use tokio::sync::broadcast;
+
+const PAYLOAD_BYTES: usize = 1024 * 1024;
+const SUBSCRIBERS: usize = 100;
+
+/// Counts the bytes allocated through the global allocator.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn fan_out<T: Clone>(payload: T) -> usize {
+    let (tx, _) = broadcast::channel(1);
+    let mut receivers: Vec<_> = (0..SUBSCRIBERS).map(|_| tx.subscribe()).collect();
+    let _ = tx.send(payload);
+    let mut received = 0;
+    for receiver in &mut receivers {
+        if let Ok(payload) = receiver.try_recv() {
+            criterion::black_box(payload);
+            received += 1;
+        }
+    }
+    received
+}
+
+fn allocated_by(f: impl FnOnce()) -> usize {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    f();
+    ALLOCATED.load(Ordering::Relaxed) - before
+}
+
+fn payload(c: &mut Criterion) {
+    let text = "x".repeat(PAYLOAD_BYTES);
+    let shared: Arc<str> = text.clone().into();
+    println!(
+        "bytes allocated fanning out to {SUBSCRIBERS} subscribers: {} as String, {} as Arc<str>",
+        allocated_by(|| {
+            fan_out(text.clone());
+        }),
+        allocated_by(|| {
+            fan_out(shared.clone());
+        }),
+    );
+    let mut group = c.benchmark_group("payload");
+    group.bench_function("string", |b| b.iter(|| fan_out(text.clone())));
+    group.bench_function("arc_str", |b| b.iter(|| fan_out(shared.clone())));
+    group.finish();
+}
+
+criterion_group!(benches, payload);
+criterion_main!(benches);
@@ -0,0 +1,866 @@
+//! Authenticating publishers, subscribers and admins, and the errors
+//! requests are rejected with.
+
+use anyhow::Context;
+use axum::{
+    async_trait,
+    extract::{ws::Message, ConnectInfo, FromRequestParts, Query, State},
+    http::{header, request::Parts, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json, TypedHeader,
+};
+use dashmap::DashMap;
+use headers::{
+    authorization::{Basic, Bearer},
+    Authorization,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    convert::Infallible,
+    hash::BuildHasher,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    config::{read_secret, Config},
+    pubsub::{Scope, SharedState},
+};
+
+/// How long `/ready` reuses the outcome of probing `AUTH_URL`, so that load
+/// balancer health checks do not hammer the auth service.
+pub(crate) const AUTH_PROBE_TTL: Duration = Duration::from_secs(5);
+
+/// Publisher credentials, either one password shared by every publisher name or
+/// a password per publisher name.
+pub(crate) enum Credentials {
+    Shared(String),
+    PerPublisher(HashMap<String, String>),
+    /// Accepts any password, and publishes without credentials as `anonymous`.
+    /// Only for local development with `DEV_MODE`.
+    Disabled,
+}
+
+impl Credentials {
+    /// Uses the `credentials` pairs or a JSON object of the form
+    /// `{"user": "pass"}` from the file at `credentials_path`, falling back to
+    /// the single password from `password_file` or `password`.
+    pub(crate) fn from_config(config: &Config) -> anyhow::Result<Self> {
+        if config.dev_mode.unwrap_or(false) {
+            // A server bound to loopback only cannot be reached from other
+            // machines, which keeps an accidental DEV_MODE from opening it up.
+            anyhow::ensure!(
+                config.ip.iter().flatten().all(IpAddr::is_loopback),
+                "DEV_MODE requires IP to only contain loopback addresses"
+            );
+            tracing::warn!(
+                "DEV_MODE is enabled: publishers are NOT authenticated, anyone who can reach \
+                 this server can publish"
+            );
+            return Ok(Self::Disabled);
+        }
+        if let Some(credentials) = &config.credentials {
+            return Ok(Self::PerPublisher(credentials.clone()));
+        }
+        if let Some(path) = &config.credentials_path {
+            let file = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read credentials from `{path}`"))?;
+            let credentials = serde_json::from_str(&file)
+                .with_context(|| format!("failed to parse credentials in `{path}`"))?;
+            return Ok(Self::PerPublisher(credentials));
+        }
+        let password = match (&config.password_file, &config.password) {
+            (Some(path), _) => read_secret(path)?,
+            (None, Some(password)) => password.clone(),
+            // Publishers can only authenticate with their bearer tokens.
+            (None, None) if config.publisher_tokens.is_some() => {
+                return Ok(Self::PerPublisher(HashMap::new()))
+            }
+            (None, None) => anyhow::bail!(
+                "one of PASSWORD, PASSWORD_FILE, CREDENTIALS, CREDENTIALS_PATH or \
+                 PUBLISHER_TOKENS must be set"
+            ),
+        };
+        Ok(Self::Shared(password))
+    }
+
+    fn verify(&self, username: &str, password: &str) -> bool {
+        match self {
+            Self::Shared(expected) => constant_time_eq(password, expected),
+            Self::Disabled => true,
+            Self::PerPublisher(credentials) => match credentials.get(username) {
+                Some(expected) => constant_time_eq(password, expected),
+                // Spends the same time on unknown usernames as on wrong passwords.
+                None => {
+                    std::hint::black_box(constant_time_eq(password, ""));
+                    false
+                }
+            },
+        }
+    }
+}
+
+/// Compares a provided secret with the expected one without short-circuiting, so
+/// that response times do not reveal how much of it was right. Both sides are
+/// hashed first, which also hides the length of the expected secret.
+fn constant_time_eq(provided: &str, expected: &str) -> bool {
+    let provided = Sha256::digest(provided.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    provided.ct_eq(&expected).into()
+}
+
+/// A token bucket rate limiter keyed by publisher name.
+pub(crate) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: DashMap<String, TokenBucket>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Takes a token for `key`, or returns how long to wait until one is available.
+    pub(crate) fn acquire(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut bucket = self
+            .buckets
+            .entry(key.to_string())
+            .or_insert_with(|| TokenBucket {
+                tokens: self.burst,
+                updated: now,
+            });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Drops buckets that have refilled completely, since they behave exactly
+    /// like a fresh bucket.
+    pub(crate) fn prune(&self) {
+        let now = Instant::now();
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * self.rate < self.burst
+        });
+    }
+}
+
+/// Verifies subscriber bearer tokens as JWTs locally instead of asking `AUTH_URL`.
+pub(crate) struct JwtVerifier {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    /// Subscriber the token was issued to.
+    sub: Option<String>,
+    /// Topic patterns the token holder may subscribe to. Unrestricted if absent.
+    topics: Option<Vec<String>>,
+}
+
+impl JwtVerifier {
+    /// Uses an HS256 secret from `jwt_secret_file` or `jwt_secret`, or an RS256
+    /// public key from `jwt_public_key`, given either as PEM or as a path to a
+    /// PEM file.
+    pub(crate) fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let secret = match &config.jwt_secret_file {
+            Some(path) => Some(read_secret(path)?),
+            None => config.jwt_secret.clone(),
+        };
+        if let Some(secret) = secret {
+            return Ok(Some(Self {
+                key: DecodingKey::from_secret(secret.as_bytes()),
+                validation: Validation::new(Algorithm::HS256),
+            }));
+        }
+        if let Some(public_key) = &config.jwt_public_key {
+            let pem = if public_key.starts_with("-----BEGIN") {
+                public_key.clone()
+            } else {
+                std::fs::read_to_string(public_key)
+                    .with_context(|| format!("failed to read JWT public key from `{public_key}`"))?
+            };
+            return Ok(Some(Self {
+                key: DecodingKey::from_rsa_pem(pem.as_bytes())
+                    .context("failed to parse JWT_PUBLIC_KEY")?,
+                validation: Validation::new(Algorithm::RS256),
+            }));
+        }
+        Ok(None)
+    }
+
+    /// Checks the token's signature and expiry.
+    fn verify(&self, token: &str) -> Result<Claims, AuthError> {
+        jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|_| AuthError::WrongCredentials)
+    }
+}
+
+/// Remembers recent `AUTH_URL` verdicts per bearer token, as the granted scope
+/// or `None` for a rejection. Tokens are only
+/// stored as hashes, and rejections are kept for a shorter time than approvals so
+/// that a token which just became valid is not locked out for long.
+pub(crate) struct AuthCache {
+    ttl: Duration,
+    hasher: RandomState,
+    entries: DashMap<u64, (Option<Scope>, Instant)>,
+}
+
+impl AuthCache {
+    const MAX_NEGATIVE_TTL: Duration = Duration::from_secs(5);
+
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            hasher: RandomState::new(),
+            entries: DashMap::new(),
+        }
+    }
+
+    fn key(&self, token: &str) -> u64 {
+        self.hasher.hash_one(token)
+    }
+
+    fn get(&self, token: &str) -> Option<Option<Scope>> {
+        let key = self.key(token);
+        let (scope, expires) = self.entries.get(&key)?.clone();
+        if expires > Instant::now() {
+            Some(scope)
+        } else {
+            self.entries.remove(&key);
+            None
+        }
+    }
+
+    fn insert(&self, token: &str, scope: Option<Scope>) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let ttl = if scope.is_some() {
+            self.ttl
+        } else {
+            self.ttl.min(Self::MAX_NEGATIVE_TTL)
+        };
+        self.entries
+            .insert(self.key(token), (scope, Instant::now() + ttl));
+    }
+
+    pub(crate) fn prune(&self) {
+        let now = Instant::now();
+        self.entries.retain(|_, (_, expires)| *expires > now);
+    }
+}
+
+/// Rejects publishes from outside `PUB_ALLOW_CIDRS` before their body is read.
+pub(crate) async fn authorize_publisher_source<B>(
+    State(state): State<Arc<SharedState>>,
+    ClientAddr(addr): ClientAddr,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, AuthError> {
+    state.authorize_source(addr.ip())?;
+    Ok(next.run(request).await)
+}
+
+/// The address of the client, which is read from the forwarding headers of
+/// trusted proxies if `TRUST_PROXY` is enabled, see
+/// [`SharedState::client_addr`].
+pub(crate) struct ClientAddr(pub(crate) SocketAddr);
+
+#[async_trait]
+impl FromRequestParts<Arc<SharedState>> for ClientAddr {
+    type Rejection = <ConnectInfo<SocketAddr> as FromRequestParts<Arc<SharedState>>>::Rejection;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<SharedState>,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state).await?;
+        Ok(Self(state.client_addr(peer, &parts.headers)))
+    }
+}
+
+/// The client addresses listed by the `Forwarded` header, or the
+/// `X-Forwarded-For` header without it, from the original client to the last
+/// proxy. Hops that are not an IP address, like obfuscated identifiers, are
+/// `None`.
+pub(crate) fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .flat_map(|value| value.to_str().unwrap_or("_").split(','))
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values(header::FORWARDED.as_str());
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                let hop = element.split(';').find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    key.trim().eq_ignore_ascii_case("for").then_some(value)
+                });
+                hop.and_then(parse_hop)
+            })
+            .collect();
+    }
+    values("x-forwarded-for")
+        .into_iter()
+        .map(parse_hop)
+        .collect()
+}
+
+/// Parses a forwarded client address, which may carry a port and, for IPv6,
+/// brackets and quotes.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    hop.parse()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .or_else(|| hop.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+/// Checks basic auth credentials against `ADMIN_PASSWORD` with the username
+/// `admin`.
+pub(crate) fn authenticate_admin(
+    state: &SharedState,
+    server_info: Option<TypedHeader<headers::Authorization<headers::authorization::Basic>>>,
+) -> Result<(), AuthError> {
+    let Some(TypedHeader(provided)) = server_info else {
+        return Err(state.metrics.auth_failure(AuthError::MissingCredentials));
+    };
+    let expected = state.admin_password.as_deref().unwrap_or_default();
+    // Both checks run so that a wrong username takes as long as a wrong password.
+    let username_ok = constant_time_eq(provided.username(), "admin");
+    let password_ok = constant_time_eq(provided.password(), expected);
+    if username_ok & password_ok {
+        Ok(())
+    } else {
+        Err(state.metrics.auth_failure(AuthError::WrongCredentials))
+    }
+}
+
+/// The publisher's credentials from the `Authorization` header, which may use
+/// either the `Basic` or the `Bearer` scheme.
+pub(crate) enum PublisherAuth {
+    Basic(Basic),
+    Bearer(Bearer),
+    /// The publisher named by the `X-Publisher-Name` header of a proxy trusted
+    /// by `TRUST_PROXY`, such as an API gateway that authenticated the caller
+    /// itself.
+    Asserted(String),
+    Missing,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<SharedState>> for PublisherAuth {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<SharedState>,
+    ) -> Result<Self, Self::Rejection> {
+        let asserted = parts
+            .headers
+            .get("x-publisher-name")
+            .and_then(|name| name.to_str().ok())
+            .filter(|name| !name.is_empty());
+        if let Some(name) = asserted {
+            let peer = parts.extensions.get::<ConnectInfo<SocketAddr>>();
+            if peer.is_some_and(|ConnectInfo(peer)| state.is_trusted_proxy(peer.ip())) {
+                return Ok(Self::Asserted(name.to_string()));
+            }
+        }
+        if let Ok(TypedHeader(Authorization(basic))) =
+            TypedHeader::<Authorization<Basic>>::from_request_parts(parts, state).await
+        {
+            return Ok(Self::Basic(basic));
+        }
+        Ok(
+            match TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state).await {
+                Ok(TypedHeader(Authorization(bearer))) => Self::Bearer(bearer),
+                Err(_) => Self::Missing,
+            },
+        )
+    }
+}
+
+/// Checks the publisher's basic auth credentials or bearer token and returns
+/// its name, or the name asserted by a trusted proxy.
+pub(crate) fn authenticate_publisher(
+    state: &SharedState,
+    auth: PublisherAuth,
+) -> Result<String, AuthError> {
+    match auth {
+        PublisherAuth::Basic(basic) => check_credentials(state, basic.username(), basic.password()),
+        PublisherAuth::Bearer(bearer) => check_publisher_token(state, bearer.token()),
+        PublisherAuth::Asserted(name) => Ok(name),
+        PublisherAuth::Missing
+            if matches!(state.settings.load().credentials, Credentials::Disabled) =>
+        {
+            Ok(String::from("anonymous"))
+        }
+        PublisherAuth::Missing => Err(state.metrics.auth_failure(AuthError::MissingCredentials)),
+    }
+}
+
+/// Returns the publisher `PUBLISHER_TOKENS` gives `token` to.
+fn check_publisher_token(state: &SharedState, token: &str) -> Result<String, AuthError> {
+    let settings = state.settings.load();
+    let mut publisher = None;
+    // Compares every token so that the time taken does not reveal which one
+    // matched, if any.
+    for (name, expected) in &settings.publisher_tokens {
+        if constant_time_eq(token, expected) {
+            publisher = Some(name);
+        }
+    }
+    match publisher {
+        Some(name) => Ok(name.clone()),
+        None if matches!(settings.credentials, Credentials::Disabled) => {
+            Ok(String::from("anonymous"))
+        }
+        None => Err(state.metrics.auth_failure(AuthError::WrongCredentials)),
+    }
+}
+
+pub(crate) fn check_credentials(
+    state: &SharedState,
+    username: &str,
+    password: &str,
+) -> Result<String, AuthError> {
+    if state.settings.load().credentials.verify(username, password) {
+        Ok(username.to_string())
+    } else {
+        Err(state.metrics.auth_failure(AuthError::WrongCredentials))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum AuthError {
+    WrongCredentials,
+    MissingCredentials,
+    Forbidden,
+    MissingTopic,
+    RateLimited {
+        retry_after: Duration,
+    },
+    TooManyConnections,
+    /// The client's IP has `MAX_CONN_PER_IP` websocket connections open.
+    TooManyConnectionsPerIp,
+    UnsupportedSubprotocol,
+    TooManyTopics,
+    InvalidTopic,
+    /// A streamed publish was not valid UTF-8 or could not be read to the end.
+    InvalidPayload,
+    /// The auth service at `AUTH_URL` could not be reached, which says nothing
+    /// about the credentials.
+    AuthBackendUnavailable,
+    /// Nobody answered a `/request` in time.
+    NoReply,
+    /// The message could not be written to the journal at `DB_PATH`, so it
+    /// was not published.
+    StorageFailed,
+}
+
+impl AuthError {
+    pub(crate) fn status_and_message(&self) -> (StatusCode, &'static str) {
+        match self {
+            AuthError::WrongCredentials => (StatusCode::UNAUTHORIZED, "Wrong credentials"),
+            AuthError::MissingCredentials => (StatusCode::BAD_REQUEST, "Missing credentials"),
+            AuthError::Forbidden => (StatusCode::FORBIDDEN, "Forbidden"),
+            AuthError::MissingTopic => (StatusCode::BAD_REQUEST, "Missing topic"),
+            AuthError::RateLimited { .. } => (StatusCode::TOO_MANY_REQUESTS, "Too many requests"),
+            AuthError::TooManyConnections => {
+                (StatusCode::SERVICE_UNAVAILABLE, "Too many connections")
+            }
+            AuthError::TooManyConnectionsPerIp => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many connections from this address",
+            ),
+            AuthError::UnsupportedSubprotocol => {
+                (StatusCode::BAD_REQUEST, "Unsupported subprotocol")
+            }
+            AuthError::TooManyTopics => (StatusCode::BAD_REQUEST, "Too many topics"),
+            AuthError::InvalidTopic => (StatusCode::BAD_REQUEST, "Invalid topic"),
+            AuthError::InvalidPayload => (StatusCode::BAD_REQUEST, "Invalid payload"),
+            AuthError::AuthBackendUnavailable => {
+                (StatusCode::SERVICE_UNAVAILABLE, "Auth service unavailable")
+            }
+            AuthError::NoReply => (StatusCode::GATEWAY_TIMEOUT, "No reply"),
+            AuthError::StorageFailed => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "Failed to store message")
+            }
+        }
+    }
+
+    /// A stable identifier of the error for clients to match on, unlike the
+    /// message, which is meant for people.
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            AuthError::WrongCredentials => "WRONG_CREDENTIALS",
+            AuthError::MissingCredentials => "MISSING_CREDENTIALS",
+            AuthError::Forbidden => "FORBIDDEN",
+            AuthError::MissingTopic => "MISSING_TOPIC",
+            AuthError::RateLimited { .. } => "RATE_LIMITED",
+            AuthError::TooManyConnections => "TOO_MANY_CONNECTIONS",
+            AuthError::TooManyConnectionsPerIp => "TOO_MANY_CONNECTIONS_PER_IP",
+            AuthError::UnsupportedSubprotocol => "UNSUPPORTED_SUBPROTOCOL",
+            AuthError::TooManyTopics => "TOO_MANY_TOPICS",
+            AuthError::InvalidTopic => "INVALID_TOPIC",
+            AuthError::InvalidPayload => "INVALID_PAYLOAD",
+            AuthError::AuthBackendUnavailable => "AUTH_BACKEND_UNAVAILABLE",
+            AuthError::NoReply => "NO_REPLY",
+            AuthError::StorageFailed => "STORAGE_FAILED",
+        }
+    }
+
+    /// The `{"error": ..., "code": ...}` body the error is reported with.
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let (_, error_message) = self.status_and_message();
+        json!({ "error": error_message, "code": self.code() })
+    }
+
+    /// The error as a text frame for websocket clients.
+    pub(crate) fn to_message(&self) -> Message {
+        Message::Text(self.to_json().to_string())
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let (status, _) = self.status_and_message();
+        let mut response = (status, Json(self.to_json())).into_response();
+        match self {
+            AuthError::RateLimited { retry_after } => {
+                let retry_after = retry_after.as_secs_f64().ceil() as u64;
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+            }
+            // Publishers may use either scheme, subscribers bearer tokens and
+            // admins basic auth.
+            AuthError::WrongCredentials => {
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static(r#"Basic realm="isimud", Bearer realm="isimud""#),
+                );
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+/// The subscriber's bearer token from the `Authorization` header or, since
+/// browsers cannot set headers on websockets and `EventSource`, from the `token`
+/// query parameter.
+pub(crate) struct SubscriberBearer(pub(crate) Option<TypedHeader<headers::Authorization<Bearer>>>);
+
+#[derive(Deserialize)]
+struct TokenParams {
+    token: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for SubscriberBearer {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let bearer =
+            Option::<TypedHeader<headers::Authorization<Bearer>>>::from_request_parts(parts, state)
+                .await?;
+        if bearer.is_some() {
+            return Ok(Self(bearer));
+        }
+        let token = Query::<TokenParams>::from_request_parts(parts, state)
+            .await
+            .ok()
+            .and_then(|Query(params)| params.token);
+        Ok(Self(token.and_then(|token| {
+            headers::Authorization::bearer(&token).ok().map(TypedHeader)
+        })))
+    }
+}
+
+/// Checks the subscriber's bearer token locally as a JWT or against `AUTH_URL`,
+/// if configured, and returns who the subscriber is and the topics it may
+/// subscribe to.
+pub(crate) async fn authorize_subscriber(
+    state: &SharedState,
+    bearer: Option<TypedHeader<headers::Authorization<Bearer>>>,
+) -> Result<Scope, AuthError> {
+    if let Some(jwt) = &state.jwt {
+        let result = match bearer {
+            Some(bearer) => jwt.verify(bearer.token()).map(|claims| Scope {
+                identity: claims.sub,
+                topics: claims.topics,
+            }),
+            None => Err(AuthError::MissingCredentials),
+        };
+        return result.map_err(|error| state.metrics.auth_failure(error));
+    }
+    let Some(validation_url) = &state.auth_url else {
+        return Ok(Scope::default());
+    };
+    let result = match bearer {
+        Some(bearer) => match state.auth_cache.get(bearer.token()) {
+            Some(Some(scope)) => Ok(scope),
+            Some(None) => Err(AuthError::WrongCredentials),
+            None => {
+                let result = match state
+                    .client
+                    .get(validation_url.as_str())
+                    .bearer_auth(bearer.token())
+                    .send()
+                    .await
+                {
+                    Ok(response) if response.status().is_success() => Ok(Scope {
+                        identity: subscriber_identity(state, response).await,
+                        topics: None,
+                    }),
+                    Ok(_) => Err(AuthError::WrongCredentials),
+                    Err(error) if error.is_timeout() => {
+                        tracing::warn!(
+                            event = "auth_backend_error",
+                            "auth service at {validation_url} did not answer in time: {error}",
+                        );
+                        Err(AuthError::AuthBackendUnavailable)
+                    }
+                    Err(error) => {
+                        tracing::warn!(
+                            event = "auth_backend_error",
+                            "auth service at {validation_url} could not be reached: {error}",
+                        );
+                        Err(AuthError::AuthBackendUnavailable)
+                    }
+                };
+                match &result {
+                    Ok(scope) => state.auth_cache.insert(bearer.token(), Some(scope.clone())),
+                    Err(AuthError::WrongCredentials) => {
+                        state.auth_cache.insert(bearer.token(), None)
+                    }
+                    Err(_) => {}
+                }
+                result
+            }
+        },
+        None => Err(AuthError::MissingCredentials),
+    };
+    result.map_err(|error| state.metrics.auth_failure(error))
+}
+
+/// Reads the subscriber's identity from the `AUTH_IDENTITY_FIELD` of an
+/// `AUTH_URL` approval. A body that is not JSON or lacks the field still
+/// authorizes the subscriber, just anonymously.
+async fn subscriber_identity(state: &SharedState, response: reqwest::Response) -> Option<String> {
+    let field = state.auth_identity_field.as_deref()?;
+    let body = response.bytes().await.unwrap_or_default();
+    let body: serde_json::Value = match serde_json::from_slice(&body) {
+        Ok(body) => body,
+        Err(error) => {
+            tracing::warn!("auth service response is not valid JSON: {error}");
+            return None;
+        }
+    };
+    match body.get(field) {
+        Some(serde_json::Value::String(identity)) => Some(identity.clone()),
+        Some(serde_json::Value::Number(identity)) => Some(identity.to_string()),
+        _ => {
+            tracing::warn!("auth service response has no string `{field}` field");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn compares_secrets() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(constant_time_eq("", ""));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secret!"));
+        assert!(!constant_time_eq("", "secret"));
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn sign(secret: &str, exp: u64) -> String {
+        let claims = json!({ "sub": "alice", "topics": ["sensors/#"], "exp": exp });
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    fn verifier() -> JwtVerifier {
+        let config = Config {
+            jwt_secret: Some(String::from("jwt-secret")),
+            ..Config::default()
+        };
+        JwtVerifier::from_config(&config).unwrap().unwrap()
+    }
+
+    #[test]
+    fn accepts_valid_tokens() {
+        let claims = verifier()
+            .verify(&sign("jwt-secret", now() + 3600))
+            .unwrap();
+        assert_eq!(claims.sub.as_deref(), Some("alice"));
+        assert_eq!(claims.topics.unwrap(), ["sensors/#"]);
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let token = sign("jwt-secret", now() - 3600);
+        assert!(matches!(
+            verifier().verify(&token),
+            Err(AuthError::WrongCredentials)
+        ));
+    }
+
+    #[test]
+    fn rejects_tampered_signatures() {
+        let token = sign("jwt-secret", now() + 3600);
+        let forged = sign("other-secret", now() + 3600);
+        let (claims, _) = token.rsplit_once('.').unwrap();
+        let (_, signature) = forged.rsplit_once('.').unwrap();
+        assert!(matches!(
+            verifier().verify(&format!("{claims}.{signature}")),
+            Err(AuthError::WrongCredentials)
+        ));
+    }
+
+    #[test]
+    fn parses_forwarded_addresses() {
+        let ipv4: IpAddr = "203.0.113.7".parse().unwrap();
+        let ipv6: IpAddr = "2001:db8::1".parse().unwrap();
+        for (hop, expected) in [
+            ("203.0.113.7", Some(ipv4)),
+            (" 203.0.113.7:8080", Some(ipv4)),
+            ("2001:db8::1", Some(ipv6)),
+            ("[2001:db8::1]", Some(ipv6)),
+            ("\"[2001:db8::1]:4711\"", Some(ipv6)),
+            ("_hidden", None),
+            ("unknown", None),
+        ] {
+            assert_eq!(parse_hop(hop), expected, "{hop}");
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| {
+                (
+                    header::HeaderName::from_static(name),
+                    value.parse().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lists_the_forwarding_hops() {
+        let forwarded = "for=192.0.2.60;proto=http, For=\"[2001:db8:cafe::17]:4711\", for=_hidden";
+        assert_eq!(
+            forwarded_hops(&headers(&[
+                ("forwarded", forwarded),
+                ("x-forwarded-for", "198.51.100.1"),
+            ])),
+            [
+                Some("192.0.2.60".parse().unwrap()),
+                Some("2001:db8:cafe::17".parse().unwrap()),
+                None,
+            ]
+        );
+        assert_eq!(
+            forwarded_hops(&headers(&[("x-forwarded-for", "198.51.100.1, 10.0.0.1")])),
+            [
+                Some("198.51.100.1".parse().unwrap()),
+                Some("10.0.0.1".parse().unwrap()),
+            ]
+        );
+        assert_eq!(forwarded_hops(&HeaderMap::new()), []);
+    }
+
+    #[test]
+    fn believes_only_trusted_proxies() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            trust_proxy: Some(true),
+            trusted_proxy_cidrs: Some(vec!["10.0.0.0/8".parse().unwrap()]),
+            ..Config::default()
+        };
+        let state = SharedState::new(&config).unwrap();
+        let client = |peer: &str, forwarded_for: &'static str| {
+            let peer: SocketAddr = peer.parse().unwrap();
+            state
+                .client_addr(peer, &headers(&[("x-forwarded-for", forwarded_for)]))
+                .to_string()
+        };
+
+        // The rightmost hop that is not a trusted proxy, as anything to the
+        // left of it may be made up by the client.
+        assert_eq!(
+            client("10.0.0.2:5000", "198.51.100.1, 203.0.113.7, 10.0.0.5"),
+            "203.0.113.7:5000"
+        );
+        assert_eq!(client("192.0.2.1:5000", "203.0.113.7"), "192.0.2.1:5000");
+        assert_eq!(
+            client("10.0.0.2:5000", "203.0.113.7, unknown"),
+            "10.0.0.2:5000"
+        );
+        assert_eq!(
+            client("10.0.0.2:5000", "10.0.0.3, 10.0.0.4"),
+            "10.0.0.3:5000"
+        );
+
+        let config = Config {
+            trust_proxy: Some(false),
+            ..config
+        };
+        let state = SharedState::new(&config).unwrap();
+        let peer = "10.0.0.2:5000".parse().unwrap();
+        let headers = headers(&[("x-forwarded-for", "203.0.113.7")]);
+        assert_eq!(state.client_addr(peer, &headers), peer);
+    }
+}
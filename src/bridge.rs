@@ -0,0 +1,442 @@
+//! Forwarding messages between instances over Redis, to an MQTT broker and to
+//! webhooks.
+
+use anyhow::Context;
+use axum::http::header;
+use futures::StreamExt;
+use redis::AsyncCommands;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use std::{collections::hash_map::RandomState, hash::BuildHasher, sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+
+use crate::{
+    config::Config,
+    pubsub::{topic_matches, Payload, PubSubMsg, PublisherMsg, SharedState},
+};
+
+/// Shares publishes with other instances through a Redis pub/sub channel.
+/// Every instance delivers its own publishes locally and skips them when they
+/// come back from Redis, so nothing is delivered twice or published again.
+pub(crate) struct RedisRelay {
+    client: redis::Client,
+    channel: String,
+    /// Tells this instance's messages apart from those of other instances.
+    origin: u64,
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+/// A message as sent between instances over Redis.
+#[derive(Serialize, Deserialize)]
+struct RelayedMsg {
+    origin: u64,
+    publisher: String,
+    topic: String,
+    retain: bool,
+    #[serde(default)]
+    ttl_ms: Option<u64>,
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(default)]
+    reply_to: Option<String>,
+    #[serde(default)]
+    correlation_id: Option<String>,
+    timestamp: u64,
+    data: RelayedPayload,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RelayedPayload {
+    Text(Arc<str>),
+    Binary(Arc<[u8]>),
+}
+
+impl From<RelayedMsg> for PubSubMsg {
+    fn from(relayed: RelayedMsg) -> Self {
+        Self {
+            name: relayed.publisher,
+            msg: PublisherMsg {
+                topic: relayed.topic,
+                data: match relayed.data {
+                    RelayedPayload::Text(text) => Payload::Text(text),
+                    RelayedPayload::Binary(bytes) => Payload::Binary(bytes),
+                },
+                retain: relayed.retain,
+                ttl_ms: relayed.ttl_ms,
+                key: relayed.key,
+                reply_to: relayed.reply_to,
+                correlation_id: relayed.correlation_id,
+            },
+            timestamp: relayed.timestamp,
+            seq: 0,
+            stream: None,
+            compressed: Arc::default(),
+        }
+    }
+}
+
+impl RedisRelay {
+    /// Connects lazily to `redis_url`, so the server also starts while Redis is
+    /// down. Publishes are sent to Redis from a background task so that
+    /// publishers never wait on it.
+    pub(crate) fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(url) = &config.redis_url else {
+            return Ok(None);
+        };
+        let client = redis::Client::open(url.as_str()).context("invalid REDIS_URL")?;
+        let channel = config
+            .redis_channel
+            .clone()
+            .unwrap_or_else(|| String::from("isimud"));
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        tokio::spawn(forward_to_redis(
+            client.clone(),
+            channel.clone(),
+            outgoing_rx,
+        ));
+        Ok(Some(Self {
+            client,
+            channel,
+            origin: RandomState::new().hash_one(std::process::id()),
+            outgoing,
+        }))
+    }
+
+    pub(crate) fn relay(&self, msg: &PubSubMsg) {
+        let relayed = RelayedMsg {
+            origin: self.origin,
+            publisher: msg.name.clone(),
+            topic: msg.msg.topic.clone(),
+            retain: msg.msg.retain,
+            ttl_ms: msg.msg.ttl_ms,
+            key: msg.msg.key.clone(),
+            reply_to: msg.msg.reply_to.clone(),
+            correlation_id: msg.msg.correlation_id.clone(),
+            timestamp: msg.timestamp,
+            data: match &msg.msg.data {
+                Payload::Text(text) => RelayedPayload::Text(text.clone()),
+                Payload::Binary(bytes) => RelayedPayload::Binary(bytes.clone()),
+            },
+        };
+        let relayed = serde_json::to_vec(&relayed).expect("relayed message is always serializable");
+        let _ = self.outgoing.send(relayed);
+    }
+
+    /// Delivers a message received from Redis to local subscribers, unless
+    /// this instance published it.
+    fn receive(&self, state: &Arc<SharedState>, payload: &[u8]) {
+        match serde_json::from_slice::<RelayedMsg>(payload) {
+            Ok(relayed) if relayed.origin != self.origin => {
+                // Delivered in order without waiting for the journal. A message
+                // it cannot store is dropped, which it logs.
+                drop(state.deliver(relayed.into()));
+            }
+            Ok(_) => {}
+            Err(error) => tracing::warn!("ignoring invalid message from Redis: {error}"),
+        }
+    }
+}
+
+/// Publishes relayed messages to Redis, dropping those that cannot be sent
+/// while Redis is unreachable.
+async fn forward_to_redis(
+    client: redis::Client,
+    channel: String,
+    mut outgoing: mpsc::UnboundedReceiver<Vec<u8>>,
+) {
+    let mut connection = loop {
+        match redis::aio::ConnectionManager::new(client.clone()).await {
+            Ok(connection) => break connection,
+            Err(error) => {
+                tracing::warn!("failed to connect to Redis: {error}");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    };
+    while let Some(relayed) = outgoing.recv().await {
+        // The connection manager reconnects after a failed command, so one
+        // retry covers a connection that Redis dropped while idle.
+        let mut result = connection.publish::<_, _, ()>(&channel, &relayed).await;
+        if result.is_err() {
+            result = connection.publish(&channel, &relayed).await;
+        }
+        if let Err(error) = result {
+            tracing::warn!("failed to publish to Redis: {error}");
+        }
+    }
+}
+
+/// Delivers messages published on other instances to local subscribers,
+/// subscribing to Redis again whenever the connection drops.
+pub(crate) async fn relay_from_redis(state: Arc<SharedState>) {
+    let Some(redis) = &state.redis else {
+        return;
+    };
+    loop {
+        let pubsub = async {
+            let mut pubsub = redis.client.get_async_connection().await?.into_pubsub();
+            pubsub.subscribe(&redis.channel).await?;
+            Ok::<_, redis::RedisError>(pubsub)
+        };
+        match pubsub.await {
+            Ok(mut pubsub) => {
+                tracing::debug!("subscribed to Redis channel {}", redis.channel);
+                let mut messages = pubsub.on_message();
+                while let Some(message) = messages.next().await {
+                    redis.receive(&state, message.get_payload_bytes());
+                }
+                tracing::warn!("lost connection to Redis");
+            }
+            Err(error) => tracing::warn!("failed to subscribe to Redis: {error}"),
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// Mirrors publishes to an MQTT broker under the topic `<publisher>/<topic>`.
+pub(crate) struct MqttBridge {
+    client: rumqttc::AsyncClient,
+}
+
+impl MqttBridge {
+    /// Number of messages queued for the broker before further ones are dropped.
+    const QUEUE_CAPACITY: usize = 1024;
+
+    /// Connects to `mqtt_broker_url`, given as `mqtt://[user:password@]host[:port]`
+    /// with an optional `client_id` query parameter, from a background task.
+    pub(crate) fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(url) = &config.mqtt_broker_url else {
+            return Ok(None);
+        };
+        let url: Url = url.parse().context("invalid MQTT_BROKER_URL")?;
+        anyhow::ensure!(
+            matches!(url.scheme(), "mqtt" | "tcp"),
+            "MQTT_BROKER_URL must use the mqtt:// scheme"
+        );
+        let host = url
+            .host_str()
+            .context("MQTT_BROKER_URL must contain a host")?;
+        let client_id = url
+            .query_pairs()
+            .find(|(key, _)| key == "client_id")
+            .map_or_else(|| String::from("isimud"), |(_, id)| id.into_owned());
+        let mut options = rumqttc::MqttOptions::new(client_id, host, url.port().unwrap_or(1883));
+        if !url.username().is_empty() {
+            options.set_credentials(url.username(), url.password().unwrap_or_default());
+        }
+        let (client, event_loop) = rumqttc::AsyncClient::new(options, Self::QUEUE_CAPACITY);
+        tokio::spawn(drive_mqtt(event_loop));
+        Ok(Some(Self { client }))
+    }
+
+    /// Queues `msg` for the broker without waiting for it to be sent.
+    pub(crate) fn mirror(&self, msg: &PubSubMsg) {
+        let payload = match &msg.msg.data {
+            Payload::Text(text) => text.as_bytes().to_vec(),
+            Payload::Binary(bytes) => bytes.to_vec(),
+        };
+        let topic = format!("{}/{}", msg.name, msg.msg.topic);
+        if let Err(error) =
+            self.client
+                .try_publish(topic, rumqttc::QoS::AtLeastOnce, msg.msg.retain, payload)
+        {
+            tracing::warn!("dropping message for MQTT broker: {error}");
+        }
+    }
+}
+
+/// Keeps the MQTT connection alive, reconnecting with exponential backoff
+/// while the broker is unreachable.
+async fn drive_mqtt(mut event_loop: rumqttc::EventLoop) {
+    const MAX_BACKOFF: Duration = Duration::from_secs(30);
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match event_loop.poll().await {
+            Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                tracing::debug!("connected to MQTT broker");
+                backoff = Duration::from_secs(1);
+            }
+            Ok(_) => {}
+            Err(error) => {
+                tracing::warn!("MQTT broker connection failed: {error}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Forwards publishes matching `publisher` and `topic` to `url` as HTTP POSTs of
+/// the same JSON envelope that subscribers receive.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Webhook {
+    /// Matches every publisher if absent.
+    publisher: Option<String>,
+    /// Topic pattern, with the same wildcards as subscriptions.
+    topic: String,
+    pub(crate) url: String,
+    /// Seconds each delivery attempt may take.
+    #[serde(default = "Webhook::default_timeout")]
+    timeout: u64,
+}
+
+impl Webhook {
+    const ATTEMPTS: u32 = 3;
+
+    fn default_timeout() -> u64 {
+        5
+    }
+
+    pub(crate) fn matches(&self, msg: &PubSubMsg) -> bool {
+        self.publisher
+            .as_ref()
+            .is_none_or(|publisher| *publisher == msg.name)
+            && topic_matches(&self.topic, &msg.msg.topic)
+    }
+}
+
+/// A [`Webhook`] with the messages waiting to be posted to it, which a
+/// background task posts one at a time, so that a slow or unreachable receiver
+/// holds up neither publishers nor the other webhooks.
+pub(crate) struct WebhookQueue {
+    pub(crate) webhook: Arc<Webhook>,
+    queue: mpsc::Sender<Arc<str>>,
+}
+
+impl WebhookQueue {
+    /// Number of messages waiting for a webhook before further ones are dropped.
+    pub(crate) const CAPACITY: usize = 1024;
+
+    pub(crate) fn spawn(client: Client, webhook: Webhook) -> Self {
+        let webhook = Arc::new(webhook);
+        let (queue, mut bodies) = mpsc::channel::<Arc<str>>(Self::CAPACITY);
+        let worker = webhook.clone();
+        tokio::spawn(async move {
+            while let Some(body) = bodies.recv().await {
+                post_to_webhook(&client, &worker, &body).await;
+            }
+        });
+        Self { webhook, queue }
+    }
+
+    /// Queues `body` for the webhook, or returns `false` if its queue is full.
+    pub(crate) fn post(&self, body: Arc<str>) -> bool {
+        self.queue.try_send(body).is_ok()
+    }
+}
+
+/// Posts `body` to `webhook`, retrying with exponential backoff when the
+/// receiver is unreachable or answers with a server error.
+async fn post_to_webhook(client: &Client, webhook: &Webhook, body: &str) {
+    let mut backoff = Duration::from_secs(1);
+    for attempt in 1..=Webhook::ATTEMPTS {
+        let result = client
+            .post(&webhook.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .timeout(Duration::from_secs(webhook.timeout))
+            .body(body.to_string())
+            .send()
+            .await;
+        let error = match result {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) if response.status().is_client_error() => {
+                tracing::warn!(
+                    url = %webhook.url,
+                    status = %response.status(),
+                    "webhook rejected message",
+                );
+                return;
+            }
+            Ok(response) => response.status().to_string(),
+            Err(error) => error.to_string(),
+        };
+        if attempt == Webhook::ATTEMPTS {
+            tracing::warn!(url = %webhook.url, %error, "giving up on webhook delivery");
+        } else {
+            tracing::debug!(url = %webhook.url, %error, attempt, "retrying webhook delivery");
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    /// A message as another instance, or this one if `origin` is its own,
+    /// would send it over Redis.
+    fn relayed(origin: u64, data: &str) -> Vec<u8> {
+        serde_json::to_vec(&RelayedMsg {
+            origin,
+            publisher: String::from("p"),
+            topic: String::from("greetings"),
+            retain: false,
+            ttl_ms: None,
+            key: None,
+            reply_to: None,
+            correlation_id: None,
+            timestamp: 0,
+            data: RelayedPayload::Text(data.into()),
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn delivers_only_messages_of_other_instances() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            // Never connected to, since nothing is relayed.
+            redis_url: Some(String::from("redis://127.0.0.1:1")),
+            ..Config::default()
+        };
+        let state = Arc::new(SharedState::new(&config).unwrap());
+        let redis = state.redis.as_ref().unwrap();
+        let (mut receiver, _) = state.watch();
+
+        redis.receive(&state, &relayed(redis.origin, "own"));
+        redis.receive(&state, b"not json");
+        redis.receive(&state, &relayed(redis.origin.wrapping_add(1), "foreign"));
+
+        let delivered = receiver.try_recv().unwrap();
+        assert_eq!(delivered.name, "p");
+        assert_eq!(delivered.msg.topic, "greetings");
+        assert!(matches!(delivered.msg.data, Payload::Text(text) if &*text == "foreign"));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn drops_messages_for_a_webhook_that_falls_behind() {
+        // Accepts connections into its backlog but never answers them.
+        let receiver = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let webhook = Webhook {
+            publisher: None,
+            topic: String::from("hooks"),
+            url: format!("http://{}/", receiver.local_addr().unwrap()),
+            timeout: 60,
+        };
+        let config = Config {
+            password: Some(String::from("secret")),
+            webhooks: Some(vec![webhook]),
+            ..Config::default()
+        };
+        let state = Arc::new(SharedState::new(&config).unwrap());
+
+        // The worker holds on to at most one message while the rest wait.
+        for _ in 0..WebhookQueue::CAPACITY + 3 {
+            let msg = PublisherMsg::new("hooks", "hello");
+            state
+                .publish(PubSubMsg::new(msg, String::from("p")))
+                .await
+                .unwrap();
+        }
+        let dropped = state
+            .metrics
+            .webhook_dropped_messages
+            .load(Ordering::Relaxed);
+        assert!((2..=3).contains(&dropped), "dropped {dropped}");
+    }
+}
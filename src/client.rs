@@ -0,0 +1,271 @@
+//! A typed client for publishing to and subscribing from an isimud server.
+//!
+//! ```
+//! use futures::StreamExt;
+//! use isimud::client::{Publisher, PublisherMsg, Subscriber, SubscriberMsg};
+//!
+//! # #[tokio::main]
+//! # async fn main() -> anyhow::Result<()> {
+//! # std::env::set_var("PASSWORD", "password");
+//! # let config = isimud::Config::load()?;
+//! # let state = std::sync::Arc::new(isimud::SharedState::new(&config)?);
+//! # let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+//! # let addr = listener.local_addr()?;
+//! # let app = isimud::app(&config, state)?
+//! #     .into_make_service_with_connect_info::<std::net::SocketAddr>();
+//! # tokio::spawn(axum::Server::from_tcp(listener)?.serve(app));
+//! let mut subscriber =
+//!     Subscriber::connect(&format!("ws://{addr}"), &SubscriberMsg::new("sensors/#")).await?;
+//!
+//! let publisher = Publisher::new(&format!("http://{addr}"), "weather", "password")?;
+//! let delivered_to = publisher
+//!     .publish(&PublisherMsg::new("sensors/room1/temp", "21.5"))
+//!     .await?;
+//! println!("delivered to {delivered_to} subscribers");
+//!
+//! if let Some(msg) = subscriber.next().await {
+//!     let msg = msg?;
+//!     println!("{} published {} to {}", msg.publisher, msg.data, msg.topic);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{SinkExt, Stream, StreamExt};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    tungstenite::{self, client::IntoClientRequest, http::HeaderValue, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+pub use crate::pubsub::{Mode, PublisherMsg, SubscriberMsg};
+
+/// A message received by a [`Subscriber`].
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeliveredMsg {
+    pub publisher: String,
+    pub topic: String,
+    pub data: String,
+    /// Unix time in milliseconds at which the message was published.
+    pub timestamp: u64,
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    #[serde(default)]
+    pub correlation_id: Option<String>,
+    /// Sequence number, only sent to subscribers with a session or resuming with
+    /// `since_seq`.
+    #[serde(default)]
+    pub seq: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidUrl(String),
+    Http(reqwest::Error),
+    WebSocket(Box<tungstenite::Error>),
+    Json(serde_json::Error),
+    /// The server answered a publish with an error status.
+    Rejected {
+        status: StatusCode,
+        body: String,
+    },
+    /// The server sent an `{"error": ...}` message over the websocket.
+    Server(String),
+    /// Some messages after `since_seq` are no longer available to resume from.
+    /// The subscriber keeps receiving the later ones.
+    Gap {
+        since_seq: u64,
+    },
+    /// The subscriber fell behind and `skipped` messages were dropped. It keeps
+    /// receiving the later ones.
+    Lagged {
+        skipped: u64,
+    },
+}
+
+impl Error {
+    fn websocket(error: tungstenite::Error) -> Self {
+        Self::WebSocket(Box::new(error))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidUrl(error) => write!(f, "invalid server URL: {error}"),
+            Self::Http(error) => write!(f, "request failed: {error}"),
+            Self::WebSocket(error) => write!(f, "websocket error: {error}"),
+            Self::Json(error) => write!(f, "unexpected message from the server: {error}"),
+            Self::Rejected { status, body } => write!(f, "publish rejected with {status}: {body}"),
+            Self::Server(error) => write!(f, "server error: {error}"),
+            Self::Gap { since_seq } => write!(f, "missed messages after seq {since_seq}"),
+            Self::Lagged { skipped } => {
+                write!(f, "skipped {skipped} messages while lagging behind")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Http(error) => Some(error),
+            Self::WebSocket(error) => Some(error),
+            Self::Json(error) => Some(error),
+            Self::InvalidUrl(_)
+            | Self::Rejected { .. }
+            | Self::Server(_)
+            | Self::Gap { .. }
+            | Self::Lagged { .. } => None,
+        }
+    }
+}
+
+/// Publishes messages over HTTP with the credentials of one publisher.
+#[derive(Clone)]
+pub struct Publisher {
+    http: reqwest::Client,
+    pub_url: Url,
+    username: String,
+    password: String,
+}
+
+impl Publisher {
+    /// A publisher for the server at `base_url`, e.g. `https://example.com`,
+    /// publishing as `username`.
+    pub fn new(
+        base_url: &str,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Result<Self, Error> {
+        let pub_url = Url::parse(base_url)
+            .and_then(|url| url.join("pub"))
+            .map_err(|error| Error::InvalidUrl(error.to_string()))?;
+        Ok(Self {
+            http: reqwest::Client::new(),
+            pub_url,
+            username: username.into(),
+            password: password.into(),
+        })
+    }
+
+    /// Publishes `msg` and returns how many subscribers it was delivered to.
+    pub async fn publish(&self, msg: &PublisherMsg) -> Result<usize, Error> {
+        #[derive(Deserialize)]
+        struct Published {
+            delivered_to: usize,
+        }
+
+        let response = self
+            .http
+            .post(self.pub_url.clone())
+            .basic_auth(&self.username, Some(&self.password))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(msg).map_err(Error::Json)?)
+            .send()
+            .await
+            .map_err(Error::Http)?;
+        let status = response.status();
+        let body = response.bytes().await.map_err(Error::Http)?;
+        if !status.is_success() {
+            let body = String::from_utf8_lossy(&body).into_owned();
+            return Err(Error::Rejected { status, body });
+        }
+        let published: Published = serde_json::from_slice(&body).map_err(Error::Json)?;
+        Ok(published.delivered_to)
+    }
+}
+
+/// A websocket subscriber, which is a stream of the messages it receives.
+pub struct Subscriber {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl Subscriber {
+    /// Connects to the server at `base_url`, e.g. `wss://example.com`, and
+    /// sends `subscription`.
+    pub async fn connect(base_url: &str, subscription: &SubscriberMsg) -> Result<Self, Error> {
+        let url = Url::parse(base_url)
+            .and_then(|url| url.join("sub"))
+            .map_err(|error| Error::InvalidUrl(error.to_string()))?;
+        let mut request = url
+            .as_str()
+            .into_client_request()
+            .map_err(Error::websocket)?;
+        // Asks for the JSON envelope even if the server uses `RAW_DELIVERY`.
+        request.headers_mut().insert(
+            "sec-websocket-protocol",
+            HeaderValue::from_static("isimud.v1.json"),
+        );
+        let (mut socket, _) = tokio_tungstenite::connect_async(request)
+            .await
+            .map_err(Error::websocket)?;
+        let subscription = serde_json::to_string(subscription).map_err(Error::Json)?;
+        socket
+            .send(Message::Text(subscription))
+            .await
+            .map_err(Error::websocket)?;
+        Ok(Self { socket })
+    }
+}
+
+/// The text frames a subscriber may receive, with `Hello` also standing for
+/// subscription confirmations.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Frame {
+    Message(DeliveredMsg),
+    Error { error: String },
+    Gap { gap: Gap },
+    Lagged { lagged: Lagged },
+    Hello {},
+}
+
+#[derive(Deserialize)]
+struct Gap {
+    since_seq: u64,
+}
+
+#[derive(Deserialize)]
+struct Lagged {
+    skipped: u64,
+}
+
+impl Stream for Subscriber {
+    type Item = Result<DeliveredMsg, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let text = match futures::ready!(self.socket.poll_next_unpin(cx)) {
+                Some(Ok(Message::Text(text))) => text,
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(None),
+                // Binary messages cannot be published as JSON, so they are not
+                // represented here.
+                Some(Ok(_)) => continue,
+                Some(Err(error)) => return Poll::Ready(Some(Err(Error::websocket(error)))),
+            };
+            return Poll::Ready(Some(match serde_json::from_str(&text) {
+                Ok(Frame::Message(msg)) => Ok(msg),
+                Ok(Frame::Error { error }) => Err(Error::Server(error)),
+                Ok(Frame::Gap { gap }) => Err(Error::Gap {
+                    since_seq: gap.since_seq,
+                }),
+                Ok(Frame::Lagged { lagged }) => Err(Error::Lagged {
+                    skipped: lagged.skipped,
+                }),
+                Ok(Frame::Hello {}) => continue,
+                Err(error) => Err(Error::Json(error)),
+            }));
+        }
+    }
+}
@@ -0,0 +1,439 @@
+//! Server settings, from the TOML file at `CONFIG_PATH` and the environment.
+
+use anyhow::Context;
+use ipnet::IpNet;
+use serde::{de::IntoDeserializer, Deserialize};
+use std::{collections::HashMap, convert::Infallible, net::IpAddr, str::FromStr};
+
+use crate::bridge::Webhook;
+
+/// Server settings, read from the TOML file at `CONFIG_PATH` if set. Every
+/// setting can be overridden by the environment variable of the same name in
+/// upper case.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub(crate) password: Option<String>,
+    pub(crate) password_file: Option<String>,
+    pub(crate) credentials: Option<HashMap<String, String>>,
+    pub(crate) credentials_path: Option<String>,
+    pub(crate) publisher_tokens: Option<HashMap<String, String>>,
+    pub(crate) acl: Option<HashMap<String, Vec<String>>>,
+    pub(crate) acl_path: Option<String>,
+    pub(crate) pub_rate_per_sec: Option<f64>,
+    pub(crate) pub_burst: Option<f64>,
+    pub(crate) auth_cache_ttl: Option<u64>,
+    pub(crate) auth_request_timeout: Option<u64>,
+    pub(crate) jwt_secret: Option<String>,
+    pub(crate) jwt_secret_file: Option<String>,
+    pub(crate) jwt_public_key: Option<String>,
+    pub(crate) ping_interval: Option<u64>,
+    pub(crate) sse_keepalive: Option<u64>,
+    pub(crate) ping_timeout: Option<u64>,
+    pub(crate) subscribe_timeout: Option<u64>,
+    pub(crate) max_conn_lifetime: Option<u64>,
+    pub(crate) slow_subscriber_policy: Option<SlowSubscriberPolicy>,
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) max_conn_per_ip: Option<usize>,
+    pub(crate) ip: Option<Vec<IpAddr>>,
+    pub(crate) port: Option<u16>,
+    pub(crate) log_format: Option<String>,
+    pub(crate) homepage: Option<Homepage>,
+    pub(crate) homepage_url: Option<String>,
+    pub(crate) auth_url: Option<String>,
+    pub(crate) auth_identity_field: Option<String>,
+    pub(crate) broadcast_capacity: Option<usize>,
+    pub(crate) max_payload_bytes: Option<usize>,
+    pub(crate) stream_chunk_bytes: Option<usize>,
+    pub(crate) ws_max_message_bytes: Option<usize>,
+    pub(crate) raw_delivery: Option<bool>,
+    pub(crate) dev_mode: Option<bool>,
+    pub(crate) shard_by_publisher: Option<bool>,
+    pub(crate) history_size: Option<usize>,
+    pub(crate) tls_cert_path: Option<String>,
+    pub(crate) tls_key_path: Option<String>,
+    pub(crate) cors_allowed_origins: Option<Vec<String>>,
+    pub(crate) pub_allow_cidrs: Option<Vec<IpNet>>,
+    pub(crate) trust_proxy: Option<bool>,
+    pub(crate) trusted_proxy_cidrs: Option<Vec<IpNet>>,
+    pub(crate) redis_url: Option<String>,
+    pub(crate) redis_channel: Option<String>,
+    pub(crate) mqtt_broker_url: Option<String>,
+    pub(crate) webhooks: Option<Vec<Webhook>>,
+    pub(crate) webhooks_path: Option<String>,
+    pub(crate) admin_password: Option<String>,
+    pub(crate) ack_buffer_size: Option<usize>,
+    pub(crate) metrics_max_topics: Option<usize>,
+    pub(crate) max_topics_per_conn: Option<usize>,
+    pub(crate) db_path: Option<String>,
+    pub(crate) db_max_rows: Option<usize>,
+    pub(crate) db_max_age: Option<u64>,
+}
+
+impl Config {
+    /// Reads the settings from `CONFIG_PATH` and the environment.
+    pub fn load() -> anyhow::Result<Self> {
+        Self::load_from(&env_vars())
+    }
+
+    /// Reads the settings from the TOML file at `path`, if any, in place of
+    /// `CONFIG_PATH`, and the environment.
+    pub(crate) fn load_path(path: Option<&str>) -> anyhow::Result<Self> {
+        let mut vars = env_vars();
+        vars.remove("CONFIG_PATH");
+        if let Some(path) = path {
+            vars.insert(String::from("CONFIG_PATH"), path.to_string());
+        }
+        Self::load_from(&vars)
+    }
+
+    /// Reads the settings from `CONFIG_PATH` and the other variables in `vars`.
+    pub(crate) fn load_from(vars: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let mut config: Self = match vars.get("CONFIG_PATH") {
+            Some(path) => {
+                let file = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read config from `{path}`"))?;
+                toml::from_str(&file)
+                    .with_context(|| format!("failed to parse config in `{path}`"))?
+            }
+            None => Self::default(),
+        };
+        env_override(vars, &mut config.password, "PASSWORD")?;
+        env_override(vars, &mut config.password_file, "PASSWORD_FILE")?;
+        pairs_override(vars, &mut config.credentials, "CREDENTIALS", "user:pass")?;
+        env_override(vars, &mut config.credentials_path, "CREDENTIALS_PATH")?;
+        pairs_override(
+            vars,
+            &mut config.publisher_tokens,
+            "PUBLISHER_TOKENS",
+            "publisher:token",
+        )?;
+        env_override(vars, &mut config.acl_path, "ACL_PATH")?;
+        env_override(vars, &mut config.pub_rate_per_sec, "PUB_RATE_PER_SEC")?;
+        env_override(vars, &mut config.pub_burst, "PUB_BURST")?;
+        env_override(vars, &mut config.auth_cache_ttl, "AUTH_CACHE_TTL")?;
+        env_override(
+            vars,
+            &mut config.auth_request_timeout,
+            "AUTH_REQUEST_TIMEOUT",
+        )?;
+        env_override(vars, &mut config.jwt_secret, "JWT_SECRET")?;
+        env_override(vars, &mut config.jwt_secret_file, "JWT_SECRET_FILE")?;
+        env_override(vars, &mut config.jwt_public_key, "JWT_PUBLIC_KEY")?;
+        env_override(vars, &mut config.ping_interval, "PING_INTERVAL")?;
+        env_override(vars, &mut config.sse_keepalive, "SSE_KEEPALIVE")?;
+        env_override(vars, &mut config.ping_timeout, "PING_TIMEOUT")?;
+        env_override(vars, &mut config.subscribe_timeout, "SUBSCRIBE_TIMEOUT")?;
+        env_override(vars, &mut config.max_conn_lifetime, "MAX_CONN_LIFETIME")?;
+        env_override(
+            vars,
+            &mut config.slow_subscriber_policy,
+            "SLOW_SUBSCRIBER_POLICY",
+        )?;
+        env_override(vars, &mut config.max_connections, "MAX_CONNECTIONS")?;
+        env_override(vars, &mut config.max_conn_per_ip, "MAX_CONN_PER_IP")?;
+        list_override(vars, &mut config.ip, "IP")?;
+        env_override(vars, &mut config.port, "PORT")?;
+        env_override(vars, &mut config.log_format, "LOG_FORMAT")?;
+        env_override(vars, &mut config.homepage, "HOMEPAGE")?;
+        env_override(vars, &mut config.homepage_url, "HOMEPAGE_URL")?;
+        env_override(vars, &mut config.auth_url, "AUTH_URL")?;
+        env_override(vars, &mut config.auth_identity_field, "AUTH_IDENTITY_FIELD")?;
+        env_override(vars, &mut config.broadcast_capacity, "BROADCAST_CAPACITY")?;
+        env_override(vars, &mut config.max_payload_bytes, "MAX_PAYLOAD_BYTES")?;
+        env_override(vars, &mut config.stream_chunk_bytes, "STREAM_CHUNK_BYTES")?;
+        env_override(
+            vars,
+            &mut config.ws_max_message_bytes,
+            "WS_MAX_MESSAGE_BYTES",
+        )?;
+        flag_override(vars, &mut config.raw_delivery, "RAW_DELIVERY");
+        flag_override(vars, &mut config.dev_mode, "DEV_MODE");
+        flag_override(vars, &mut config.shard_by_publisher, "SHARD_BY_PUBLISHER");
+        env_override(vars, &mut config.history_size, "HISTORY_SIZE")?;
+        env_override(vars, &mut config.tls_cert_path, "TLS_CERT_PATH")?;
+        env_override(vars, &mut config.tls_key_path, "TLS_KEY_PATH")?;
+        list_override(
+            vars,
+            &mut config.cors_allowed_origins,
+            "CORS_ALLOWED_ORIGINS",
+        )?;
+        list_override(vars, &mut config.pub_allow_cidrs, "PUB_ALLOW_CIDRS")?;
+        flag_override(vars, &mut config.trust_proxy, "TRUST_PROXY");
+        list_override(vars, &mut config.trusted_proxy_cidrs, "TRUSTED_PROXY_CIDRS")?;
+        env_override(vars, &mut config.redis_url, "REDIS_URL")?;
+        env_override(vars, &mut config.redis_channel, "REDIS_CHANNEL")?;
+        env_override(vars, &mut config.mqtt_broker_url, "MQTT_BROKER_URL")?;
+        env_override(vars, &mut config.webhooks_path, "WEBHOOKS_PATH")?;
+        env_override(vars, &mut config.admin_password, "ADMIN_PASSWORD")?;
+        env_override(vars, &mut config.ack_buffer_size, "ACK_BUFFER_SIZE")?;
+        env_override(vars, &mut config.metrics_max_topics, "METRICS_MAX_TOPICS")?;
+        env_override(vars, &mut config.max_topics_per_conn, "MAX_TOPICS_PER_CONN")?;
+        env_override(vars, &mut config.db_path, "DB_PATH")?;
+        env_override(vars, &mut config.db_max_rows, "DB_MAX_ROWS")?;
+        env_override(vars, &mut config.db_max_age, "DB_MAX_AGE")?;
+        Ok(config)
+    }
+}
+
+/// What `/` serves: a redirect to `HOMEPAGE_URL` for `true`, the dashboard for
+/// `dashboard` and nothing for any other value.
+#[derive(Clone, Copy)]
+pub(crate) enum Homepage {
+    Redirect,
+    Dashboard,
+    Disabled,
+}
+
+impl FromStr for Homepage {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(match value {
+            "true" | "t" | "1" => Self::Redirect,
+            "dashboard" => Self::Dashboard,
+            _ => Self::Disabled,
+        })
+    }
+}
+
+/// What happens to a websocket subscriber that lags so far behind that messages
+/// were dropped from the broadcast channel before it received them.
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SlowSubscriberPolicy {
+    /// Skips the dropped messages and carries on with the oldest one left.
+    Skip,
+    /// Closes the connection with [`TOO_SLOW_CLOSE_CODE`](crate::handlers::TOO_SLOW_CLOSE_CODE).
+    Disconnect,
+}
+
+impl FromStr for SlowSubscriberPolicy {
+    type Err = serde::de::value::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::deserialize(value.into_deserializer())
+    }
+}
+
+impl<'de> Deserialize<'de> for Homepage {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum FlagOrName {
+            Flag(bool),
+            Name(String),
+        }
+
+        Ok(match FlagOrName::deserialize(deserializer)? {
+            FlagOrName::Flag(true) => Self::Redirect,
+            FlagOrName::Flag(false) => Self::Disabled,
+            FlagOrName::Name(name) => name.parse().unwrap_or_else(|never| match never {}),
+        })
+    }
+}
+
+/// The environment variables, leaving out those that are not Unicode like
+/// `std::env::var` does.
+fn env_vars() -> HashMap<String, String> {
+    std::env::vars_os()
+        .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+        .collect()
+}
+
+fn env_override<T>(
+    vars: &HashMap<String, String>,
+    field: &mut Option<T>,
+    key: &str,
+) -> anyhow::Result<()>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    if let Some(value) = vars.get(key) {
+        *field = Some(
+            value
+                .parse()
+                .with_context(|| format!("invalid value `{value}` for {key}"))?,
+        );
+    }
+    Ok(())
+}
+
+/// Overrides `field` with the comma-separated `name:value` pairs of `key`, if
+/// set, where `expected` shows the form of a pair in errors.
+fn pairs_override(
+    vars: &HashMap<String, String>,
+    field: &mut Option<HashMap<String, String>>,
+    key: &str,
+    expected: &str,
+) -> anyhow::Result<()> {
+    if let Some(pairs) = vars.get(key) {
+        let pairs = pairs
+            .split(',')
+            .map(|pair| {
+                pair.split_once(':')
+                    .map(|(name, value)| (name.trim().to_string(), value.to_string()))
+                    .with_context(|| {
+                        format!("invalid pair `{pair}` in {key}, expected `{expected}`")
+                    })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        *field = Some(pairs);
+    }
+    Ok(())
+}
+
+/// Overrides `field` with the comma-separated values of `key`, if set.
+fn list_override<T>(
+    vars: &HashMap<String, String>,
+    field: &mut Option<Vec<T>>,
+    key: &str,
+) -> anyhow::Result<()>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    if let Some(values) = vars.get(key) {
+        let values = values
+            .split(',')
+            .map(|value| {
+                value
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid value `{value}` in {key}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        *field = Some(values);
+    }
+    Ok(())
+}
+
+/// Reads a secret such as a Docker or Kubernetes secret mount, without the
+/// trailing newline.
+pub(crate) fn read_secret(path: &str) -> anyhow::Result<String> {
+    let secret = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read secret from `{path}`"))?;
+    Ok(secret.trim_end_matches(['\n', '\r']).to_string())
+}
+
+fn flag_override(vars: &HashMap<String, String>, field: &mut Option<bool>, key: &str) {
+    if let Some(value) = vars.get(key) {
+        *field = Some(matches!(value.as_str(), "true" | "t" | "1"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `contents` to a config file of its own for each test and returns
+    /// the variables pointing `CONFIG_PATH` at it.
+    fn config_file(name: &str, contents: &str) -> HashMap<String, String> {
+        let path =
+            std::env::temp_dir().join(format!("isimud-config-{}-{name}.toml", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let path = path.to_str().unwrap().to_string();
+        HashMap::from([(String::from("CONFIG_PATH"), path)])
+    }
+
+    fn remove(vars: &HashMap<String, String>) {
+        let _ = std::fs::remove_file(&vars["CONFIG_PATH"]);
+    }
+
+    #[test]
+    fn reads_settings_from_the_file() {
+        let vars = config_file(
+            "file",
+            r#"
+                password = "secret"
+                port = 8080
+                ip = ["127.0.0.1", "::1"]
+                dev_mode = true
+                slow_subscriber_policy = "disconnect"
+                credentials = { alice = "a-pass" }
+            "#,
+        );
+        let config = Config::load_from(&vars);
+        remove(&vars);
+        let config = config.unwrap();
+        assert_eq!(config.password.as_deref(), Some("secret"));
+        assert_eq!(config.port, Some(8080));
+        assert_eq!(
+            config.ip,
+            Some(vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()])
+        );
+        assert_eq!(config.dev_mode, Some(true));
+        assert!(matches!(
+            config.slow_subscriber_policy,
+            Some(SlowSubscriberPolicy::Disconnect)
+        ));
+        assert_eq!(config.credentials.unwrap()["alice"], "a-pass");
+        assert_eq!(config.history_size, None);
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let mut vars = config_file(
+            "override",
+            r#"
+                password = "from-file"
+                port = 8080
+                history_size = 10
+                dev_mode = true
+            "#,
+        );
+        vars.extend([
+            (String::from("PASSWORD"), String::from("from-env")),
+            (String::from("PORT"), String::from("9090")),
+            (String::from("DEV_MODE"), String::from("false")),
+            (String::from("CORS_ALLOWED_ORIGINS"), String::from("a, b")),
+        ]);
+        let config = Config::load_from(&vars);
+        remove(&vars);
+        let config = config.unwrap();
+        assert_eq!(config.password.as_deref(), Some("from-env"));
+        assert_eq!(config.port, Some(9090));
+        assert_eq!(config.dev_mode, Some(false));
+        assert_eq!(
+            config.cors_allowed_origins,
+            Some(vec![String::from("a"), String::from("b")])
+        );
+        assert_eq!(config.history_size, Some(10));
+    }
+
+    #[test]
+    fn fails_on_a_malformed_file() {
+        for (name, contents) in [
+            ("syntax", "port = "),
+            ("type", "port = \"eighty\""),
+            ("unknown", "prot = 8080"),
+        ] {
+            let vars = config_file(name, contents);
+            let result = Config::load_from(&vars);
+            remove(&vars);
+            let error = format!("{:#}", result.err().unwrap());
+            assert!(error.starts_with("failed to parse config in"), "{error}");
+        }
+    }
+
+    #[test]
+    fn fails_on_a_missing_file() {
+        let vars = HashMap::from([(
+            String::from("CONFIG_PATH"),
+            String::from("/nonexistent/isimud.toml"),
+        )]);
+        let error = format!("{:#}", Config::load_from(&vars).err().unwrap());
+        assert!(error.starts_with("failed to read config from"), "{error}");
+    }
+
+    #[test]
+    fn fails_on_a_malformed_variable() {
+        let vars = HashMap::from([(String::from("PORT"), String::from("eighty"))]);
+        let error = Config::load_from(&vars).err().unwrap().to_string();
+        assert_eq!(error, "invalid value `eighty` for PORT");
+    }
+}
@@ -0,0 +1,1590 @@
+//! The HTTP and websocket endpoints.
+
+use axum::{
+    async_trait,
+    body::{Body, Bytes, HttpBody},
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        FromRequest, Query, State, WebSocketUpgrade,
+    },
+    http::{header, HeaderMap, Request, StatusCode},
+    response::{
+        sse::{Event, Sse},
+        Html, IntoResponse, Redirect, Response,
+    },
+    BoxError, Form, Json, TypedHeader,
+};
+use futures::{FutureExt, SinkExt, Stream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::json;
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::{
+    auth::{
+        authenticate_admin, authenticate_publisher, authorize_subscriber, check_credentials,
+        AuthError, ClientAddr, PublisherAuth, SubscriberBearer,
+    },
+    config::{Homepage, SlowSubscriberPolicy},
+    metrics::{Metrics, LATENCY_SAMPLE_INTERVAL},
+    pubsub::{
+        gzip, unix_millis, update_feed, validate_topic, ClientMsg, ControlMsg, Feed, Mode, Part,
+        Payload, PubSubMsg, PublisherMsg, Scope, Session, SharedState, StreamPart, Subscriber,
+        SubscriberMsg, Subscriptions, COMPRESSION_THRESHOLD,
+    },
+};
+
+const MAX_POLL_TIMEOUT_SECS: u64 = 300;
+
+/// Most topics listed by one `/topics` response.
+const MAX_LISTED_TOPICS: usize = 1000;
+
+/// Close code for subscribers disconnected by `SLOW_SUBSCRIBER_POLICY`, from the
+/// range reserved for applications.
+const TOO_SLOW_CLOSE_CODE: u16 = 4000;
+
+/// A request body in the format of its `Content-Type`: MessagePack, a form, or
+/// JSON, which is also what anything else is rejected as not being.
+pub(crate) struct PublishBody<T>(T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for PublishBody<T>
+where
+    T: DeserializeOwned,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let mime = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(str::trim);
+        match mime {
+            Some("application/msgpack" | "application/x-msgpack") => {}
+            Some("application/x-www-form-urlencoded") => {
+                return match Form::from_request(req, state).await {
+                    Ok(Form(value)) => Ok(Self(value)),
+                    Err(rejection) => Err(rejection.into_response()),
+                };
+            }
+            _ => {
+                return match Json::from_request(req, state).await {
+                    Ok(Json(value)) => Ok(Self(value)),
+                    Err(rejection) => Err(rejection.into_response()),
+                };
+            }
+        }
+        let bytes = Bytes::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        rmp_serde::from_slice(&bytes).map(Self).map_err(|error| {
+            let message = format!("Failed to deserialize the MessagePack request body: {error}");
+            (StatusCode::UNPROCESSABLE_ENTITY, message).into_response()
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PubParams {
+    /// Answers with 202 Accepted instead of 200 OK when nobody is subscribed.
+    #[serde(default)]
+    wait_for_subscriber: bool,
+    /// Answers with 409 Conflict and publishes nothing when no subscription
+    /// matches.
+    #[serde(default)]
+    require_subscriber: bool,
+    /// Runs every check without publishing, and answers with the number of
+    /// subscribers the message would have been handed to.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+pub(crate) async fn pub_handler(
+    auth: PublisherAuth,
+    Query(params): Query<PubParams>,
+    state: State<Arc<SharedState>>,
+    PublishBody(mut payload): PublishBody<PublisherMsg>,
+) -> Result<Response, AuthError> {
+    let received_at = Instant::now();
+    let publisher = authenticate_publisher(&state, auth)?;
+    payload.topic = validate_topic(&payload.topic)?;
+    payload.reply_to = payload
+        .reply_to
+        .as_deref()
+        .map(validate_topic)
+        .transpose()?;
+    state.authorize_topic(&publisher, &payload.topic)?;
+    if params.dry_run {
+        // Leaves the rate limit alone so that validating does not use it up.
+        let would_deliver_to = state.receiver_count(&PubSubMsg::new(payload, publisher));
+        return Ok(Json(json!({ "would_deliver_to": would_deliver_to })).into_response());
+    }
+    state.check_rate_limit(&publisher)?;
+    let msg = PubSubMsg::new(payload, publisher);
+    let matched_subscribers = state.matched_subscribers(&msg);
+    if params.require_subscriber && matched_subscribers == 0 {
+        return Ok((StatusCode::CONFLICT, Json(json!({ "delivered_to": 0 }))).into_response());
+    }
+    let delivered_to = state.publish(msg).await?;
+    state
+        .metrics
+        .publish_duration
+        .observe(received_at.elapsed());
+    let status = if params.wait_for_subscriber && delivered_to == 0 {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::OK
+    };
+    let body = json!({
+        "delivered_to": delivered_to,
+        "matched_subscribers": matched_subscribers,
+    });
+    Ok((status, Json(body)).into_response())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RequestParams {
+    /// Seconds to wait for the reply.
+    timeout: Option<u64>,
+}
+
+/// Publishes a request like `/pub` and answers with the first text reply
+/// published to its `reply_to` topic with the same `correlation_id`, both of
+/// which are generated if the request leaves them out.
+pub(crate) async fn request_handler(
+    auth: PublisherAuth,
+    Query(params): Query<RequestParams>,
+    state: State<Arc<SharedState>>,
+    PublishBody(mut payload): PublishBody<PublisherMsg>,
+) -> Result<Response, AuthError> {
+    let publisher = authenticate_publisher(&state, auth)?;
+    payload.topic = validate_topic(&payload.topic)?;
+    let reply_to = match &payload.reply_to {
+        Some(reply_to) => validate_topic(reply_to)?,
+        None => format!("_reply/{}", Uuid::new_v4()),
+    };
+    let correlation_id = payload
+        .correlation_id
+        .get_or_insert_with(|| Uuid::new_v4().to_string())
+        .clone();
+    payload.reply_to = Some(reply_to.clone());
+    state.authorize_topic(&publisher, &payload.topic)?;
+    state.check_rate_limit(&publisher)?;
+    let mut subscriptions = Subscriptions::new(Scope::default());
+    subscriptions.apply(ClientMsg::Subscribe(SubscriberMsg {
+        publisher: None,
+        topic: vec![reply_to],
+        session: None,
+        filter: None,
+        compress: false,
+        since_seq: None,
+        coalesce_ms: None,
+        mode: None,
+    }));
+    // Listens before publishing so that an immediate reply is not missed.
+    let mut feed = Feed::new();
+    update_feed(&mut feed, state.receivers(&subscriptions));
+    let delivered_to = state.publish(PubSubMsg::new(payload, publisher)).await?;
+    tracing::debug!(event = "request", %correlation_id, delivered_to, "waiting for reply");
+    let timeout = Duration::from_secs(params.timeout.unwrap_or(30).min(MAX_POLL_TIMEOUT_SECS));
+    let reply = async {
+        while let Some((_, data)) = feed.next().await {
+            if let Some(delivered) = data
+                .ok()
+                .filter(|data| {
+                    subscriptions.matches(data)
+                        && !data.is_expired()
+                        && data.msg.correlation_id.as_ref() == Some(&correlation_id)
+                })
+                .and_then(|data| data.to_delivered())
+            {
+                return Some(delivered);
+            }
+        }
+        None
+    };
+    match tokio::time::timeout(timeout, reply).await {
+        Ok(Some(delivered)) => Ok(Json(delivered).into_response()),
+        _ => Err(AuthError::NoReply),
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct BatchParams {
+    /// Stops at the first message that cannot be published.
+    #[serde(default)]
+    fail_fast: bool,
+}
+
+/// Publishes several messages in order with a single authentication. Every
+/// message is checked against the ACL and rate limit on its own, and the
+/// response lists the outcome of each one.
+pub(crate) async fn pub_batch_handler(
+    auth: PublisherAuth,
+    Query(params): Query<BatchParams>,
+    state: State<Arc<SharedState>>,
+    Json(payloads): Json<Vec<PublisherMsg>>,
+) -> Result<Response, AuthError> {
+    let publisher = authenticate_publisher(&state, auth)?;
+    let mut results = Vec::with_capacity(payloads.len());
+    for mut payload in payloads {
+        let result = validate_topic(&payload.topic)
+            .and_then(|topic| {
+                payload.topic = topic;
+                payload.reply_to = payload
+                    .reply_to
+                    .as_deref()
+                    .map(validate_topic)
+                    .transpose()?;
+                state.authorize_topic(&publisher, &payload.topic)
+            })
+            .and_then(|()| state.check_rate_limit(&publisher));
+        let result = match result {
+            Ok(()) => {
+                state
+                    .publish(PubSubMsg::new(payload, publisher.clone()))
+                    .await
+            }
+            Err(error) => Err(error),
+        };
+        match result {
+            Ok(delivered_to) => {
+                results.push(json!({ "delivered_to": delivered_to }));
+            }
+            Err(error) => {
+                results.push(error.to_json());
+                if params.fail_fast {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(Json(results).into_response())
+}
+
+/// Publishes the raw request body as a binary message, taking the topic from the
+/// `X-Topic` header, the retain flag from the optional `X-Retain` header, the
+/// TTL from the optional `X-TTL-Ms` header, the partition key from the
+/// optional `X-Key` header and the reply topic and correlation ID from the
+/// optional `X-Reply-To` and `X-Correlation-Id` headers.
+pub(crate) async fn pub_binary_handler(
+    auth: PublisherAuth,
+    headers: HeaderMap,
+    state: State<Arc<SharedState>>,
+    body: Bytes,
+) -> Result<Response, AuthError> {
+    let publisher = authenticate_publisher(&state, auth)?;
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let topic = validate_topic(header("x-topic").ok_or(AuthError::MissingTopic)?)?;
+    let retain = matches!(header("x-retain"), Some("true" | "t" | "1"));
+    let ttl_ms = header("x-ttl-ms").and_then(|ttl_ms| ttl_ms.parse().ok());
+    let payload = PublisherMsg {
+        topic,
+        data: Payload::Binary(body.as_ref().into()),
+        retain,
+        ttl_ms,
+        key: header("x-key").map(str::to_string),
+        reply_to: header("x-reply-to").map(validate_topic).transpose()?,
+        correlation_id: header("x-correlation-id").map(str::to_string),
+    };
+    state.authorize_topic(&publisher, &payload.topic)?;
+    state.check_rate_limit(&publisher)?;
+    let delivered_to = state.publish(PubSubMsg::new(payload, publisher)).await?;
+    Ok(Json(json!({ "delivered_to": delivered_to })).into_response())
+}
+
+/// Publishes the request body while it arrives, as text messages of at most
+/// `STREAM_CHUNK_BYTES` between a `begin` and an `end` message (see
+/// [`StreamPart`]), so that neither the server nor subscribers hold all of it
+/// at once. The topic, partition key, reply topic and correlation ID are taken
+/// from the same headers as for `/pub/binary`.
+pub(crate) async fn pub_stream_handler(
+    auth: PublisherAuth,
+    headers: HeaderMap,
+    state: State<Arc<SharedState>>,
+    request: Request<Body>,
+) -> Result<Response, AuthError> {
+    let publisher = authenticate_publisher(&state, auth)?;
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let topic = validate_topic(header("x-topic").ok_or(AuthError::MissingTopic)?)?;
+    let template = PublisherMsg {
+        topic,
+        data: Payload::Text("".into()),
+        retain: false,
+        ttl_ms: None,
+        key: header("x-key").map(str::to_string),
+        reply_to: header("x-reply-to").map(validate_topic).transpose()?,
+        correlation_id: header("x-correlation-id").map(str::to_string),
+    };
+    state.authorize_topic(&publisher, &template.topic)?;
+    state.check_rate_limit(&publisher)?;
+    let id = Uuid::new_v4();
+    let mut index = 0;
+    let mut part = |part, data: String| {
+        let payload = PublisherMsg {
+            data: Payload::Text(data.into()),
+            ..template.clone()
+        };
+        let mut msg = PubSubMsg::new(payload, publisher.clone());
+        msg.stream = Some(StreamPart { id, part, index });
+        index += 1;
+        msg
+    };
+    let delivered_to = state.deliver(part(Part::Begin, String::new())).await?;
+    let mut body = request.into_body();
+    let mut pending = Vec::new();
+    loop {
+        let chunk = body.data().await;
+        let last = chunk.is_none();
+        match chunk {
+            Some(Ok(chunk)) => pending.extend_from_slice(&chunk),
+            Some(Err(error)) => {
+                tracing::info!(event = "stream_aborted", "streamed publish failed: {error}");
+                state.deliver(part(Part::Abort, String::new())).await?;
+                return Err(AuthError::InvalidPayload);
+            }
+            None => {}
+        }
+        // Waits for a whole part unless the body is over.
+        while pending.len() >= state.stream_chunk_bytes || last && !pending.is_empty() {
+            let Some(text) = split_text(&mut pending, state.stream_chunk_bytes) else {
+                state.deliver(part(Part::Abort, String::new())).await?;
+                return Err(AuthError::InvalidPayload);
+            };
+            state.deliver(part(Part::Continue, text)).await?;
+        }
+        if last {
+            break;
+        }
+    }
+    state.deliver(part(Part::End, String::new())).await?;
+    Ok(Json(json!({ "delivered_to": delivered_to, "parts": index })).into_response())
+}
+
+/// Takes the longest text of at most `max` bytes off the front of `pending`,
+/// leaving a character cut off at the end for the next part. Returns `None` if
+/// `pending` does not start with valid UTF-8.
+fn split_text(pending: &mut Vec<u8>, max: usize) -> Option<String> {
+    let end = max.min(pending.len());
+    let valid = match std::str::from_utf8(&pending[..end]) {
+        Ok(text) => text.len(),
+        Err(error) => error.valid_up_to(),
+    };
+    if valid == 0 {
+        return None;
+    }
+    let rest = pending.split_off(valid);
+    let text = std::mem::replace(pending, rest);
+    Some(String::from_utf8(text).expect("checked to be valid UTF-8"))
+}
+
+/// Lists the connected websocket subscribers and what they are subscribed to.
+pub(crate) async fn admin_subscriptions_handler(
+    server_info: Option<TypedHeader<headers::Authorization<headers::authorization::Basic>>>,
+    state: State<Arc<SharedState>>,
+) -> Result<Response, AuthError> {
+    authenticate_admin(&state, server_info)?;
+    let mut subscribers: Vec<_> = state
+        .subscribers
+        .iter()
+        .map(|entry| {
+            let subscriber = entry.value();
+            let mut subscriptions: Vec<_> = subscriber
+                .subscriptions
+                .lock()
+                .unwrap()
+                .entries
+                .iter()
+                .map(|(pair, filter)| (pair.clone(), filter.clone()))
+                .collect();
+            subscriptions.sort_by(|(a, _), (b, _)| a.cmp(b));
+            let subscriptions: Vec<_> = subscriptions
+                .into_iter()
+                .map(|((publisher, topic), filter)| {
+                    json!({ "publisher": publisher, "topic": topic, "filter": filter })
+                })
+                .collect();
+            (
+                *entry.key(),
+                json!({
+                    "conn_id": subscriber.conn_id,
+                    "addr": subscriber.addr,
+                    "user_agent": subscriber.user_agent,
+                    "identity": subscriber.identity,
+                    "subscriptions": subscriptions,
+                }),
+            )
+        })
+        .collect();
+    subscribers.sort_by_key(|(id, _)| *id);
+    let subscribers: Vec<_> = subscribers
+        .into_iter()
+        .map(|(_, subscriber)| subscriber)
+        .collect();
+    Ok(Json(json!({ "subscribers": subscribers })).into_response())
+}
+
+/// Acts on the `authenticate` and `publish` control messages of a websocket
+/// client. Any other message is handed back to be applied to its subscriptions.
+async fn handle_publisher_msg(
+    state: &Arc<SharedState>,
+    who: SocketAddr,
+    publisher: &mut Option<String>,
+    msg: ClientMsg,
+) -> Result<Option<ClientMsg>, AuthError> {
+    match msg {
+        ClientMsg::Control(ControlMsg::Authenticate { username, password }) => {
+            state.authorize_source(who.ip())?;
+            *publisher = Some(check_credentials(state, &username, &password)?);
+            Ok(None)
+        }
+        ClientMsg::Control(ControlMsg::Publish(mut payload)) => {
+            let publisher = publisher
+                .clone()
+                .ok_or_else(|| state.metrics.auth_failure(AuthError::MissingCredentials))?;
+            payload.topic = validate_topic(&payload.topic)?;
+            payload.reply_to = payload
+                .reply_to
+                .as_deref()
+                .map(validate_topic)
+                .transpose()?;
+            state.authorize_topic(&publisher, &payload.topic)?;
+            state.check_rate_limit(&publisher)?;
+            state.publish(PubSubMsg::new(payload, publisher)).await?;
+            Ok(None)
+        }
+        msg => Ok(Some(msg)),
+    }
+}
+
+pub(crate) async fn metrics_handler(state: State<Arc<SharedState>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
+pub(crate) async fn health_handler() -> StatusCode {
+    StatusCode::OK
+}
+
+pub(crate) async fn ready_handler(state: State<Arc<SharedState>>) -> StatusCode {
+    if state.ready.load(Ordering::Relaxed) && state.auth_reachable().await {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+pub(crate) async fn homepage_handler(state: State<Arc<SharedState>>) -> Response {
+    let settings = state.settings.load();
+    match settings.homepage {
+        Homepage::Redirect => Redirect::to(settings.homepage_url.as_str()).into_response(),
+        Homepage::Dashboard => Html(include_str!("dashboard.html")).into_response(),
+        Homepage::Disabled => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// The message formats a websocket subscriber can ask for with the
+/// `Sec-WebSocket-Protocol` header, instead of the `RAW_DELIVERY` default.
+#[derive(Clone, Copy, PartialEq)]
+enum Subprotocol {
+    Json,
+    Raw,
+    /// The envelope as MessagePack in binary frames, which can also carry
+    /// binary messages.
+    Msgpack,
+}
+
+impl Subprotocol {
+    const ALL: [Self; 3] = [Self::Json, Self::Raw, Self::Msgpack];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Json => "isimud.v1.json",
+            Self::Raw => "isimud.v1.raw",
+            Self::Msgpack => "isimud.v1.msgpack",
+        }
+    }
+
+    /// The first of the offered subprotocols that is supported, or `None` if
+    /// none were offered at all.
+    fn negotiate(headers: &HeaderMap) -> Result<Option<Self>, AuthError> {
+        let mut offered = headers
+            .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .peekable();
+        if offered.peek().is_none() {
+            return Ok(None);
+        }
+        offered
+            .find_map(|name| {
+                Self::ALL
+                    .into_iter()
+                    .find(|subprotocol| subprotocol.name() == name)
+            })
+            .map(Some)
+            .ok_or(AuthError::UnsupportedSubprotocol)
+    }
+}
+
+pub(crate) async fn ws_handler(
+    ws: WebSocketUpgrade,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+    SubscriberBearer(bearer): SubscriberBearer,
+    headers: HeaderMap,
+    ClientAddr(addr): ClientAddr,
+    state: State<Arc<SharedState>>,
+) -> Result<Response, AuthError> {
+    let subprotocol = Subprotocol::negotiate(&headers)?;
+    let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
+        user_agent.to_string()
+    } else {
+        String::from("Unknown browser")
+    };
+    let conn_id = Uuid::new_v4();
+    let span = tracing::info_span!("connection", %conn_id);
+    span.in_scope(|| {
+        tracing::info!(%addr, event = "connect", %user_agent, "websocket client connected");
+    });
+    // The slot is held until the connection is closed.
+    let slot = match &state.connection_slots {
+        Some(slots) => Some(
+            slots
+                .clone()
+                .try_acquire_owned()
+                .map_err(|_| AuthError::TooManyConnections)?,
+        ),
+        None => None,
+    };
+    let ip_slot = state.acquire_ip_slot(addr.ip())?;
+    let scope = authorize_subscriber(&state, bearer).await?;
+    if let Some(identity) = &scope.identity {
+        span.in_scope(|| {
+            tracing::info!(%addr, event = "authorized", %identity, "websocket client authorized");
+        });
+    }
+    let ws = match subprotocol {
+        Some(subprotocol) => ws.protocols([subprotocol.name()]),
+        None => ws,
+    };
+    Ok(ws
+        .max_message_size(state.ws_max_message_bytes)
+        .max_frame_size(state.ws_max_message_bytes)
+        .on_upgrade(move |socket| async move {
+            let connection = Connection {
+                id: conn_id,
+                addr,
+                user_agent,
+            };
+            handle_socket(socket, connection, state, scope, subprotocol)
+                .instrument(span)
+                .await;
+            drop(slot);
+            drop(ip_slot);
+        }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SseParams {
+    publisher: Option<String>,
+    topic: String,
+}
+
+pub(crate) async fn sse_handler(
+    Query(params): Query<SseParams>,
+    SubscriberBearer(bearer): SubscriberBearer,
+    ClientAddr(addr): ClientAddr,
+    state: State<Arc<SharedState>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AuthError> {
+    let topic = validate_topic(&params.topic)?;
+    let scope = authorize_subscriber(&state, bearer).await?;
+    tracing::info!(
+        %addr,
+        event = "subscribe",
+        %topic,
+        identity = scope.identity.as_deref(),
+        "subscribed over SSE",
+    );
+    let mut subscriptions = Subscriptions::new(scope);
+    let rejected = subscriptions.apply(ClientMsg::Subscribe(SubscriberMsg {
+        publisher: params.publisher,
+        topic: vec![topic],
+        session: None,
+        filter: None,
+        compress: false,
+        since_seq: None,
+        coalesce_ms: None,
+        mode: None,
+    }));
+    if !rejected.is_empty() {
+        return Err(state.metrics.auth_failure(AuthError::Forbidden));
+    }
+    let mut feed = Feed::new();
+    update_feed(&mut feed, state.receivers(&subscriptions));
+    let subscriptions = Arc::new(Mutex::new(subscriptions));
+    let registration = state.register_filters(subscriptions.clone());
+    let keepalive = state.sse_keepalive;
+    let keepalive_period = keepalive.max(Duration::from_secs(1));
+    let keepalive_interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + keepalive_period,
+        keepalive_period,
+    );
+    // The receivers and the registration are dropped together with the stream
+    // once the client disconnects.
+    let stream = futures::stream::unfold(
+        (feed, subscriptions, keepalive_interval, registration),
+        move |(mut feed, subscriptions, mut keepalive_interval, registration)| async move {
+            loop {
+                let next = tokio::select! {
+                    next = feed.next() => next?,
+                    // Only comes around once no event was sent for a whole
+                    // period, to keep proxies from closing the idle stream.
+                    _ = keepalive_interval.tick(), if !keepalive.is_zero() => {
+                        let comment = Event::default().comment("keepalive");
+                        return Some((
+                            Ok(comment),
+                            (feed, subscriptions, keepalive_interval, registration),
+                        ));
+                    }
+                };
+                match next {
+                    (_, Ok(data))
+                        if subscriptions.lock().unwrap().matches(&data) && !data.is_expired() =>
+                    {
+                        if let Some(delivered) = data.to_delivered() {
+                            let event = Event::default()
+                                .json_data(delivered)
+                                .expect("envelope is always serializable");
+                            keepalive_interval.reset();
+                            return Some((
+                                Ok(event),
+                                (feed, subscriptions, keepalive_interval, registration),
+                            ));
+                        }
+                    }
+                    (_, Ok(_)) => {}
+                    (_, Err(BroadcastStreamRecvError::Lagged(skipped))) => {
+                        tracing::warn!(event = "lag", skipped, "SSE client lagged behind");
+                        let metrics = &registration.state.metrics;
+                        metrics.lag_events.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        },
+    );
+    Ok(Sse::new(stream))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PollParams {
+    publisher: Option<String>,
+    topic: String,
+    /// Seconds to wait for a matching message.
+    timeout: Option<u64>,
+}
+
+pub(crate) async fn poll_handler(
+    Query(params): Query<PollParams>,
+    SubscriberBearer(bearer): SubscriberBearer,
+    state: State<Arc<SharedState>>,
+) -> Result<Response, AuthError> {
+    let topic = validate_topic(&params.topic)?;
+    let scope = authorize_subscriber(&state, bearer).await?;
+    let mut subscriptions = Subscriptions::new(scope);
+    let rejected = subscriptions.apply(ClientMsg::Subscribe(SubscriberMsg {
+        publisher: params.publisher,
+        topic: vec![topic],
+        session: None,
+        filter: None,
+        compress: false,
+        since_seq: None,
+        coalesce_ms: None,
+        mode: None,
+    }));
+    if !rejected.is_empty() {
+        return Err(state.metrics.auth_failure(AuthError::Forbidden));
+    }
+    let mut feed = Feed::new();
+    update_feed(&mut feed, state.receivers(&subscriptions));
+    let subscriptions = Arc::new(Mutex::new(subscriptions));
+    let _registration = state.register_filters(subscriptions.clone());
+    let timeout = Duration::from_secs(params.timeout.unwrap_or(30).min(MAX_POLL_TIMEOUT_SECS));
+    let next_match = async {
+        while let Some((_, data)) = feed.next().await {
+            if let Some(delivered) = data
+                .ok()
+                .filter(|data| {
+                    data.stream.is_none()
+                        && subscriptions.lock().unwrap().matches(data)
+                        && !data.is_expired()
+                })
+                .and_then(|data| data.to_delivered())
+            {
+                return Some(delivered);
+            }
+        }
+        None
+    };
+    match tokio::time::timeout(timeout, next_match).await {
+        Ok(Some(delivered)) => Ok(Json(delivered).into_response()),
+        _ => Ok(StatusCode::NO_CONTENT.into_response()),
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct TopicsParams {
+    #[serde(default)]
+    prefix: String,
+}
+
+/// Lists the topics with a retained message or recent messages that the
+/// subscriber may subscribe to, so that it can find out what to subscribe to.
+pub(crate) async fn topics_handler(
+    Query(params): Query<TopicsParams>,
+    SubscriberBearer(bearer): SubscriberBearer,
+    state: State<Arc<SharedState>>,
+) -> Result<Response, AuthError> {
+    let scope = authorize_subscriber(&state, bearer).await?;
+    let mut topics: Vec<_> = state
+        .active_topics(&params.prefix)
+        .into_iter()
+        .filter(|topic| scope.allows(topic))
+        .collect();
+    let truncated = topics.len() > MAX_LISTED_TOPICS;
+    topics.truncate(MAX_LISTED_TOPICS);
+    Ok(Json(json!({ "topics": topics, "truncated": truncated })).into_response())
+}
+
+/// Turns messages into frames for one websocket connection, keeping a copy of
+/// each text message until it is acknowledged if the subscriber has a session.
+/// Acknowledgements need an envelope, so they are not available with
+/// `RAW_DELIVERY` or for binary messages, nor for streamed publishes.
+///
+/// Subscribers asking for compression receive large text frames as gzip
+/// compressed binary frames instead, and no binary messages, which would be
+/// indistinguishable from them.
+struct Delivery {
+    format: Subprotocol,
+    compress: bool,
+    session: Option<Arc<Mutex<Session>>>,
+    buffer_size: usize,
+    /// Sends sequence numbers without a session, for resuming with `since_seq`.
+    sequenced: bool,
+}
+
+impl Delivery {
+    /// The frame to send for `msg`, if this subscriber receives it at all.
+    fn to_message(&self, who: SocketAddr, msg: &PubSubMsg, metrics: &Metrics) -> Option<Message> {
+        let raw = match self.format {
+            Subprotocol::Json => false,
+            Subprotocol::Raw => true,
+            Subprotocol::Msgpack => {
+                let seq = (self.track(who, msg) || self.sequenced).then_some(msg.seq);
+                return Some(Message::Binary(msg.to_msgpack(seq)));
+            }
+        };
+        // Without an envelope, the data of a streamed publish is all there is.
+        if raw
+            && msg
+                .stream
+                .as_ref()
+                .is_some_and(|stream| stream.part != Part::Continue)
+        {
+            return None;
+        }
+        if !self.compress {
+            return Some(
+                self.tracked_envelope(who, msg)
+                    .map_or_else(|| msg.to_message(raw), Message::Text),
+            );
+        }
+        if matches!(msg.msg.data, Payload::Binary(_)) {
+            return None;
+        }
+        Some(match self.tracked_envelope(who, msg) {
+            Some(text) if text.len() >= COMPRESSION_THRESHOLD => {
+                let compressed = gzip(&text);
+                metrics.frame_compressed(text.len(), compressed.len());
+                Message::Binary(compressed)
+            }
+            Some(text) => Message::Text(text),
+            None => msg.to_compressed_message(raw, metrics),
+        })
+    }
+
+    /// The JSON envelope of `msg` with its sequence number, if it is tracked or
+    /// the subscriber asked for sequence numbers.
+    fn tracked_envelope(&self, who: SocketAddr, msg: &PubSubMsg) -> Option<String> {
+        let sequenced = self.track(who, msg) || self.sequenced && self.format != Subprotocol::Raw;
+        if !sequenced {
+            return None;
+        }
+        let mut delivered = msg.to_delivered()?;
+        delivered.seq = Some(msg.seq);
+        Some(serde_json::to_string(&delivered).expect("envelope is always serializable"))
+    }
+
+    /// Records `msg` as unacknowledged if the subscriber has a session, and
+    /// returns whether it did.
+    fn track(&self, who: SocketAddr, msg: &PubSubMsg) -> bool {
+        let (Some(session), Payload::Text(_)) = (&self.session, &msg.msg.data) else {
+            return false;
+        };
+        // Parts of streamed publishes would fill the buffer with what was not
+        // meant to be held at once.
+        if self.format == Subprotocol::Raw || msg.stream.is_some() {
+            return false;
+        }
+        let mut session = session.lock().unwrap();
+        session.outstanding.insert(msg.seq, msg.clone());
+        if session.outstanding.len() > self.buffer_size {
+            if let Some((dropped, _)) = session.outstanding.pop_first() {
+                tracing::warn!(
+                    addr = %who,
+                    event = "ack_overflow",
+                    seq = dropped,
+                    "dropped unacknowledged message",
+                );
+            }
+        }
+        true
+    }
+}
+
+/// Parses a text frame from a websocket client and logs it, leaving out the
+/// password of `authenticate` messages.
+fn parse_client_msg(who: SocketAddr, text: &str) -> Option<ClientMsg> {
+    let msg = serde_json::from_str::<ClientMsg>(text);
+    if let Ok(ClientMsg::Control(ControlMsg::Authenticate { username, .. })) = &msg {
+        tracing::info!(addr = %who, event = "authenticate", %username, "received credentials");
+    } else {
+        tracing::info!(addr = %who, event = "text", %text, "received text message");
+    }
+    msg.ok()
+}
+
+/// Confirms a subscribe to the client once its receivers are attached, with the
+/// topics that were not `rejected`.
+fn subscribed_frame(msg: &ClientMsg, rejected: &[String]) -> Option<Message> {
+    let (ClientMsg::Subscribe(sub) | ClientMsg::Control(ControlMsg::Subscribe(sub))) = msg else {
+        return None;
+    };
+    let publisher = sub.publisher.as_ref().filter(|publisher| *publisher != "*");
+    let topics: Vec<_> = sub
+        .topic
+        .iter()
+        .filter(|topic| !rejected.contains(topic))
+        .collect();
+    let confirmation = json!({ "type": "subscribed", "publisher": publisher, "topic": topics });
+    Some(Message::Text(confirmation.to_string()))
+}
+
+/// Normalizes the topics of a subscribe or unsubscribe with [`validate_topic`],
+/// leaving out invalid ones, and returns the error frame to send if there were
+/// any.
+fn validate_topics(who: SocketAddr, msg: &mut ClientMsg) -> Option<Message> {
+    let (ClientMsg::Subscribe(sub)
+    | ClientMsg::Control(ControlMsg::Subscribe(sub) | ControlMsg::Unsubscribe(sub))) = msg
+    else {
+        return None;
+    };
+    let mut error = None;
+    let mut invalid = Vec::new();
+    sub.topic = std::mem::take(&mut sub.topic)
+        .into_iter()
+        .filter_map(|topic| match validate_topic(&topic) {
+            Ok(topic) => Some(topic),
+            Err(topic_error) => {
+                error = Some(topic_error);
+                invalid.push(topic);
+                None
+            }
+        })
+        .collect();
+    let error = error?;
+    tracing::warn!(
+        addr = %who,
+        event = "invalid_topic",
+        topics = ?invalid,
+        "subscription has invalid topics",
+    );
+    Some(error.to_message())
+}
+
+/// Applies `MAX_TOPICS_PER_CONN` to a subscribe on top of `subscriptions`, and
+/// returns the error frame to send if some of its topics were left out.
+fn limit_topics(
+    state: &SharedState,
+    who: SocketAddr,
+    subscriptions: &Subscriptions,
+    msg: &mut ClientMsg,
+) -> Option<Message> {
+    let dropped = subscriptions.limit(msg, state.max_topics_per_conn);
+    if dropped.is_empty() {
+        return None;
+    }
+    tracing::warn!(
+        addr = %who,
+        event = "too_many_topics",
+        topics = ?dropped,
+        "subscription exceeds MAX_TOPICS_PER_CONN",
+    );
+    Some(AuthError::TooManyTopics.to_message())
+}
+
+/// Close frame whose reason carries the same `{"error": ...}` body as error replies.
+fn error_close(code: u16, error: &str) -> Message {
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: json!({ "error": error }).to_string().into(),
+    }))
+}
+
+/// The close frame answering a message the client sent that was larger than
+/// `WS_MAX_MESSAGE_BYTES`. Other receive errors mean the connection is gone.
+fn receive_error_close(who: SocketAddr, error: axum::Error) -> Option<Message> {
+    let error = error.into_inner();
+    let Some(tungstenite::Error::Capacity(error)) = error.downcast_ref::<tungstenite::Error>()
+    else {
+        tracing::debug!(addr = %who, "websocket receive failed: {error}");
+        return None;
+    };
+    tracing::info!(addr = %who, event = "too_big", "client message rejected: {error}");
+    Some(error_close(close_code::SIZE, "message too big"))
+}
+
+/// Close frame for a connection the server ends on its own account, whose reason
+/// also tells the client how many milliseconds to wait before reconnecting.
+fn reconnect_close(state: &SharedState, who: SocketAddr, code: u16, error: &str) -> Message {
+    let retry_after = state.reconnect_backoff.retry_after(who.ip());
+    let reason = json!({ "error": error, "retry_after_ms": retry_after.as_millis() as u64 });
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.to_string().into(),
+    }))
+}
+
+/// Who is on the other end of a websocket connection.
+struct Connection {
+    /// Identifies the connection in logs, and is sent to the client so that it
+    /// can be quoted in bug reports.
+    id: Uuid,
+    addr: SocketAddr,
+    user_agent: String,
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    connection: Connection,
+    State(state): State<Arc<SharedState>>,
+    scope: Scope,
+    subprotocol: Option<Subprotocol>,
+) {
+    let format = match subprotocol {
+        Some(subprotocol) => subprotocol,
+        None if state.raw_delivery => Subprotocol::Raw,
+        None => Subprotocol::Json,
+    };
+    // Clients that did not pick a subprotocol may not expect these.
+    let confirm_subscriptions = subprotocol.is_some_and(|format| format != Subprotocol::Raw);
+    let Connection {
+        id: conn_id,
+        addr: who,
+        user_agent,
+    } = connection;
+    let metrics_state = state.clone();
+    state
+        .metrics
+        .active_subscribers
+        .fetch_add(1, Ordering::Relaxed);
+    let connected_at = tokio::time::Instant::now();
+    let (mut sender, mut receiver) = socket.split();
+    // Catches everything published while waiting for the first subscription, so
+    // that messages sent right after connecting are not missed.
+    let (pending, latest_seq) = state.watch();
+    // Raw delivery has no room for anything but message data.
+    if format != Subprotocol::Raw {
+        let hello = json!({ "conn_id": conn_id, "seq": latest_seq }).to_string();
+        let _ = sender.send(Message::Text(hello)).await;
+    }
+    let mut publisher = None;
+    let mut session_id = None;
+    let mut compress = false;
+    let mut since_seq = None;
+    let mut coalesce = None;
+    let mut mode = None;
+    let mut confirmation = None;
+    let mut barriers = VecDeque::new();
+    let wait_for_subscription = async {
+        let mut subscriptions = None;
+        while let Some(msg) = receiver.next().await {
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(error) => {
+                    if let Some(close) = receive_error_close(who, error) {
+                        let _ = sender.send(close).await;
+                    }
+                    break;
+                }
+            };
+            match msg {
+                Message::Text(t) => {
+                    if let Some(msg) = parse_client_msg(who, &t) {
+                        let msg = match handle_publisher_msg(&state, who, &mut publisher, msg).await
+                        {
+                            Ok(Some(msg)) => msg,
+                            Ok(None) => continue,
+                            Err(error) => {
+                                let _ = sender.send(error.to_message()).await;
+                                continue;
+                            }
+                        };
+                        if let ClientMsg::Subscribe(sub)
+                        | ClientMsg::Control(ControlMsg::Subscribe(sub)) = &msg
+                        {
+                            session_id = sub.session.clone();
+                            compress = sub.compress;
+                            since_seq = sub.since_seq;
+                            mode = sub.mode;
+                            coalesce = sub
+                                .coalesce_ms
+                                .filter(|&ms| ms > 0)
+                                .map(Duration::from_millis);
+                        }
+                        if let ClientMsg::Control(ControlMsg::Barrier { id }) = &msg {
+                            barriers.push_back(id.clone());
+                        }
+                        let mut initial = Subscriptions::new(scope.clone());
+                        let mut msg = msg;
+                        if let Some(error) = validate_topics(who, &mut msg) {
+                            let _ = sender.send(error).await;
+                        }
+                        if let Some(error) = limit_topics(&state, who, &initial, &mut msg) {
+                            let _ = sender.send(error).await;
+                        }
+                        let rejected = initial.apply(msg.clone());
+                        confirmation = subscribed_frame(&msg, &rejected);
+                        if !rejected.is_empty() {
+                            tracing::warn!(
+                                addr = %who,
+                                event = "forbidden",
+                                identity = scope.identity.as_deref(),
+                                topics = ?rejected,
+                                "subscription rejected",
+                            );
+                        }
+                        subscriptions = Some(initial);
+                    } else {
+                        let _ = sender
+                            .send(error_close(
+                                close_code::INVALID,
+                                "invalid subscription payload",
+                            ))
+                            .await;
+                    }
+                    break;
+                }
+                Message::Ping(v) => {
+                    tracing::info!(addr = %who, event = "ping", payload = ?v, "received ping");
+                }
+                Message::Close(c) => {
+                    if let Some(cf) = c {
+                        tracing::info!(
+                            addr = %who,
+                            event = "close",
+                            code = cf.code,
+                            reason = %cf.reason,
+                            "received close"
+                        );
+                    } else {
+                        tracing::info!(
+                            addr = %who,
+                            event = "close",
+                            "received close without CloseFrame",
+                        );
+                    }
+                    break;
+                }
+                _ => {
+                    let _ = sender
+                        .send(error_close(
+                            close_code::UNSUPPORTED,
+                            "unexpected message type",
+                        ))
+                        .await;
+                    break;
+                }
+            }
+        }
+        subscriptions
+    };
+    let mut shutting_down = state.shutting_down.subscribe();
+    // The reason for closing the connection before it subscribed, if any.
+    let (subscriptions, closing) = tokio::select! {
+        waited = tokio::time::timeout(state.subscribe_timeout, wait_for_subscription) => match waited {
+            Ok(subscriptions) => (subscriptions, None),
+            Err(_) => {
+                tracing::info!(
+                    addr = %who,
+                    event = "subscribe_timeout",
+                    "client did not subscribe in time",
+                );
+                (None, Some((close_code::POLICY, "subscribe timeout")))
+            }
+        },
+        Ok(_) = shutting_down.wait_for(|&shutting_down| shutting_down) => {
+            state.metrics.drained_connections.fetch_add(1, Ordering::Relaxed);
+            tracing::info!(
+                addr = %who,
+                event = "going_away",
+                dropped = 0,
+                "closing connection for shutdown",
+            );
+            (None, Some((close_code::AWAY, "server shutting down")))
+        }
+    };
+    if let Some((code, error)) = closing {
+        let _ = tokio::time::timeout(
+            Duration::from_secs(5),
+            sender.send(reconnect_close(&state, who, code, error)),
+        )
+        .await;
+    }
+    if let Some(subscriptions) = subscriptions {
+        let (direct_tx, mut direct_rx) = mpsc::unbounded_channel();
+        let (feed_tx, mut feed_rx) = mpsc::unbounded_channel();
+        let (barrier_tx, mut barrier_rx) = mpsc::unbounded_channel();
+        let (receivers, history, gap) = state.subscribe(
+            &subscriptions,
+            pending,
+            since_seq,
+            matches!(mode, None | Some(Mode::Replay)),
+        );
+        let mut feed = Feed::new();
+        update_feed(&mut feed, receivers);
+        let scope_identity = scope.identity.clone();
+        let session = session_id
+            .clone()
+            .map(|id| state.attach_session(scope_identity.clone(), id));
+        let delivery = Arc::new(Delivery {
+            format,
+            // MessagePack frames are binary already and not worth telling apart
+            // from compressed ones.
+            compress: compress && format != Subprotocol::Msgpack,
+            session: session.clone(),
+            buffer_size: state.ack_buffer_size,
+            sequenced: since_seq.is_some(),
+        });
+        tracing::info!(
+            addr = %who,
+            event = "subscribe",
+            compress = delivery.compress,
+            session = session_id.as_deref(),
+            "websocket client subscribed",
+        );
+        // Messages a previous connection of the session did not acknowledge go
+        // first, and are not repeated by the history or retained messages.
+        let redelivered: Vec<_> = match &session {
+            Some(session) => {
+                let mut session = session.lock().unwrap();
+                session.outstanding.retain(|_, msg| !msg.is_expired());
+                session.outstanding.values().cloned().collect()
+            }
+            None => Vec::new(),
+        };
+        let history: Vec<_> = history
+            .into_iter()
+            .filter(|msg| !redelivered.iter().any(|sent| sent.seq == msg.seq))
+            .collect();
+        // History already carries an equally fresh value for these topics.
+        let retained: Vec<_> = matches!(mode, None | Some(Mode::Latest))
+            .then(|| state.retained_matching(&subscriptions))
+            .into_iter()
+            .flatten()
+            .filter(|retained| since_seq.is_none_or(|since_seq| retained.seq > since_seq))
+            .filter(|retained| {
+                !history
+                    .iter()
+                    .chain(&redelivered)
+                    .any(|msg| msg.name == retained.name && msg.msg.topic == retained.msg.topic)
+            })
+            .collect();
+        if let Some(confirmation) = confirmation.filter(|_| confirm_subscriptions) {
+            let _ = direct_tx.send(confirmation);
+        }
+        if let Some(since_seq) = since_seq.filter(|_| gap && format != Subprotocol::Raw) {
+            tracing::info!(
+                addr = %who,
+                event = "gap",
+                since_seq,
+                "resuming subscriber missed messages",
+            );
+            let gap = json!({ "gap": { "since_seq": since_seq } }).to_string();
+            let _ = direct_tx.send(Message::Text(gap));
+        }
+        for msg in redelivered.iter().chain(&retained).chain(&history) {
+            if let Some(message) = delivery.to_message(who, msg, &state.metrics) {
+                let _ = direct_tx.send(message);
+            }
+        }
+        let subscriptions = Arc::new(Mutex::new(subscriptions));
+        let subscriber_id = state.next_subscriber_id.fetch_add(1, Ordering::Relaxed);
+        state.subscribers.insert(
+            subscriber_id,
+            Subscriber {
+                conn_id,
+                addr: who,
+                user_agent,
+                identity: scope.identity.clone(),
+                subscriptions: subscriptions.clone(),
+            },
+        );
+        let registration = state.register_filters(subscriptions.clone());
+        let send_subscriptions = subscriptions.clone();
+        let send_delivery = delivery.clone();
+        let send_state = state.clone();
+        let last_pong = Arc::new(Mutex::new(Instant::now()));
+        let send_last_pong = last_pong.clone();
+        let mut send_task = tokio::spawn(async move {
+            let ping_period = send_state.ping_interval.max(Duration::from_secs(1));
+            let mut ping_interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + ping_period, ping_period);
+            let mut ping_sent: Option<Instant> = None;
+            let mut latency_sampled_at: Option<Instant> = None;
+            // The latest message per publisher and topic waiting for the
+            // coalescing window to close, and the frames it then leaves to send.
+            let mut coalesced: Vec<PubSubMsg> = Vec::new();
+            let mut flush_at = tokio::time::Instant::now();
+            let mut outbox = VecDeque::new();
+            let mut shutting_down = send_state.shutting_down.subscribe();
+            let expires_at = connected_at + send_state.max_conn_lifetime.unwrap_or_default();
+            loop {
+                let pong_deadline =
+                    ping_sent.unwrap_or_else(Instant::now) + send_state.ping_timeout;
+                let message = tokio::select! {
+                    biased;
+                    Ok(()) = shutting_down.changed() => {
+                        let mut dropped = (coalesced.len() + outbox.len()) as u64;
+                        while direct_rx.try_recv().is_ok() {
+                            dropped += 1;
+                        }
+                        while let Some(Some((_, data))) = feed.next().now_or_never() {
+                            if let Ok(data) = data {
+                                if !data.is_expired() && send_subscriptions.lock().unwrap().matches(&data) {
+                                    dropped += 1;
+                                }
+                            }
+                        }
+                        send_state.metrics.drained_connections.fetch_add(1, Ordering::Relaxed);
+                        send_state.metrics.drain_dropped_messages.fetch_add(dropped, Ordering::Relaxed);
+                        tracing::info!(
+                            addr = %who,
+                            event = "going_away",
+                            dropped,
+                            "closing connection for shutdown",
+                        );
+                        // Tells the client to reconnect, to another instance if
+                        // there is one, rather than to treat it as a network error.
+                        let _ = tokio::time::timeout(
+                            Duration::from_secs(5),
+                            sender.send(reconnect_close(
+                                &send_state,
+                                who,
+                                close_code::AWAY,
+                                "server shutting down",
+                            )),
+                        )
+                        .await;
+                        return;
+                    }
+                    _ = tokio::time::sleep_until(expires_at), if send_state.max_conn_lifetime.is_some() => {
+                        tracing::info!(
+                            addr = %who,
+                            event = "lifetime_exceeded",
+                            "client reached the maximum connection lifetime",
+                        );
+                        let _ = tokio::time::timeout(
+                            Duration::from_secs(5),
+                            sender.send(reconnect_close(
+                                &send_state,
+                                who,
+                                close_code::POLICY,
+                                "connection lifetime exceeded",
+                            )),
+                        )
+                        .await;
+                        return;
+                    }
+                    _ = tokio::time::sleep_until(pong_deadline.into()), if ping_sent.is_some() => {
+                        if *send_last_pong.lock().unwrap() < ping_sent.unwrap() {
+                            tracing::info!(
+                                addr = %who,
+                                event = "ping_timeout",
+                                "client did not answer ping in time",
+                            );
+                            return;
+                        }
+                        ping_sent = None;
+                        continue;
+                    }
+                    _ = ping_interval.tick(), if !send_state.ping_interval.is_zero() => {
+                        ping_sent.get_or_insert_with(Instant::now);
+                        Message::Ping(Vec::new())
+                    }
+                    // Neither channel runs out of cooperative budget, which would
+                    // pass for having nothing left to send and ack barriers early.
+                    // Writing to the socket still yields to other tasks.
+                    Some(message) = tokio::task::unconstrained(direct_rx.recv()) => message,
+                    _ = std::future::ready(()), if !outbox.is_empty() => {
+                        outbox.pop_front().expect("outbox is not empty")
+                    }
+                    _ = tokio::time::sleep_until(flush_at), if !coalesced.is_empty() => {
+                        for data in coalesced.drain(..).filter(|data| !data.is_expired()) {
+                            outbox.extend(send_delivery.to_message(who, &data, &send_state.metrics));
+                        }
+                        continue;
+                    }
+                    Some(receivers) = feed_rx.recv() => {
+                        update_feed(&mut feed, receivers);
+                        continue;
+                    }
+                    // Without subscriptions the feed is empty and would end right
+                    // away, so it is left alone until the next subscribe.
+                    Some((_, data)) = tokio::task::unconstrained(feed.next()), if !feed.is_empty() => match data {
+                        Ok(data) => {
+                            if data.is_expired() || !send_subscriptions.lock().unwrap().matches(&data) {
+                                continue;
+                            }
+                            // Coalescing would leave holes in streamed publishes.
+                            if let Some(window) = coalesce.filter(|_| data.stream.is_none()) {
+                                if coalesced.is_empty() {
+                                    flush_at = tokio::time::Instant::now() + window;
+                                }
+                                // Keeps the frames in the order of the messages they carry.
+                                coalesced.retain(|msg| msg.name != data.name || msg.msg.topic != data.msg.topic);
+                                coalesced.push(data);
+                                continue;
+                            }
+                            let Some(message) = send_delivery.to_message(who, &data, &send_state.metrics) else {
+                                continue;
+                            };
+                            if latency_sampled_at.is_none_or(|at| at.elapsed() >= LATENCY_SAMPLE_INTERVAL) {
+                                latency_sampled_at = Some(Instant::now());
+                                let latency = unix_millis().saturating_sub(data.timestamp);
+                                send_state
+                                    .metrics
+                                    .delivery_latency
+                                    .observe(Duration::from_millis(latency));
+                            }
+                            message
+                        }
+                        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                            tracing::warn!(
+                                addr = %who,
+                                event = "lag",
+                                skipped,
+                                "client lagged behind",
+                            );
+                            send_state.metrics.lag_events.fetch_add(1, Ordering::Relaxed);
+                            if send_state.slow_subscriber_policy == SlowSubscriberPolicy::Disconnect {
+                                reconnect_close(&send_state, who, TOO_SLOW_CLOSE_CODE, "too slow")
+                            } else if send_delivery.format == Subprotocol::Raw {
+                                continue;
+                            } else {
+                                Message::Text(json!({ "lagged": { "skipped": skipped } }).to_string())
+                            }
+                        }
+                    },
+                    Some(id) = barrier_rx.recv() => {
+                        barriers.push_back(id);
+                        continue;
+                    }
+                    // Publishing puts a message in every receiver before the
+                    // publish returns, so once nothing above is ready, everything
+                    // published before the barrier arrived has been sent.
+                    _ = std::future::ready(()), if !barriers.is_empty() && coalesced.is_empty() => {
+                        let id = barriers.pop_front();
+                        Message::Text(json!({ "type": "barrier_ack", "id": id }).to_string())
+                    }
+                };
+                let closing = matches!(message, Message::Close(_));
+                if tokio::time::timeout(Duration::from_secs(5), sender.send(message))
+                    .await
+                    .is_err()
+                {
+                    tracing::info!(
+                        addr = %who,
+                        event = "disconnect",
+                        "client abruptly disconnected",
+                    );
+                    return;
+                }
+                if closing {
+                    return;
+                }
+            }
+        }.in_current_span());
+        let mut recv_task = tokio::spawn(async move {
+            while let Some(msg) = receiver.next().await {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(error) => match receive_error_close(who, error) {
+                        Some(close) => {
+                            let _ = direct_tx.send(close);
+                            return true;
+                        }
+                        None => break,
+                    },
+                };
+                match msg {
+                    Message::Ping(v) => {
+                        tracing::info!(addr = %who, event = "ping", payload = ?v, "received ping");
+                    }
+                    Message::Pong(_) => {
+                        *last_pong.lock().unwrap() = Instant::now();
+                    }
+                    Message::Close(c) => {
+                        if let Some(cf) = c {
+                            tracing::info!(
+                                addr = %who,
+                                event = "close",
+                                code = cf.code,
+                                reason = %cf.reason,
+                                "received close"
+                            );
+                        } else {
+                            tracing::info!(
+                                addr = %who,
+                                event = "close",
+                                "received close without CloseFrame"
+                            );
+                        }
+                        break;
+                    }
+                    Message::Text(t) => {
+                        if let Some(msg) = parse_client_msg(who, &t) {
+                            let msg = match handle_publisher_msg(&state, who, &mut publisher, msg).await {
+                                Ok(Some(msg)) => msg,
+                                Ok(None) => continue,
+                                Err(error) => {
+                                    let _ = direct_tx.send(error.to_message());
+                                    continue;
+                                }
+                            };
+                            if let ClientMsg::Control(ControlMsg::Ack { seq }) = msg {
+                                if let Some(session) = &delivery.session {
+                                    session.lock().unwrap().outstanding.remove(&seq);
+                                }
+                                continue;
+                            }
+                            if let ClientMsg::Control(ControlMsg::Barrier { id }) = msg {
+                                let _ = barrier_tx.send(id);
+                                continue;
+                            }
+                            let mut msg = msg;
+                            if let Some(error) = validate_topics(who, &mut msg) {
+                                let _ = direct_tx.send(error);
+                            }
+                            let error =
+                                limit_topics(&state, who, &subscriptions.lock().unwrap(), &mut msg);
+                            if let Some(error) = error {
+                                let _ = direct_tx.send(error);
+                            }
+                            let mut added = Subscriptions::new(scope.clone());
+                            let rejected = added.apply(msg.clone());
+                            if !rejected.is_empty() {
+                                tracing::warn!(
+                                    addr = %who,
+                                    event = "forbidden",
+                                    identity = scope.identity.as_deref(),
+                                    topics = ?rejected,
+                                    "subscription rejected",
+                                );
+                            }
+                            let confirmation = subscribed_frame(&msg, &rejected);
+                            let live = matches!(
+                                &msg,
+                                ClientMsg::Subscribe(sub) | ClientMsg::Control(ControlMsg::Subscribe(sub))
+                                    if sub.mode == Some(Mode::Live)
+                            );
+                            let receivers = {
+                                let mut subscriptions = subscriptions.lock().unwrap();
+                                subscriptions.apply(msg);
+                                state.receivers(&subscriptions)
+                            };
+                            let _ = feed_tx.send(receivers);
+                            // Goes out before anything the new receivers get.
+                            if let Some(confirmation) = confirmation.filter(|_| confirm_subscriptions) {
+                                let _ = direct_tx.send(confirmation);
+                            }
+                            let retained = (!live).then(|| state.retained_matching(&added));
+                            for retained in retained.into_iter().flatten() {
+                                if let Some(message) =
+                                    delivery.to_message(who, &retained, &state.metrics)
+                                {
+                                    let _ = direct_tx.send(message);
+                                }
+                            }
+                        } else {
+                            let _ = direct_tx.send(error_close(
+                                close_code::INVALID,
+                                "invalid subscription payload",
+                            ));
+                            return true;
+                        }
+                    }
+                    _ => {
+                        let _ = direct_tx.send(error_close(
+                            close_code::UNSUPPORTED,
+                            "unexpected message type",
+                        ));
+                        return true;
+                    }
+                }
+            }
+            false
+        }.in_current_span());
+        tokio::select! {
+            _ = (&mut send_task) => {
+                recv_task.abort();
+            },
+            closing = (&mut recv_task) => {
+                // Let the send task flush the close frame the receiver queued.
+                if matches!(closing, Ok(true)) {
+                    let _ = tokio::time::timeout(Duration::from_secs(1), &mut send_task).await;
+                }
+                send_task.abort();
+            }
+        }
+        metrics_state.subscribers.remove(&subscriber_id);
+        drop(registration);
+        if let (Some(session), Some(id)) = (&session, session_id) {
+            metrics_state.detach_session(scope_identity, id, session);
+        }
+    }
+    metrics_state
+        .metrics
+        .active_subscribers
+        .fetch_sub(1, Ordering::Relaxed);
+    tracing::info!(addr = %who, event = "destroy", "websocket context destroyed");
+}
@@ -0,0 +1,542 @@
+//! The journal at `DB_PATH` that messages are kept in across restarts.
+
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{mpsc, Arc},
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+use crate::{
+    auth::AuthError,
+    bridge::RelayedPayload,
+    config::Config,
+    pubsub::{unix_millis, Payload, PubSubMsg, PublisherMsg, SharedState},
+};
+
+/// Most messages written to the database in one transaction.
+const MAX_BATCH: usize = 256;
+
+/// Keeps delivered messages in an SQLite database at `DB_PATH`, so that
+/// history, retained messages and sequence numbers survive restarts.
+///
+/// The database is written by a thread of its own, which hands out sequence
+/// numbers and delivers every message once it is committed, in order. Messages
+/// queued while a commit is under way are committed together in the next one,
+/// so that publishing does not wait for the disk once per message.
+pub(crate) struct Journal {
+    jobs: mpsc::Sender<Job>,
+}
+
+enum Job {
+    Deliver(Box<Delivery>),
+    Prune,
+}
+
+/// A message waiting for the writer to store and deliver it.
+struct Delivery {
+    msg: PubSubMsg,
+    state: Arc<SharedState>,
+    done: oneshot::Sender<Result<usize, AuthError>>,
+}
+
+/// The database and the writer's view of it.
+struct Db {
+    conn: Connection,
+    max_rows: usize,
+    max_age: Option<Duration>,
+    /// Sequence number of the next message, also stored in the `meta` table so
+    /// that it survives pruning every message.
+    next_seq: u64,
+}
+
+/// A message as kept in the `message` column of the `messages` table.
+#[derive(Serialize, Deserialize)]
+struct StoredMsg {
+    seq: u64,
+    publisher: String,
+    topic: String,
+    retain: bool,
+    ttl_ms: Option<u64>,
+    key: Option<String>,
+    #[serde(default)]
+    reply_to: Option<String>,
+    #[serde(default)]
+    correlation_id: Option<String>,
+    timestamp: u64,
+    data: RelayedPayload,
+}
+
+impl From<&PubSubMsg> for StoredMsg {
+    fn from(msg: &PubSubMsg) -> Self {
+        Self {
+            seq: msg.seq,
+            publisher: msg.name.clone(),
+            topic: msg.msg.topic.clone(),
+            retain: msg.msg.retain,
+            ttl_ms: msg.msg.ttl_ms,
+            key: msg.msg.key.clone(),
+            reply_to: msg.msg.reply_to.clone(),
+            correlation_id: msg.msg.correlation_id.clone(),
+            timestamp: msg.timestamp,
+            data: match &msg.msg.data {
+                Payload::Text(text) => RelayedPayload::Text(text.clone()),
+                Payload::Binary(bytes) => RelayedPayload::Binary(bytes.clone()),
+            },
+        }
+    }
+}
+
+impl From<StoredMsg> for PubSubMsg {
+    fn from(stored: StoredMsg) -> Self {
+        Self {
+            name: stored.publisher,
+            msg: PublisherMsg {
+                topic: stored.topic,
+                data: match stored.data {
+                    RelayedPayload::Text(text) => Payload::Text(text),
+                    RelayedPayload::Binary(bytes) => Payload::Binary(bytes),
+                },
+                retain: stored.retain,
+                ttl_ms: stored.ttl_ms,
+                key: stored.key,
+                reply_to: stored.reply_to,
+                correlation_id: stored.correlation_id,
+            },
+            timestamp: stored.timestamp,
+            seq: stored.seq,
+            stream: None,
+            compressed: Arc::default(),
+        }
+    }
+}
+
+impl Journal {
+    /// Opens the journal at `db_path`, if set, and reads back the sequence
+    /// number of the next message and the messages still kept, oldest first.
+    pub(crate) fn open(config: &Config) -> anyhow::Result<Option<(Self, u64, Vec<PubSubMsg>)>> {
+        let Some((db, msgs)) = Db::open(config)? else {
+            return Ok(None);
+        };
+        let next_seq = db.next_seq;
+        let (jobs, queue) = mpsc::channel();
+        std::thread::Builder::new()
+            .name(String::from("journal"))
+            .spawn(move || db.run(queue))
+            .context("failed to start the journal writer")?;
+        Ok(Some((Self { jobs }, next_seq, msgs)))
+    }
+
+    /// Queues `msg` to be stored and then delivered through `state`, and returns
+    /// where the number of subscribers it was handed to arrives. Parts of
+    /// streamed publishes are not stored, only delivered in turn.
+    pub(crate) fn deliver(
+        &self,
+        state: &Arc<SharedState>,
+        msg: PubSubMsg,
+    ) -> oneshot::Receiver<Result<usize, AuthError>> {
+        let (done, delivered) = oneshot::channel();
+        let delivery = Box::new(Delivery {
+            msg,
+            state: state.clone(),
+            done,
+        });
+        if let Err(mpsc::SendError(Job::Deliver(delivery))) = self.jobs.send(Job::Deliver(delivery))
+        {
+            tracing::error!(event = "journal_error", "journal writer is gone");
+            let _ = delivery.done.send(Err(AuthError::StorageFailed));
+        }
+        delivered
+    }
+
+    /// Has the writer drop the messages beyond `DB_MAX_ROWS` or older than
+    /// `DB_MAX_AGE`.
+    pub(crate) fn prune(&self) {
+        let _ = self.jobs.send(Job::Prune);
+    }
+}
+
+impl Db {
+    fn open(config: &Config) -> anyhow::Result<Option<(Self, Vec<PubSubMsg>)>> {
+        let Some(path) = &config.db_path else {
+            return Ok(None);
+        };
+        let max_rows = config.db_max_rows.unwrap_or(10000);
+        anyhow::ensure!(max_rows > 0, "DB_MAX_ROWS must be greater than 0");
+        let conn = Connection::open(path).with_context(|| format!("failed to open `{path}`"))?;
+        // Every commit is synced to disk before its messages are delivered.
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = FULL;
+            CREATE TABLE IF NOT EXISTS messages (
+                seq INTEGER PRIMARY KEY,
+                timestamp INTEGER NOT NULL,
+                message TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS meta (
+                key TEXT PRIMARY KEY,
+                value INTEGER NOT NULL
+            );",
+        )
+        .with_context(|| format!("failed to set up `{path}`"))?;
+        let stored_next_seq: Option<i64> = conn
+            .query_row("SELECT value FROM meta WHERE key = 'next_seq'", [], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let last_seq: Option<i64> =
+            conn.query_row("SELECT MAX(seq) FROM messages", [], |row| row.get(0))?;
+        let next_seq = stored_next_seq
+            .unwrap_or(0)
+            .max(last_seq.map_or(0, |seq| seq + 1)) as u64;
+        let mut db = Self {
+            conn,
+            max_rows,
+            max_age: config.db_max_age.map(Duration::from_secs),
+            next_seq,
+        };
+        db.prune()
+            .with_context(|| format!("failed to prune `{path}`"))?;
+        let mut msgs = Vec::new();
+        {
+            let mut select = db
+                .conn
+                .prepare("SELECT message FROM messages ORDER BY seq")?;
+            for message in select.query_map([], |row| row.get::<_, String>(0))? {
+                match serde_json::from_str::<StoredMsg>(&message?) {
+                    Ok(stored) => msgs.push(stored.into()),
+                    Err(error) => tracing::warn!("skipping unreadable message in journal: {error}"),
+                }
+            }
+        }
+        Ok(Some((db, msgs)))
+    }
+
+    /// Commits what is queued in batches until the journal is dropped.
+    fn run(mut self, queue: mpsc::Receiver<Job>) {
+        while let Ok(job) = queue.recv() {
+            let mut deliveries = Vec::new();
+            for job in std::iter::once(job).chain(queue.try_iter().take(MAX_BATCH - 1)) {
+                match job {
+                    Job::Deliver(delivery) => deliveries.push(*delivery),
+                    Job::Prune => {
+                        if let Err(error) = self.prune() {
+                            tracing::error!(
+                                event = "journal_error",
+                                "failed to prune journal: {error}"
+                            );
+                        }
+                    }
+                }
+            }
+            self.deliver(deliveries);
+        }
+    }
+
+    /// Numbers `deliveries`, stores them in one transaction and delivers them
+    /// in order once it is committed. If the commit fails, none of the stored
+    /// messages are delivered and their sequence numbers are handed out again.
+    fn deliver(&mut self, mut deliveries: Vec<Delivery>) {
+        for (seq, delivery) in (self.next_seq..).zip(&mut deliveries) {
+            delivery.msg.seq = seq;
+        }
+        let next_seq = self.next_seq + deliveries.len() as u64;
+        let stored: Vec<_> = deliveries
+            .iter()
+            .map(|delivery| &delivery.msg)
+            .filter(|msg| msg.stream.is_none())
+            .collect();
+        let committed = stored.is_empty() || {
+            match self.store(&stored, next_seq) {
+                Ok(()) => true,
+                Err(error) => {
+                    tracing::error!(
+                        event = "journal_error",
+                        "failed to write to journal: {error}"
+                    );
+                    false
+                }
+            }
+        };
+        if !committed {
+            let (failed, parts): (Vec<_>, Vec<_>) = deliveries
+                .into_iter()
+                .partition(|delivery| delivery.msg.stream.is_none());
+            for delivery in failed {
+                let _ = delivery.done.send(Err(AuthError::StorageFailed));
+            }
+            deliveries = parts;
+            for (seq, delivery) in (self.next_seq..).zip(&mut deliveries) {
+                delivery.msg.seq = seq;
+            }
+        }
+        self.next_seq += deliveries.len() as u64;
+        for delivery in deliveries {
+            let delivered_to = delivery.state.deliver_numbered(delivery.msg);
+            // Whoever published may have stopped waiting.
+            let _ = delivery.done.send(Ok(delivered_to));
+        }
+    }
+
+    /// Writes `msgs` and the sequence number of the next message in one
+    /// transaction, dropping the oldest messages beyond `max_rows`.
+    fn store(&mut self, msgs: &[&PubSubMsg], next_seq: u64) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT INTO messages (seq, timestamp, message) VALUES (?1, ?2, ?3)",
+            )?;
+            for &msg in msgs {
+                let message = serde_json::to_string(&StoredMsg::from(msg))
+                    .expect("stored message is always serializable");
+                insert.execute(params![msg.seq as i64, msg.timestamp as i64, message])?;
+            }
+        }
+        tx.execute(
+            "INSERT INTO meta (key, value) VALUES ('next_seq', ?1)
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            [next_seq as i64],
+        )?;
+        delete_beyond(&tx, self.max_rows)?;
+        tx.commit()
+    }
+
+    /// Drops the messages beyond `max_rows` or older than `max_age`.
+    fn prune(&mut self) -> rusqlite::Result<()> {
+        delete_beyond(&self.conn, self.max_rows)?;
+        if let Some(max_age) = self.max_age {
+            let oldest = unix_millis().saturating_sub(max_age.as_millis() as u64);
+            self.conn
+                .execute("DELETE FROM messages WHERE timestamp < ?1", [oldest as i64])?;
+        }
+        Ok(())
+    }
+}
+
+/// Drops every message but the latest `max_rows`.
+fn delete_beyond(conn: &Connection, max_rows: usize) -> rusqlite::Result<usize> {
+    conn.execute(
+        "DELETE FROM messages WHERE seq <= (
+            SELECT seq FROM messages ORDER BY seq DESC LIMIT 1 OFFSET ?1
+        )",
+        [max_rows as i64],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database path of its own for each test, removed when dropped.
+    struct TempPath(String);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("isimud-journal-{}-{name}.db", std::process::id()));
+            let path = Self(path.to_str().unwrap().to_string());
+            path.remove();
+            path
+        }
+
+        fn config(&self) -> Config {
+            Config {
+                password: Some(String::from("secret")),
+                db_path: Some(self.0.clone()),
+                ..Config::default()
+            }
+        }
+
+        fn remove(&self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let _ = std::fs::remove_file(format!("{}{suffix}", self.0));
+            }
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            self.remove();
+        }
+    }
+
+    fn msg(seq: u64, data: &str) -> PubSubMsg {
+        let payload = serde_json::from_value(serde_json::json!({
+            "topic": "sensors/a",
+            "data": data,
+        }))
+        .unwrap();
+        let mut msg = PubSubMsg::new(payload, String::from("p"));
+        msg.seq = seq;
+        msg
+    }
+
+    fn texts(msgs: &[PubSubMsg]) -> Vec<String> {
+        msgs.iter()
+            .map(|msg| match &msg.msg.data {
+                Payload::Text(text) => text.to_string(),
+                Payload::Binary(_) => panic!("expected a text message"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn writes_messages_to_the_database() {
+        let path = TempPath::new("write");
+        let (mut db, msgs) = Db::open(&path.config()).unwrap().unwrap();
+        assert_eq!((db.next_seq, msgs.len()), (0, 0));
+
+        db.store(&[&msg(0, "hello")], 1).unwrap();
+        let conn = Connection::open(&path.0).unwrap();
+        let (seq, message): (i64, String) = conn
+            .query_row("SELECT seq, message FROM messages", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(seq, 0);
+        assert!(message.contains(r#""data":{"text":"hello"}"#), "{message}");
+    }
+
+    #[test]
+    fn replays_messages_after_a_restart() {
+        let path = TempPath::new("replay");
+        let (mut db, _) = Db::open(&path.config()).unwrap().unwrap();
+        for (seq, data) in ["one", "two", "three"].into_iter().enumerate() {
+            let seq = seq as u64;
+            db.store(&[&msg(seq, data)], seq + 1).unwrap();
+        }
+        drop(db);
+
+        let (db, msgs) = Db::open(&path.config()).unwrap().unwrap();
+        assert_eq!(db.next_seq, 3);
+        assert_eq!(texts(&msgs), ["one", "two", "three"]);
+        assert_eq!(
+            msgs.iter().map(|msg| msg.seq).collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert_eq!(msgs[0].name, "p");
+        assert_eq!(msgs[0].msg.topic, "sensors/a");
+    }
+
+    #[test]
+    fn prunes_beyond_max_rows() {
+        let path = TempPath::new("max-rows");
+        let config = Config {
+            db_max_rows: Some(2),
+            ..path.config()
+        };
+        let (mut db, _) = Db::open(&config).unwrap().unwrap();
+        let msgs = [msg(0, "one"), msg(1, "two"), msg(2, "three")];
+        db.store(&msgs.iter().collect::<Vec<_>>(), 3).unwrap();
+        drop(db);
+
+        let (db, msgs) = Db::open(&config).unwrap().unwrap();
+        assert_eq!(db.next_seq, 3);
+        assert_eq!(texts(&msgs), ["two", "three"]);
+    }
+
+    #[test]
+    fn prunes_beyond_max_age() {
+        let path = TempPath::new("max-age");
+        let config = Config {
+            db_max_age: Some(60),
+            ..path.config()
+        };
+        let (mut db, _) = Db::open(&config).unwrap().unwrap();
+        let mut old = msg(0, "old");
+        old.timestamp -= 120_000;
+        db.store(&[&old, &msg(1, "new")], 2).unwrap();
+        db.prune().unwrap();
+        drop(db);
+
+        let (db, msgs) = Db::open(&config).unwrap().unwrap();
+        assert_eq!(db.next_seq, 2);
+        assert_eq!(texts(&msgs), ["new"]);
+    }
+
+    #[test]
+    fn keeps_the_sequence_number_when_every_message_is_pruned() {
+        let path = TempPath::new("next-seq");
+        let config = Config {
+            db_max_age: Some(60),
+            ..path.config()
+        };
+        let (mut db, _) = Db::open(&config).unwrap().unwrap();
+        let mut old = msg(0, "old");
+        old.timestamp -= 120_000;
+        db.store(&[&old], 1).unwrap();
+        drop(db);
+
+        let (db, msgs) = Db::open(&config).unwrap().unwrap();
+        assert!(msgs.is_empty());
+        assert_eq!(db.next_seq, 1);
+    }
+
+    #[tokio::test]
+    async fn delivers_concurrent_publishes_in_sequence_order() {
+        let path = TempPath::new("concurrent");
+        let config = Config {
+            broadcast_capacity: Some(64),
+            ..path.config()
+        };
+        let state = Arc::new(SharedState::new(&config).unwrap());
+        let (mut receiver, _) = state.watch();
+
+        let publishes: Vec<_> = (0..50)
+            .map(|i| {
+                let state = state.clone();
+                tokio::spawn(async move { state.publish(msg(0, &i.to_string())).await })
+            })
+            .collect();
+        for publish in publishes {
+            publish.await.unwrap().unwrap();
+        }
+        for seq in 0..50 {
+            assert_eq!(receiver.recv().await.unwrap().seq, seq);
+        }
+        drop(state);
+
+        let (db, msgs) = Db::open(&path.config()).unwrap().unwrap();
+        assert_eq!(db.next_seq, 50);
+        assert_eq!(msgs.len(), 50);
+    }
+
+    #[tokio::test]
+    async fn rejects_publishes_it_cannot_store() {
+        let path = TempPath::new("failed");
+        let state = Arc::new(SharedState::new(&path.config()).unwrap());
+        let (mut receiver, _) = state.watch();
+        Connection::open(&path.0)
+            .unwrap()
+            .execute_batch("DROP TABLE messages")
+            .unwrap();
+
+        let result = state.publish(msg(0, "lost")).await;
+        assert!(matches!(result, Err(AuthError::StorageFailed)));
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(state.watch().1, None);
+    }
+
+    #[tokio::test]
+    async fn restores_retained_messages_after_a_restart() {
+        let path = TempPath::new("restore");
+        let state = Arc::new(SharedState::new(&path.config()).unwrap());
+        state.publish(msg(0, "one")).await.unwrap();
+        let mut retained = msg(0, "two");
+        retained.msg.retain = true;
+        state.publish(retained).await.unwrap();
+        drop(state);
+
+        let state = SharedState::new(&path.config()).unwrap();
+        let (_, last_seq) = state.watch();
+        assert_eq!(last_seq, Some(1));
+        let retained: Vec<_> = state
+            .retained
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect();
+        assert_eq!(texts(&retained), ["two"]);
+    }
+}
@@ -0,0 +1,3494 @@
+//! Library side of isimud. The server itself is the `isimud` binary, which
+//! reads its [`Config`] and calls [`serve`]; [`app`] builds the same routes for
+//! serving in-process, e.g. from tests. With the `client` feature, this crate
+//! also provides a typed client for talking to the server from Rust.
+
+pub mod auth;
+mod bridge;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod config;
+pub mod handlers;
+mod journal;
+mod metrics;
+pub mod pubsub;
+
+pub use config::Config;
+pub use pubsub::{MessageTransform, PublisherMsg, SharedState, SubscriberMsg};
+
+use anyhow::Context;
+use axum::{
+    extract::DefaultBodyLimit,
+    http::{header, HeaderName, HeaderValue, Method, Request},
+    middleware,
+    routing::{get, post},
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use opentelemetry::{sdk::propagation::TraceContextPropagator, sdk::Resource, KeyValue};
+use opentelemetry_http::HeaderExtractor;
+use opentelemetry_otlp::WithExportConfig;
+use rustls_pemfile::Item;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    trace::{DefaultMakeSpan, MakeSpan, TraceLayer},
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt, Layer,
+};
+
+use crate::{
+    auth::authorize_publisher_source,
+    bridge::relay_from_redis,
+    handlers::{
+        admin_subscriptions_handler, health_handler, homepage_handler, metrics_handler,
+        poll_handler, pub_batch_handler, pub_binary_handler, pub_handler, pub_stream_handler,
+        ready_handler, request_handler, sse_handler, topics_handler, ws_handler,
+    },
+    pubsub::Session,
+};
+
+/// How long shutting down waits for open requests and websocket connections to
+/// finish.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Sets up logging in the format of `LOG_FORMAT`, and exporting spans if
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub fn init_tracing(config: &Config) -> anyhow::Result<()> {
+    let tracer = match std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(_) => Some(otlp_tracer()?),
+        Err(_) => None,
+    };
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "isimud=debug,tower_http=debug".into()),
+        )
+        .with(match config.log_format.as_deref() {
+            Some("json") => tracing_subscriber::fmt::layer().json().boxed(),
+            _ => tracing_subscriber::fmt::layer().boxed(),
+        })
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .init();
+    Ok(())
+}
+
+/// Serves [`app`] on the addresses of `config` until the process is asked to
+/// stop, then waits for the connections to drain.
+pub async fn serve(config: Config) -> anyhow::Result<()> {
+    let state = Arc::new(SharedState::new(&config)?);
+    let broadcast_capacity = state.broadcast_capacity;
+    let app = app(&config, state.clone())?;
+    let ips = config
+        .ip
+        .clone()
+        .unwrap_or_else(|| vec![Ipv4Addr::LOCALHOST.into()]);
+    anyhow::ensure!(!ips.is_empty(), "IP must contain at least one address");
+    let port = config.port.unwrap_or(3000);
+    let tls = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => Some(
+            load_tls_config(cert_path, key_path)
+                .await
+                .with_context(|| {
+                    format!("failed to load TLS certificate `{cert_path}` and key `{key_path}`")
+                })?,
+        ),
+        (None, None) => None,
+        _ => anyhow::bail!("TLS_CERT_PATH and TLS_KEY_PATH must be set together"),
+    };
+    tokio::spawn(prune_expired(state.clone()));
+    reload_on_hangup(state.clone(), std::env::var("CONFIG_PATH").ok())?;
+    tokio::spawn(relay_from_redis(state.clone()));
+    let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    // Binding every address up front fails startup if any of them is unavailable.
+    let listeners = bind(&ips, port)?;
+    // Every listener serves both HTTP/1.1 and HTTP/2: with TLS the protocol is
+    // negotiated with ALPN, and without it HTTP/2 clients need prior knowledge.
+    // Websocket upgrades always take HTTP/1.1.
+    let handle = axum_server::Handle::new();
+    let mut servers = Vec::new();
+    for listener in listeners {
+        let addr = listener.local_addr()?;
+        let handle = handle.clone();
+        let make_service = make_service.clone();
+        servers.push(match &tls {
+            Some(tls) => {
+                tracing::debug!("listening on {addr} with TLS");
+                let server = axum_server::from_tcp_rustls(listener, tls.clone()).handle(handle);
+                tokio::spawn(server.serve(make_service))
+            }
+            None => {
+                tracing::debug!("listening on {addr}");
+                let server = axum_server::from_tcp(listener).handle(handle);
+                tokio::spawn(server.serve(make_service))
+            }
+        });
+    }
+    tracing::debug!("broadcast channel capacity is {broadcast_capacity}");
+    tokio::spawn(mark_ready(state.clone()));
+    let drain_state = state.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        state.shutting_down.send_replace(true);
+        handle.graceful_shutdown(Some(SHUTDOWN_GRACE_PERIOD));
+    });
+    for server in futures::future::try_join_all(servers).await? {
+        server?;
+    }
+    let metrics = &drain_state.metrics;
+    // The servers no longer track upgraded connections, which close on their own
+    // once they notice the shutdown.
+    let _ = tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, async {
+        while metrics.active_subscribers.load(Ordering::Relaxed) > 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    tracing::info!(
+        event = "drained",
+        drained_connections = metrics.drained_connections.load(Ordering::Relaxed),
+        dropped_messages = metrics.drain_dropped_messages.load(Ordering::Relaxed),
+        remaining_connections = metrics.active_subscribers.load(Ordering::Relaxed),
+        "shut down",
+    );
+    tracing::debug!("final metrics:\n{}", metrics.render());
+    // Flushes the spans that are still batched for export.
+    tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await?;
+    Ok(())
+}
+
+/// Binds a listener to `port` on each of `ips`, failing if any is unavailable.
+fn bind(ips: &[IpAddr], port: u16) -> anyhow::Result<Vec<std::net::TcpListener>> {
+    ips.iter()
+        .map(|&ip| {
+            let addr = SocketAddr::new(ip, port);
+            let listener = std::net::TcpListener::bind(addr)
+                .with_context(|| format!("failed to bind to {addr}"))?;
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .collect()
+}
+
+/// The routes of the server, with the middleware and state they share.
+pub fn app(config: &Config, state: Arc<SharedState>) -> anyhow::Result<Router> {
+    let mut app = Router::new()
+        .route("/", get(homepage_handler))
+        .route(
+            "/pub",
+            post(pub_handler).layer(middleware::from_fn_with_state(
+                state.clone(),
+                authorize_publisher_source,
+            )),
+        )
+        .route(
+            "/pub/binary",
+            post(pub_binary_handler).layer(middleware::from_fn_with_state(
+                state.clone(),
+                authorize_publisher_source,
+            )),
+        )
+        .route(
+            "/request",
+            post(request_handler).layer(middleware::from_fn_with_state(
+                state.clone(),
+                authorize_publisher_source,
+            )),
+        )
+        .route(
+            "/pub/stream",
+            post(pub_stream_handler).layer(middleware::from_fn_with_state(
+                state.clone(),
+                authorize_publisher_source,
+            )),
+        )
+        .route(
+            "/pub/batch",
+            post(pub_batch_handler).layer(middleware::from_fn_with_state(
+                state.clone(),
+                authorize_publisher_source,
+            )),
+        )
+        .route("/sub", get(ws_handler))
+        .route("/sse", get(sse_handler))
+        .route("/poll", get(poll_handler))
+        .route("/topics", get(topics_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/health", get(health_handler))
+        .route("/ready", get(ready_handler))
+        .layer(DefaultBodyLimit::max(state.max_payload_bytes));
+    if state.admin_password.is_some() {
+        app = app.route("/admin/subscriptions", get(admin_subscriptions_handler));
+    }
+    if let Some(origins) = &config.cors_allowed_origins {
+        app = app.layer(cors_layer(origins)?);
+    }
+    Ok(app
+        .layer(
+            TraceLayer::new_for_http().make_span_with(|request: &Request<_>| {
+                let span = DefaultMakeSpan::default()
+                    .include_headers(true)
+                    .make_span(request);
+                // Continues the trace of the caller, e.g. a publisher sending `traceparent`.
+                let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+                    propagator.extract(&HeaderExtractor(request.headers()))
+                });
+                span.set_parent(parent);
+                span
+            }),
+        )
+        .with_state(state))
+}
+
+/// Allows browsers on `origins` to publish and subscribe over SSE and long
+/// polling, or any origin if `origins` contains `*`.
+fn cors_layer(origins: &[String]) -> anyhow::Result<CorsLayer> {
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                origin
+                    .parse::<HeaderValue>()
+                    .with_context(|| format!("invalid CORS origin `{origin}`"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            HeaderName::from_static("x-topic"),
+            HeaderName::from_static("x-retain"),
+            HeaderName::from_static("x-ttl-ms"),
+            HeaderName::from_static("traceparent"),
+        ])
+        .expose_headers([header::RETRY_AFTER]))
+}
+
+/// Sets up exporting spans over OTLP to `OTEL_EXPORTER_OTLP_ENDPOINT`.
+fn otlp_tracer() -> anyhow::Result<opentelemetry::sdk::trace::Tracer> {
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_env())
+        .with_trace_config(
+            opentelemetry::sdk::trace::config()
+                .with_resource(Resource::new([KeyValue::new("service.name", "isimud")])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)
+        .context("failed to set up OTLP trace export")
+}
+
+/// Reloads the settings from the TOML file at `config_path`, if any, and the
+/// environment, keeping the old ones if the new ones are invalid.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn reload(state: &SharedState, config_path: Option<&str>) -> anyhow::Result<()> {
+    Config::load_path(config_path).and_then(|config| state.reload(&config))
+}
+
+/// [`reload`]s the settings on every `SIGHUP`.
+#[cfg(unix)]
+fn reload_on_hangup(state: Arc<SharedState>, config_path: Option<String>) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    // Listens before returning, as a `SIGHUP` arriving earlier would still
+    // terminate the process.
+    let mut hangup = signal(SignalKind::hangup()).context("failed to listen for SIGHUP")?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match reload(&state, config_path.as_deref()) {
+                Ok(()) => tracing::info!(event = "reloaded", "reloaded settings"),
+                Err(error) => tracing::error!(
+                    event = "reload_failed",
+                    "failed to reload settings, keeping the old ones: {error:#}"
+                ),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn reload_on_hangup(_state: Arc<SharedState>, _config_path: Option<String>) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Resolves once the process is asked to stop with Ctrl+C or `SIGTERM`.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        if let Ok(mut signal) =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        {
+            signal.recv().await;
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!(event = "shutdown", "shutting down");
+}
+
+async fn load_tls_config(cert_path: &str, key_path: &str) -> anyhow::Result<RustlsConfig> {
+    let certs = rustls_pemfile::certs(&mut std::fs::read(cert_path)?.as_slice())?;
+    anyhow::ensure!(!certs.is_empty(), "no certificates found in `{cert_path}`");
+    let key = rustls_pemfile::read_all(&mut std::fs::read(key_path)?.as_slice())?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .with_context(|| format!("no private key found in `{key_path}`"))?;
+    Ok(RustlsConfig::from_der(certs, key).await?)
+}
+
+/// Periodically drops idle rate limit buckets, expired auth cache entries and
+/// messages past the journal's retention.
+async fn prune_expired(state: Arc<SharedState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        if let Some(rate_limiter) = &state.settings.load().rate_limiter {
+            rate_limiter.prune();
+        }
+        state.auth_cache.prune();
+        state.reconnect_backoff.prune();
+        state.prune_active_topics();
+        state.channels.retain(|_, tx| tx.receiver_count() > 0);
+        state.retained.retain(|_, msg| !msg.is_expired());
+        if let Some(journal) = &state.journal {
+            journal.prune();
+        }
+        state.sessions.retain(|_, session| {
+            let session = session.lock().unwrap();
+            session.connections > 0 || session.last_seen.elapsed() < Session::TTL
+        });
+    }
+}
+
+/// Marks the server as ready once the auth service, if any, answers a probe.
+async fn mark_ready(state: Arc<SharedState>) {
+    if let Some(auth_url) = &state.auth_url {
+        while let Err(error) = state.client.get(auth_url.as_str()).send().await {
+            tracing::warn!("auth service at {auth_url} is unreachable: {error}");
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    }
+    state.ready.store(true, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Homepage, SlowSubscriberPolicy};
+    use axum::http::StatusCode;
+    use futures::future::BoxFuture;
+    use futures::{SinkExt, StreamExt};
+    use opentelemetry::sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use opentelemetry::trace::{SpanId, TraceId, TracerProvider};
+    use serde_json::json;
+    use std::{collections::HashMap, net::Ipv6Addr};
+    use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+    /// Serves `config` on an ephemeral port of localhost, returning the bound
+    /// address and a handle to shut the server down with.
+    async fn spawn_server(config: Config) -> anyhow::Result<(SocketAddr, axum_server::Handle)> {
+        let state = Arc::new(SharedState::new(&config)?);
+        spawn_app(&config, state)
+    }
+
+    /// Serves `state` like [`spawn_server`], for tests that need to get at it.
+    fn spawn_app(
+        config: &Config,
+        state: Arc<SharedState>,
+    ) -> anyhow::Result<(SocketAddr, axum_server::Handle)> {
+        spawn_app_on(Ipv4Addr::LOCALHOST.into(), config, state)
+    }
+
+    /// Serves `state` on an ephemeral port of `ip`.
+    fn spawn_app_on(
+        ip: IpAddr,
+        config: &Config,
+        state: Arc<SharedState>,
+    ) -> anyhow::Result<(SocketAddr, axum_server::Handle)> {
+        let app = app(config, state)?;
+        let listener = bind(&[ip], 0)?.remove(0);
+        let addr = listener.local_addr()?;
+        let handle = axum_server::Handle::new();
+        let server = axum_server::from_tcp(listener).handle(handle.clone());
+        tokio::spawn(server.serve(app.into_make_service_with_connect_info::<SocketAddr>()));
+        Ok((addr, handle))
+    }
+
+    type Socket = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    /// A websocket subscriber of `topic`, once its subscription is in place.
+    async fn subscribe(addr: SocketAddr, topic: &str) -> Socket {
+        let (socket, received) = subscribe_with(addr, json!({ "topic": topic })).await;
+        assert_eq!(received, Vec::<serde_json::Value>::new());
+        socket
+    }
+
+    /// A websocket subscriber sending `subscription`, once it is in place, and
+    /// the text frames it received until then.
+    async fn subscribe_with(
+        addr: SocketAddr,
+        subscription: serde_json::Value,
+    ) -> (Socket, Vec<serde_json::Value>) {
+        let mut socket = connect(addr).await;
+        send_json(&mut socket, subscription).await;
+        let received = barrier(&mut socket).await;
+        (socket, received)
+    }
+
+    /// A websocket connection to `/sub`, once the server has said hello.
+    async fn connect(addr: SocketAddr) -> Socket {
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/sub"))
+            .await
+            .unwrap();
+        assert!(next_json(&mut socket).await.get("conn_id").is_some());
+        socket
+    }
+
+    async fn send_json(socket: &mut Socket, msg: serde_json::Value) {
+        socket.send(WsMessage::Text(msg.to_string())).await.unwrap();
+    }
+
+    /// The text frames `socket` receives before a barrier sent now is
+    /// acknowledged, i.e. everything sent to it so far.
+    async fn barrier(socket: &mut Socket) -> Vec<serde_json::Value> {
+        send_json(socket, json!({ "action": "barrier", "id": "sync" })).await;
+        let mut received = Vec::new();
+        loop {
+            let frame = next_json(socket).await;
+            if frame["type"] == "barrier_ack" {
+                return received;
+            }
+            received.push(frame);
+        }
+    }
+
+    /// The `data` of each of `frames`, sorted since only messages to one topic
+    /// arrive in order.
+    fn sorted_data(frames: &[serde_json::Value]) -> Vec<String> {
+        let mut data: Vec<_> = frames
+            .iter()
+            .map(|frame| frame["data"].as_str().unwrap().to_string())
+            .collect();
+        data.sort();
+        data
+    }
+
+    /// Publishes `data` to `topic`, authenticated by `auth`.
+    async fn publish(
+        addr: SocketAddr,
+        auth: impl FnOnce(reqwest::RequestBuilder) -> reqwest::RequestBuilder,
+        topic: &str,
+        data: &str,
+    ) -> reqwest::Response {
+        let request = reqwest::Client::new()
+            .post(format!("http://{addr}/pub"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(json!({ "topic": topic, "data": data }).to_string());
+        auth(request).send().await.unwrap()
+    }
+
+    /// Authenticates a publish as the publisher `p` with `password`.
+    fn with_password(
+        password: &'static str,
+    ) -> impl FnOnce(reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        move |request| request.basic_auth("p", Some(password))
+    }
+
+    /// The next text frame of `socket` as JSON.
+    async fn next_json(socket: &mut Socket) -> serde_json::Value {
+        loop {
+            let frame = tokio::time::timeout(Duration::from_secs(5), socket.next())
+                .await
+                .expect("no frame in time")
+                .expect("socket closed")
+                .expect("socket failed");
+            if let WsMessage::Text(text) = frame {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn publishes_to_a_websocket_subscriber() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "greetings").await;
+
+        let response = publish(
+            addr,
+            |request| request.basic_auth("greeter", Some("secret")),
+            "greetings",
+            "hello",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let published: serde_json::Value =
+            serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(published["delivered_to"], 1);
+
+        let delivered = next_json(&mut socket).await;
+        assert_eq!(delivered["publisher"], "greeter");
+        assert_eq!(delivered["topic"], "greetings");
+        assert_eq!(delivered["data"], "hello");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn publishes_with_a_bearer_token() {
+        let config = Config {
+            publisher_tokens: Some(HashMap::from([(
+                String::from("greeter"),
+                String::from("token"),
+            )])),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "greetings").await;
+
+        let response = publish(
+            addr,
+            |request| request.bearer_auth("token"),
+            "greetings",
+            "hello",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let delivered = next_json(&mut socket).await;
+        assert_eq!(delivered["publisher"], "greeter");
+        assert_eq!(delivered["data"], "hello");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn challenges_wrong_credentials() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            publisher_tokens: Some(HashMap::from([(
+                String::from("greeter"),
+                String::from("token"),
+            )])),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+
+        let wrong_password = |request: reqwest::RequestBuilder| {
+            request.basic_auth("greeter", Some("not the secret"))
+        };
+        let wrong_token = |request: reqwest::RequestBuilder| request.bearer_auth("not the token");
+        for response in [
+            publish(addr, wrong_password, "greetings", "hello").await,
+            publish(addr, wrong_token, "greetings", "hello").await,
+        ] {
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            let challenge = response.headers()[header::WWW_AUTHENTICATE]
+                .to_str()
+                .unwrap();
+            assert!(challenge.starts_with("Basic "));
+            assert!(challenge.contains("Bearer "));
+        }
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn takes_the_publisher_name_from_a_trusted_proxy() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            trust_proxy: Some(true),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "greetings").await;
+
+        // Connections from localhost come from a trusted proxy by default.
+        let asserted =
+            |request: reqwest::RequestBuilder| request.header("x-publisher-name", "alice");
+        let response = publish(addr, asserted, "greetings", "hello").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(next_json(&mut socket).await["publisher"], "alice");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn ignores_the_publisher_name_from_untrusted_clients() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "greetings").await;
+
+        let asserted =
+            |request: reqwest::RequestBuilder| request.header("x-publisher-name", "alice");
+        let response = publish(addr, asserted, "greetings", "hello").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let authenticated = |request: reqwest::RequestBuilder| {
+            request
+                .basic_auth("greeter", Some("secret"))
+                .header("x-publisher-name", "alice")
+        };
+        let response = publish(addr, authenticated, "greetings", "hello").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(next_json(&mut socket).await["publisher"], "greeter");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn counts_only_the_matching_subscribers() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let _exact = subscribe(addr, "sensors/a").await;
+        let _wildcard = subscribe(addr, "sensors/+").await;
+        // Listens on the channel carrying every message without matching.
+        let _elsewhere = subscribe(addr, "other/#").await;
+
+        let response = publish(
+            addr,
+            |request| request.basic_auth("p", Some("secret")),
+            "sensors/a",
+            "1",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(body["delivered_to"], 3);
+        assert_eq!(body["matched_subscribers"], 2);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn hints_at_an_escalating_reconnect_delay_on_close() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            subscribe_timeout: Some(1),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut retry_after_ms = Vec::new();
+        for _ in 0..2 {
+            let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/sub"))
+                .await
+                .unwrap();
+            assert!(next_json(&mut socket).await.get("conn_id").is_some());
+            let Some(Ok(WsMessage::Close(Some(frame)))) = socket.next().await else {
+                panic!("expected a close frame");
+            };
+            let reason: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+            assert_eq!(reason["error"], "subscribe timeout");
+            retry_after_ms.push(reason["retry_after_ms"].as_u64().unwrap());
+        }
+        assert!(
+            (500..=1000).contains(&retry_after_ms[0]),
+            "{retry_after_ms:?}"
+        );
+        assert!(
+            (1000..=2000).contains(&retry_after_ms[1]),
+            "{retry_after_ms:?}"
+        );
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn lists_the_published_topics() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        for topic in ["sensors/b", "sensors/a", "other"] {
+            let response = publish(
+                addr,
+                |request| request.basic_auth("p", Some("secret")),
+                topic,
+                "1",
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let response = reqwest::get(format!("http://{addr}/topics?prefix=sensors/"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({ "topics": ["sensors/a", "sensors/b"], "truncated": false })
+        );
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn reports_errors_with_stable_codes() {
+        use crate::auth::AuthError;
+        use axum::{body::HttpBody, response::IntoResponse};
+
+        let errors = [
+            (AuthError::WrongCredentials, 401, "WRONG_CREDENTIALS"),
+            (AuthError::MissingCredentials, 400, "MISSING_CREDENTIALS"),
+            (AuthError::Forbidden, 403, "FORBIDDEN"),
+            (AuthError::MissingTopic, 400, "MISSING_TOPIC"),
+            (
+                AuthError::RateLimited {
+                    retry_after: Duration::from_secs(1),
+                },
+                429,
+                "RATE_LIMITED",
+            ),
+            (AuthError::TooManyConnections, 503, "TOO_MANY_CONNECTIONS"),
+            (
+                AuthError::TooManyConnectionsPerIp,
+                429,
+                "TOO_MANY_CONNECTIONS_PER_IP",
+            ),
+            (
+                AuthError::UnsupportedSubprotocol,
+                400,
+                "UNSUPPORTED_SUBPROTOCOL",
+            ),
+            (AuthError::TooManyTopics, 400, "TOO_MANY_TOPICS"),
+            (AuthError::InvalidTopic, 400, "INVALID_TOPIC"),
+            (AuthError::InvalidPayload, 400, "INVALID_PAYLOAD"),
+            (
+                AuthError::AuthBackendUnavailable,
+                503,
+                "AUTH_BACKEND_UNAVAILABLE",
+            ),
+            (AuthError::NoReply, 504, "NO_REPLY"),
+            (AuthError::StorageFailed, 500, "STORAGE_FAILED"),
+        ];
+        for (error, status, code) in errors {
+            let (_, message) = error.status_and_message();
+            let mut response = error.into_response();
+            assert_eq!(response.status(), status, "{code}");
+            let body = response.body_mut().data().await.unwrap().unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body, json!({ "error": message, "code": code }));
+        }
+    }
+
+    #[tokio::test]
+    async fn reassembles_a_streamed_publish() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            max_payload_bytes: Some(1000),
+            stream_chunk_bytes: Some(1000),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "logs").await;
+        // Larger than MAX_PAYLOAD_BYTES, with characters cut off at part boundaries.
+        let body = "Grüße aus Köln! ".repeat(300);
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{addr}/pub/stream"))
+            .basic_auth("p", Some("secret"))
+            .header("x-topic", "logs")
+            .body(body.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let begin = next_json(&mut socket).await;
+        assert_eq!(begin["stream"]["part"], "begin");
+        let id = begin["stream"]["id"].clone();
+        let mut reassembled = String::new();
+        let mut parts = 1;
+        loop {
+            let msg = next_json(&mut socket).await;
+            assert_eq!(msg["stream"]["id"], id);
+            assert_eq!(msg["stream"]["index"], parts);
+            parts += 1;
+            let data = msg["data"].as_str().unwrap();
+            match msg["stream"]["part"].as_str().unwrap() {
+                "continue" => {
+                    assert!(data.len() <= 1000);
+                    reassembled.push_str(data);
+                }
+                "end" => break,
+                part => panic!("unexpected part {part}"),
+            }
+        }
+        assert!(parts > 3, "the body was published in one part");
+        assert_eq!(reassembled, body);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn rejects_publishes_nobody_would_receive_if_asked_to() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let publish_if_subscribed = |topic: &str| {
+            reqwest::Client::new()
+                .post(format!("http://{addr}/pub?require_subscriber=true"))
+                .basic_auth("p", Some("secret"))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(json!({ "topic": topic, "data": "1" }).to_string())
+                .send()
+        };
+
+        let response = publish_if_subscribed("greetings").await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body: serde_json::Value =
+            serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(body, json!({ "delivered_to": 0 }));
+
+        let mut socket = subscribe(addr, "greetings").await;
+        let response = publish_if_subscribed("greetings").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(next_json(&mut socket).await["data"], "1");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn limits_the_connections_per_ip() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            max_conn_per_ip: Some(2),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let _first = subscribe(addr, "greetings").await;
+        let second = subscribe(addr, "greetings").await;
+
+        let rejected = tokio_tungstenite::connect_async(format!("ws://{addr}/sub")).await;
+        let Err(tokio_tungstenite::tungstenite::Error::Http(response)) = rejected else {
+            panic!("the third connection was accepted");
+        };
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        drop(second);
+        let mut accepted = false;
+        for _ in 0..50 {
+            if tokio_tungstenite::connect_async(format!("ws://{addr}/sub"))
+                .await
+                .is_ok()
+            {
+                accepted = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(accepted, "the closed connection still counts");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn transforms_messages_before_delivery() {
+        struct Uppercase;
+
+        impl MessageTransform for Uppercase {
+            fn transform(&self, _publisher: &str, _topic: &str, text: Arc<str>) -> Arc<str> {
+                text.to_uppercase().into()
+            }
+        }
+
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let state = SharedState::new(&config).unwrap().with_transform(Uppercase);
+        let (addr, handle) = spawn_app(&config, Arc::new(state)).unwrap();
+        let mut socket = subscribe(addr, "greetings").await;
+
+        let response = publish(
+            addr,
+            |request| request.basic_auth("p", Some("secret")),
+            "greetings",
+            "hello",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(next_json(&mut socket).await["data"], "HELLO");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn sends_keepalives_on_an_idle_sse_stream() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            sse_keepalive: Some(1),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut response = reqwest::get(format!("http://{addr}/sse?topic=idle"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let mut body = String::new();
+        let keepalive = tokio::time::timeout(Duration::from_secs(5), async {
+            while !body.contains(":keepalive\n\n") {
+                let chunk = response.chunk().await.unwrap().expect("stream ended");
+                body.push_str(std::str::from_utf8(&chunk).unwrap());
+            }
+        });
+        assert!(keepalive.await.is_ok(), "no keepalive in {body:?}");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn publishes_over_http2_with_prior_knowledge() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "greetings").await;
+
+        let response = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap()
+            .post(format!("http://{addr}/pub"))
+            .basic_auth("greeter", Some("secret"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(r#"{"topic": "greetings", "data": "hello"}"#)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(next_json(&mut socket).await["data"], "hello");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn answers_h2c_upgrades_over_http1() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                b"GET /health HTTP/1.1\r\nhost: localhost\r\nconnection: Upgrade, HTTP2-Settings\r\n\
+                  upgrade: h2c\r\nhttp2-settings: AAMAAABkAAQCAAAAAAIAAAAA\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = vec![0; 1024];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut response))
+            .await
+            .expect("no response in time")
+            .unwrap();
+        let response = String::from_utf8_lossy(&response[..read]);
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+        handle.shutdown();
+    }
+
+    /// A config file reading the password from a file of its own, both
+    /// removed when dropped.
+    struct ReloadFiles {
+        dir: std::path::PathBuf,
+    }
+
+    impl ReloadFiles {
+        fn new(name: &str, password: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("isimud-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let files = Self { dir };
+            files.write_password(password);
+            let password_file = files.dir.join("password");
+            let password_file = password_file.to_str().unwrap();
+            files.write_config(&format!("password_file = {password_file:?}\n"));
+            files
+        }
+
+        fn config_path(&self) -> String {
+            self.dir.join("config.toml").to_str().unwrap().to_string()
+        }
+
+        fn write_config(&self, contents: &str) {
+            std::fs::write(self.config_path(), contents).unwrap();
+        }
+
+        fn write_password(&self, password: &str) {
+            std::fs::write(self.dir.join("password"), password).unwrap();
+        }
+
+        /// The state the server would start with, served.
+        fn serve(&self) -> (Arc<SharedState>, SocketAddr, axum_server::Handle) {
+            let config = Config::load_path(Some(&self.config_path())).unwrap();
+            let state = Arc::new(SharedState::new(&config).unwrap());
+            let (addr, handle) = spawn_app(&config, state.clone()).unwrap();
+            (state, addr, handle)
+        }
+    }
+
+    impl Drop for ReloadFiles {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn reloads_the_password_file() {
+        let files = ReloadFiles::new("reload", "old");
+        let (state, addr, handle) = files.serve();
+        let response = publish(addr, with_password("old"), "greetings", "hello").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        files.write_password("new");
+        reload(&state, Some(&files.config_path())).unwrap();
+        let response = publish(addr, with_password("new"), "greetings", "hello").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = publish(addr, with_password("old"), "greetings", "hello").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn keeps_the_settings_when_reloading_fails() {
+        let files = ReloadFiles::new("reload-failed", "old");
+        let (state, addr, handle) = files.serve();
+
+        files.write_config("password_file = ");
+        let error = reload(&state, Some(&files.config_path())).unwrap_err();
+        assert!(format!("{error:#}").contains("failed to parse config"));
+        files.write_config("password_file = \"/nonexistent/password\"\n");
+        assert!(reload(&state, Some(&files.config_path())).is_err());
+        let response = publish(addr, with_password("old"), "greetings", "hello").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        handle.shutdown();
+    }
+
+    /// The only test sending a signal, so that nothing else reacts to it.
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reloads_on_hangup() {
+        let files = ReloadFiles::new("hangup", "old");
+        let (state, addr, handle) = files.serve();
+        reload_on_hangup(state, Some(files.config_path())).unwrap();
+
+        files.write_password("new");
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let mut reloaded = false;
+        for _ in 0..50 {
+            let response = publish(addr, with_password("new"), "greetings", "hello").await;
+            if response.status() == StatusCode::OK {
+                reloaded = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(reloaded, "the new password was not accepted");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn subscribes_to_several_topics_at_once() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let (mut socket, _) = subscribe_with(addr, json!({ "topic": ["a", "b"] })).await;
+
+        for topic in ["a", "b", "c"] {
+            publish(addr, with_password("secret"), topic, topic).await;
+        }
+        assert_eq!(sorted_data(&barrier(&mut socket).await), ["a", "b"]);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn stops_delivering_after_an_unsubscribe() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "a").await;
+
+        publish(addr, with_password("secret"), "a", "1").await;
+        assert_eq!(sorted_data(&barrier(&mut socket).await), ["1"]);
+        send_json(
+            &mut socket,
+            json!({ "action": "unsubscribe", "topic": "a" }),
+        )
+        .await;
+        barrier(&mut socket).await;
+        publish(addr, with_password("secret"), "a", "2").await;
+        assert_eq!(barrier(&mut socket).await, Vec::<serde_json::Value>::new());
+        send_json(&mut socket, json!({ "action": "subscribe", "topic": "a" })).await;
+        barrier(&mut socket).await;
+        publish(addr, with_password("secret"), "a", "3").await;
+        assert_eq!(sorted_data(&barrier(&mut socket).await), ["3"]);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn delivers_every_publisher_to_a_wildcard_subscriber() {
+        let config = Config {
+            credentials: Some(HashMap::from([
+                (String::from("alice"), String::from("a-pass")),
+                (String::from("bob"), String::from("b-pass")),
+            ])),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let (mut everyone, _) =
+            subscribe_with(addr, json!({ "publisher": "*", "topic": "news" })).await;
+        let (mut alice, _) =
+            subscribe_with(addr, json!({ "publisher": "alice", "topic": "news" })).await;
+
+        for (publisher, password) in [("alice", "a-pass"), ("bob", "b-pass")] {
+            let response = publish(
+                addr,
+                |request| request.basic_auth(publisher, Some(password)),
+                "news",
+                publisher,
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let received = barrier(&mut everyone).await;
+        let publishers: Vec<_> = received.iter().map(|frame| &frame["publisher"]).collect();
+        assert_eq!(publishers, ["alice", "bob"]);
+        assert_eq!(sorted_data(&barrier(&mut alice).await), ["alice"]);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn replays_the_history_to_a_late_subscriber() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            history_size: Some(10),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        for data in ["1", "2", "3"] {
+            publish(addr, with_password("secret"), "a", data).await;
+        }
+        publish(addr, with_password("secret"), "b", "other").await;
+
+        let (mut socket, replayed) = subscribe_with(addr, json!({ "topic": "a" })).await;
+        let replayed: Vec<_> = replayed.iter().map(|frame| &frame["data"]).collect();
+        assert_eq!(replayed, ["1", "2", "3"]);
+        publish(addr, with_password("secret"), "a", "4").await;
+        assert_eq!(sorted_data(&barrier(&mut socket).await), ["4"]);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn delivers_binary_payloads_unchanged() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "images").await;
+
+        let payload = vec![0, 159, 146, 150, 255, b'\n'];
+        let response = reqwest::Client::new()
+            .post(format!("http://{addr}/pub/binary"))
+            .basic_auth("p", Some("secret"))
+            .header("x-topic", "images")
+            .body(payload.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let frame = tokio::time::timeout(Duration::from_secs(5), socket.next())
+            .await
+            .expect("no frame in time")
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame, WsMessage::Binary(payload));
+
+        handle.shutdown();
+    }
+
+    /// The value of the sample `series`, e.g. `name{label="value"}`, in the
+    /// `/metrics` scrape of `addr`, or `None` if there is no such sample.
+    async fn metric(addr: SocketAddr, series: &str) -> Option<f64> {
+        let scrape = reqwest::get(format!("http://{addr}/metrics"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        scrape.lines().find_map(|line| {
+            let value = line.strip_prefix(series)?.strip_prefix(' ')?;
+            Some(value.parse().unwrap())
+        })
+    }
+
+    #[tokio::test]
+    async fn counts_publishes_in_the_metrics() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let before = metric(addr, "isimud_published_messages_total").await;
+        assert_eq!(before, Some(0.0));
+
+        let response = publish(addr, with_password("secret"), "a", "hello").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let after = metric(addr, "isimud_published_messages_total").await;
+        assert_eq!(after, Some(1.0));
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn rejects_publishing_with_another_publishers_password() {
+        let config = Config {
+            credentials: Some(HashMap::from([
+                (String::from("alice"), String::from("a-pass")),
+                (String::from("bob"), String::from("b-pass")),
+            ])),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "news").await;
+
+        let as_bob = |request: reqwest::RequestBuilder| request.basic_auth("bob", Some("a-pass"));
+        let response = publish(addr, as_bob, "news", "forged").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let as_alice =
+            |request: reqwest::RequestBuilder| request.basic_auth("alice", Some("a-pass"));
+        let response = publish(addr, as_alice, "news", "genuine").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let received = barrier(&mut socket).await;
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["publisher"], "alice");
+        assert_eq!(received[0]["data"], "genuine");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn enforces_the_topic_acl() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            acl: Some(HashMap::from([(
+                String::from("p"),
+                vec![String::from("sensors/#")],
+            )])),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+
+        let response = publish(addr, with_password("secret"), "sensors/a", "allowed").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = publish(addr, with_password("secret"), "alerts", "denied").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value =
+            serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(body["code"], "FORBIDDEN");
+        let as_other = |request: reqwest::RequestBuilder| request.basic_auth("q", Some("secret"));
+        let response = publish(addr, as_other, "sensors/a", "unlisted").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        handle.shutdown();
+    }
+
+    /// An authorization server for `AUTH_URL`, counting the requests it gets.
+    struct AuthServer {
+        url: String,
+        requests: Arc<std::sync::atomic::AtomicUsize>,
+        handle: axum_server::Handle,
+    }
+
+    impl AuthServer {
+        /// Answers every request with `status` and `body` after `delay`.
+        fn spawn(status: StatusCode, body: &'static str, delay: Duration) -> Self {
+            let requests = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let counter = requests.clone();
+            let app = Router::new().fallback(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(delay).await;
+                (status, body)
+            });
+            let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let handle = axum_server::Handle::new();
+            let server = axum_server::from_tcp(listener).handle(handle.clone());
+            tokio::spawn(server.serve(app.into_make_service()));
+            Self {
+                url: format!("http://{addr}/auth"),
+                requests,
+                handle,
+            }
+        }
+
+        fn requests(&self) -> usize {
+            self.requests.load(Ordering::SeqCst)
+        }
+    }
+
+    /// A websocket connection to `/sub` with `token` as its bearer token, once
+    /// the server has said hello.
+    async fn connect_with_token(
+        addr: SocketAddr,
+        token: &str,
+    ) -> Result<Socket, tokio_tungstenite::tungstenite::Error> {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let mut request = format!("ws://{addr}/sub").into_client_request().unwrap();
+        let authorization = format!("Bearer {token}").parse().unwrap();
+        request
+            .headers_mut()
+            .insert(header::AUTHORIZATION, authorization);
+        let (mut socket, _) = tokio_tungstenite::connect_async(request).await?;
+        assert!(next_json(&mut socket).await.get("conn_id").is_some());
+        Ok(socket)
+    }
+
+    #[tokio::test]
+    async fn asks_the_auth_server_once_per_token_within_the_ttl() {
+        let auth = AuthServer::spawn(StatusCode::OK, "", Duration::ZERO);
+        let config = Config {
+            password: Some(String::from("secret")),
+            auth_url: Some(auth.url.clone()),
+            auth_cache_ttl: Some(60),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+
+        for _ in 0..3 {
+            connect_with_token(addr, "token").await.unwrap();
+        }
+        assert_eq!(auth.requests(), 1);
+        connect_with_token(addr, "other").await.unwrap();
+        assert_eq!(auth.requests(), 2);
+
+        handle.shutdown();
+        auth.handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn keeps_jwt_subscribers_to_the_topics_of_their_token() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            jwt_secret: Some(String::from("key")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let claims = json!({ "sub": "alice", "exp": 4102444800u64, "topics": ["a/#"] });
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(b"key"),
+        )
+        .unwrap();
+
+        let sse = |topic: &str| {
+            reqwest::Client::new()
+                .get(format!("http://{addr}/sse?topic={topic}"))
+                .bearer_auth(&token)
+                .send()
+        };
+        assert_eq!(sse("a/b").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(sse("b/c").await.unwrap().status(), StatusCode::FORBIDDEN);
+
+        let mut socket = connect_with_token(addr, &token).await.unwrap();
+        send_json(&mut socket, json!({ "topic": ["a/b", "b/c"] })).await;
+        barrier(&mut socket).await;
+        for topic in ["a/b", "b/c"] {
+            publish(addr, with_password("secret"), topic, topic).await;
+        }
+        let received = barrier(&mut socket).await;
+        let messages: Vec<_> = received
+            .into_iter()
+            .filter(|frame| frame.get("data").is_some())
+            .collect();
+        assert_eq!(sorted_data(&messages), ["a/b"]);
+
+        handle.shutdown();
+    }
+
+    /// Reads `socket` until the server ends the connection, returning the close
+    /// frame if it sent one.
+    async fn closed(
+        socket: &mut Socket,
+    ) -> Option<tokio_tungstenite::tungstenite::protocol::CloseFrame<'static>> {
+        let close = async {
+            loop {
+                match socket.next().await {
+                    Some(Ok(WsMessage::Close(frame))) => return frame,
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => return None,
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), close)
+            .await
+            .expect("connection not closed in time")
+    }
+
+    #[tokio::test]
+    async fn disconnects_a_subscriber_that_misses_a_pong() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ping_interval: Some(1),
+            ping_timeout: Some(1),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "a").await;
+        let mut responsive = subscribe(addr, "a").await;
+        let responsive = tokio::spawn(async move {
+            let read = async { while let Some(Ok(_)) = responsive.next().await {} };
+            let _ = tokio::time::timeout(Duration::from_secs(3), read).await;
+            responsive
+        });
+
+        // Pongs are only sent while the socket is read.
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        closed(&mut socket).await;
+        barrier(&mut responsive.await.unwrap()).await;
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn closes_connections_that_do_not_subscribe_in_time() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            subscribe_timeout: Some(1),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut silent = connect(addr).await;
+        let (mut subscribed, _) = subscribe_with(addr, json!({ "topic": "a" })).await;
+
+        let frame = closed(&mut silent).await.expect("no close frame");
+        assert_eq!(u16::from(frame.code), 1008);
+        let reason: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert_eq!(reason["error"], "subscribe timeout");
+        assert!(reason["retry_after_ms"].is_u64());
+        barrier(&mut subscribed).await;
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn publishes_over_a_subscribed_websocket() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut other = subscribe(addr, "chat").await;
+        let mut socket = subscribe(addr, "chat").await;
+
+        let publish = json!({ "action": "publish", "topic": "chat", "data": "hi" });
+        send_json(&mut socket, publish.clone()).await;
+        let refused = barrier(&mut socket).await;
+        assert!(refused[0]["error"].is_string(), "{refused:?}");
+        let authenticate = |password| json!({ "action": "authenticate", "username": "greeter", "password": password });
+        send_json(&mut socket, authenticate("wrong")).await;
+        send_json(&mut socket, publish.clone()).await;
+        let refused = barrier(&mut socket).await;
+        assert_eq!(refused.len(), 2);
+        assert!(refused.iter().all(|frame| frame["error"].is_string()));
+
+        send_json(&mut socket, authenticate("secret")).await;
+        send_json(&mut socket, publish).await;
+        let own = barrier(&mut socket).await;
+        assert_eq!(own.len(), 1);
+        assert_eq!(own[0]["publisher"], "greeter");
+        assert_eq!(own[0]["data"], "hi");
+        let delivered = barrier(&mut other).await;
+        assert_eq!(sorted_data(&delivered), ["hi"]);
+
+        handle.shutdown();
+    }
+
+    async fn json_body(response: reqwest::Response) -> serde_json::Value {
+        serde_json::from_slice(&response.bytes().await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn counts_the_connected_subscribers() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let delivered_to = || async {
+            let response = publish(addr, with_password("secret"), "a", "hello").await;
+            json_body(response).await["delivered_to"].as_u64().unwrap()
+        };
+        assert_eq!(delivered_to().await, 0);
+
+        let mut first = subscribe(addr, "a").await;
+        let _second = subscribe(addr, "a").await;
+        assert_eq!(delivered_to().await, 2);
+        first.close(None).await.unwrap();
+        while first.next().await.is_some() {}
+        let mut counted = 0;
+        for _ in 0..50 {
+            counted = delivered_to().await;
+            if counted == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(counted, 1);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn rejects_an_oversized_body() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            max_payload_bytes: Some(100),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let client = reqwest::Client::new();
+        for (path, body) in [
+            (
+                "pub",
+                json!({ "topic": "a", "data": "x".repeat(100) }).to_string(),
+            ),
+            ("pub/binary", "x".repeat(101)),
+        ] {
+            let response = client
+                .post(format!("http://{addr}/{path}"))
+                .basic_auth("p", Some("secret"))
+                .header("content-type", "application/json")
+                .header("x-topic", "a")
+                .body(body)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE, "{path}");
+        }
+
+        let response = publish(addr, with_password("secret"), "a", "small").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        handle.shutdown();
+    }
+
+    /// Keeps the spans exported by a test instead of sending them anywhere.
+    #[derive(Clone, Debug, Default)]
+    struct InMemoryExporter(Arc<std::sync::Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for InMemoryExporter {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[tokio::test]
+    async fn exports_a_span_for_a_publish() {
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        let exporter = InMemoryExporter::default();
+        let provider = opentelemetry::sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+
+        let response = publish(
+            addr,
+            |request| {
+                request.basic_auth("p", Some("secret")).header(
+                    "traceparent",
+                    "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+                )
+            },
+            "a",
+            "hello",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        response.bytes().await.unwrap();
+
+        let mut span = None;
+        for _ in 0..50 {
+            span = exporter
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|span| span.name == "request")
+                .cloned();
+            if span.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let span = span.expect("no span was exported for the publish");
+        assert_eq!(
+            span.span_context.trace_id(),
+            TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap()
+        );
+        assert_eq!(
+            span.parent_span_id,
+            SpanId::from_hex("b7ad6b7169203331").unwrap()
+        );
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn serves_on_ipv6_loopback() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let state = Arc::new(SharedState::new(&config).unwrap());
+        let (addr, handle) = spawn_app_on(Ipv6Addr::LOCALHOST.into(), &config, state).unwrap();
+        assert_eq!(addr.ip(), Ipv6Addr::LOCALHOST);
+
+        let mut socket = subscribe(addr, "a").await;
+        let response = publish(addr, with_password("secret"), "a", "hello").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(next_json(&mut socket).await["data"], "hello");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn reads_the_password_from_a_file() {
+        let path = std::env::temp_dir().join(format!("isimud-password-{}", std::process::id()));
+        std::fs::write(&path, "from-file\n").unwrap();
+        let config = Config {
+            password_file: Some(path.to_str().unwrap().to_string()),
+            password: Some(String::from("from-env")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let response = publish(addr, with_password("from-file"), "a", "hello").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = publish(addr, with_password("from-env"), "a", "hello").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn allows_the_configured_origins() {
+        let allowed_origin = |origins: Option<Vec<&'static str>>, origin: &'static str| async move {
+            let config = Config {
+                password: Some(String::from("secret")),
+                cors_allowed_origins: origins
+                    .map(|origins| origins.into_iter().map(String::from).collect()),
+                ..Config::default()
+            };
+            let (addr, handle) = spawn_server(config).await.unwrap();
+            let response = publish(
+                addr,
+                |request| {
+                    request
+                        .basic_auth("p", Some("secret"))
+                        .header(header::ORIGIN, origin)
+                },
+                "a",
+                "hello",
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            handle.shutdown();
+            response
+                .headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+
+        let origins = Some(vec!["https://a.example", "https://b.example"]);
+        assert_eq!(
+            allowed_origin(origins.clone(), "https://b.example")
+                .await
+                .as_deref(),
+            Some("https://b.example")
+        );
+        assert_eq!(allowed_origin(origins, "https://c.example").await, None);
+        assert_eq!(
+            allowed_origin(Some(vec!["*"]), "https://c.example")
+                .await
+                .as_deref(),
+            Some("*")
+        );
+        assert_eq!(allowed_origin(None, "https://a.example").await, None);
+    }
+
+    #[tokio::test]
+    async fn publishes_only_from_the_allowed_sources() {
+        for (cidr, allowed, blocked) in [
+            (
+                "127.0.0.0/8",
+                IpAddr::from(Ipv4Addr::LOCALHOST),
+                Ipv6Addr::LOCALHOST.into(),
+            ),
+            (
+                "::1/128",
+                Ipv6Addr::LOCALHOST.into(),
+                Ipv4Addr::LOCALHOST.into(),
+            ),
+        ] {
+            let config = Config {
+                password: Some(String::from("secret")),
+                pub_allow_cidrs: Some(vec![cidr.parse().unwrap()]),
+                ..Config::default()
+            };
+            let state = Arc::new(SharedState::new(&config).unwrap());
+            let (allowed_addr, allowed_handle) =
+                spawn_app_on(allowed, &config, state.clone()).unwrap();
+            let (blocked_addr, blocked_handle) = spawn_app_on(blocked, &config, state).unwrap();
+
+            let response = publish(allowed_addr, with_password("secret"), "a", "hello").await;
+            assert_eq!(response.status(), StatusCode::OK, "{allowed} in {cidr}");
+            let response = publish(blocked_addr, with_password("secret"), "a", "hello").await;
+            assert_eq!(
+                response.status(),
+                StatusCode::FORBIDDEN,
+                "{blocked} in {cidr}"
+            );
+
+            allowed_handle.shutdown();
+            blocked_handle.shutdown();
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_over_the_limit() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            max_connections: Some(2),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let _first = subscribe(addr, "greetings").await;
+        let second = subscribe(addr, "greetings").await;
+
+        for _ in 0..3 {
+            let rejected = tokio_tungstenite::connect_async(format!("ws://{addr}/sub")).await;
+            let Err(tokio_tungstenite::tungstenite::Error::Http(response)) = rejected else {
+                panic!("a connection over the limit was accepted");
+            };
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+
+        drop(second);
+        let mut accepted = false;
+        for _ in 0..50 {
+            if tokio_tungstenite::connect_async(format!("ws://{addr}/sub"))
+                .await
+                .is_ok()
+            {
+                accepted = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(accepted, "the closed connection still holds a slot");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn explains_closing_on_malformed_input() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let invalid = (1007, "invalid subscription payload");
+        let unsupported = (1003, "unexpected message type");
+        for (subscribed, message, (code, error)) in [
+            (false, WsMessage::Text("{\"topic\":".into()), invalid),
+            (false, WsMessage::Binary(vec![1, 2, 3]), unsupported),
+            (true, WsMessage::Text("[]".into()), invalid),
+            (true, WsMessage::Binary(vec![1, 2, 3]), unsupported),
+        ] {
+            let mut socket = if subscribed {
+                subscribe(addr, "a").await
+            } else {
+                connect(addr).await
+            };
+            socket.send(message).await.unwrap();
+            let frame = closed(&mut socket).await.expect("no close frame");
+            assert_eq!(u16::from(frame.code), code);
+            let reason: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+            assert_eq!(reason, json!({ "error": error }));
+        }
+
+        handle.shutdown();
+    }
+
+    /// The subscribers listed by `/admin/subscriptions`, with `admin` as the
+    /// admin password.
+    async fn admin_subscribers(addr: SocketAddr) -> Vec<serde_json::Value> {
+        let response = reqwest::Client::new()
+            .get(format!("http://{addr}/admin/subscriptions"))
+            .basic_auth("admin", Some("admin"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = json_body(response).await;
+        body["subscribers"].as_array().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn takes_the_identity_from_the_auth_service() {
+        for (body, identity) in [
+            (r#"{"user": "alice"}"#, json!("alice")),
+            (r#"{"name": "alice"}"#, json!(null)),
+            ("alice", json!(null)),
+        ] {
+            let auth = AuthServer::spawn(StatusCode::OK, body, Duration::ZERO);
+            let config = Config {
+                password: Some(String::from("secret")),
+                admin_password: Some(String::from("admin")),
+                auth_url: Some(auth.url.clone()),
+                auth_identity_field: Some(String::from("user")),
+                ..Config::default()
+            };
+            let (addr, handle) = spawn_server(config).await.unwrap();
+            let mut socket = connect_with_token(addr, "token").await.unwrap();
+            send_json(&mut socket, json!({ "topic": "a" })).await;
+            assert_eq!(barrier(&mut socket).await, Vec::<serde_json::Value>::new());
+
+            let subscribers = admin_subscribers(addr).await;
+            assert_eq!(subscribers.len(), 1, "{body}");
+            assert_eq!(subscribers[0]["identity"], identity, "{body}");
+
+            handle.shutdown();
+            auth.handle.shutdown();
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_the_connected_subscribers() {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let config = Config {
+            password: Some(String::from("secret")),
+            admin_password: Some(String::from("admin")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut request = format!("ws://{addr}/sub").into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert(header::USER_AGENT, "sensor-dashboard/1.0".parse().unwrap());
+        let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        let conn_id = next_json(&mut socket).await["conn_id"].clone();
+        send_json(
+            &mut socket,
+            json!({ "publisher": "p", "topic": "sensors/#" }),
+        )
+        .await;
+        assert_eq!(barrier(&mut socket).await, Vec::<serde_json::Value>::new());
+
+        let tokio_tungstenite::MaybeTlsStream::Plain(stream) = socket.get_ref() else {
+            unreachable!("connected without TLS");
+        };
+        let local_addr = stream.local_addr().unwrap();
+        assert_eq!(
+            admin_subscribers(addr).await,
+            [json!({
+                "conn_id": conn_id,
+                "addr": local_addr.to_string(),
+                "user_agent": "sensor-dashboard/1.0",
+                "identity": null,
+                "subscriptions": [{ "publisher": "p", "topic": "sensors/#", "filter": null }],
+            })]
+        );
+
+        let response = reqwest::Client::new()
+            .get(format!("http://{addr}/admin/subscriptions"))
+            .basic_auth("admin", Some("secret"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        socket.close(None).await.unwrap();
+        while socket.next().await.is_some() {}
+        let mut subscribers = Vec::new();
+        for _ in 0..50 {
+            subscribers = admin_subscribers(addr).await;
+            if subscribers.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(subscribers, Vec::<serde_json::Value>::new());
+
+        handle.shutdown();
+    }
+
+    /// Closes `socket`, returning once the server has closed it as well.
+    async fn disconnect(mut socket: Socket) {
+        socket.close(None).await.unwrap();
+        while socket.next().await.is_some() {}
+    }
+
+    #[tokio::test]
+    async fn redelivers_unacknowledged_messages() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ack_buffer_size: Some(2),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let subscription = json!({ "topic": "a", "session": "s" });
+
+        let (mut socket, _) = subscribe_with(addr, subscription.clone()).await;
+        publish(addr, with_password("secret"), "a", "1").await;
+        publish(addr, with_password("secret"), "a", "2").await;
+        let first = next_json(&mut socket).await;
+        assert_eq!(first["data"], "1");
+        assert_eq!(next_json(&mut socket).await["data"], "2");
+        send_json(&mut socket, json!({ "action": "ack", "seq": first["seq"] })).await;
+        assert_eq!(barrier(&mut socket).await, Vec::<serde_json::Value>::new());
+        disconnect(socket).await;
+
+        // Only the message left unacknowledged comes again, and still awaits an ack.
+        let (mut socket, redelivered) = subscribe_with(addr, subscription.clone()).await;
+        assert_eq!(sorted_data(&redelivered), ["2"]);
+        send_json(
+            &mut socket,
+            json!({ "action": "ack", "seq": redelivered[0]["seq"] }),
+        )
+        .await;
+        assert_eq!(barrier(&mut socket).await, Vec::<serde_json::Value>::new());
+        disconnect(socket).await;
+        let (mut socket, redelivered) = subscribe_with(addr, subscription.clone()).await;
+        assert_eq!(redelivered, Vec::<serde_json::Value>::new());
+
+        // The oldest unacknowledged message makes room once the buffer is full.
+        for data in ["3", "4", "5"] {
+            publish(addr, with_password("secret"), "a", data).await;
+        }
+        assert_eq!(sorted_data(&barrier(&mut socket).await), ["3", "4", "5"]);
+        disconnect(socket).await;
+        let (_socket, redelivered) = subscribe_with(addr, subscription).await;
+        assert_eq!(sorted_data(&redelivered), ["4", "5"]);
+
+        handle.shutdown();
+    }
+
+    /// Publishes the `/pub` body `msg` as the publisher `p` with the password `secret`.
+    async fn publish_json(addr: SocketAddr, msg: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("http://{addr}/pub"))
+            .basic_auth("p", Some("secret"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(msg.to_string())
+            .send()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn skips_messages_expiring_before_a_slow_subscriber_gets_them() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            max_payload_bytes: Some(4 << 20),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "scores").await;
+
+        // Not reading fills the socket buffers, which holds up later messages.
+        let filler = "x".repeat(2 << 20);
+        for _ in 0..8 {
+            publish(addr, with_password("secret"), "scores", &filler).await;
+        }
+        let msg = json!({ "topic": "scores", "data": "1:0", "ttl_ms": 50 });
+        assert_eq!(publish_json(addr, msg).await.status(), StatusCode::OK);
+        publish(addr, with_password("secret"), "scores", "final").await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let mut received = Vec::new();
+        loop {
+            let data = next_json(&mut socket).await["data"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            if data == "final" {
+                break;
+            }
+            received.push(data);
+        }
+        assert_eq!(received, vec![filler; 8]);
+
+        // Nor is an expired value retained for later subscribers.
+        let msg = json!({ "topic": "scores", "data": "2:0", "ttl_ms": 50, "retain": true });
+        assert_eq!(publish_json(addr, msg).await.status(), StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        subscribe(addr, "scores").await;
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn publishes_the_valid_items_of_a_batch() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            acl: Some(HashMap::from([(
+                String::from("p"),
+                vec![String::from("sensors/#")],
+            )])),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "#").await;
+        let batch = json!([
+            { "topic": "sensors/a", "data": "1" },
+            { "topic": "alerts", "data": "2" },
+            { "topic": "sensors/\u{7}", "data": "3" },
+            { "topic": "sensors/b", "data": "4" },
+        ]);
+        let publish_batch = |query: &'static str| {
+            let batch = batch.to_string();
+            async move {
+                let response = reqwest::Client::new()
+                    .post(format!("http://{addr}/pub/batch{query}"))
+                    .basic_auth("p", Some("secret"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(batch)
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                json_body(response).await
+            }
+        };
+
+        let results = publish_batch("").await;
+        assert_eq!(
+            results,
+            json!([
+                { "delivered_to": 1 },
+                { "error": "Forbidden", "code": "FORBIDDEN" },
+                { "error": "Invalid topic", "code": "INVALID_TOPIC" },
+                { "delivered_to": 1 },
+            ])
+        );
+        assert_eq!(sorted_data(&barrier(&mut socket).await), ["1", "4"]);
+
+        let results = publish_batch("?fail_fast=true").await;
+        assert_eq!(
+            results,
+            json!([
+                { "delivered_to": 1 },
+                { "error": "Forbidden", "code": "FORBIDDEN" },
+            ])
+        );
+        assert_eq!(sorted_data(&barrier(&mut socket).await), ["1"]);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn delivers_only_messages_containing_the_filter() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let subscription = json!({ "topic": "logs", "filter": { "contains": "ERROR" } });
+        let (mut socket, _) = subscribe_with(addr, subscription).await;
+
+        for data in [
+            "INFO started",
+            "ERROR disk full",
+            "WARN slow",
+            "ERROR timeout",
+        ] {
+            publish(addr, with_password("secret"), "logs", data).await;
+        }
+        let received: Vec<_> = barrier(&mut socket)
+            .await
+            .into_iter()
+            .map(|msg| msg["data"].clone())
+            .collect();
+        assert_eq!(received, ["ERROR disk full", "ERROR timeout"]);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn redirects_to_the_homepage_url() {
+        let location = |homepage_url: Option<&'static str>| async move {
+            let mut vars = HashMap::from([
+                (String::from("PASSWORD"), String::from("secret")),
+                (String::from("HOMEPAGE"), String::from("true")),
+            ]);
+            if let Some(homepage_url) = homepage_url {
+                vars.insert(String::from("HOMEPAGE_URL"), homepage_url.to_string());
+            }
+            let (addr, handle) = spawn_server(Config::load_from(&vars).unwrap())
+                .await
+                .unwrap();
+            let response = reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap()
+                .get(format!("http://{addr}/"))
+                .send()
+                .await
+                .unwrap();
+            handle.shutdown();
+            assert!(response.status().is_redirection());
+            response.headers()[header::LOCATION]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(
+            location(Some("https://status.example.com/")).await,
+            "https://status.example.com/"
+        );
+        assert_eq!(
+            location(None).await,
+            "https://github.com/tropicbliss/isimud/"
+        );
+
+        let config = Config {
+            password: Some(String::from("secret")),
+            homepage_url: Some(String::from("not a url")),
+            ..Config::default()
+        };
+        assert!(SharedState::new(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_the_dashboard() {
+        let homepage = |homepage: Homepage| async move {
+            let config = Config {
+                password: Some(String::from("secret")),
+                homepage: Some(homepage),
+                ..Config::default()
+            };
+            let (addr, handle) = spawn_server(config).await.unwrap();
+            let response = reqwest::get(format!("http://{addr}/")).await.unwrap();
+            let metrics = reqwest::get(format!("http://{addr}/metrics"))
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap();
+            handle.shutdown();
+            (response, metrics)
+        };
+
+        let (response, metrics) = homepage(Homepage::Dashboard).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let content_type = response.headers()[header::CONTENT_TYPE].to_str().unwrap();
+        assert!(content_type.starts_with("text/html"), "{content_type}");
+        let page = response.text().await.unwrap();
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        // The series the page polls for are still there.
+        for series in [
+            "isimud_published_messages_total",
+            "isimud_active_subscribers",
+        ] {
+            assert!(
+                page.contains(series) && metrics.contains(series),
+                "{series}"
+            );
+        }
+
+        let (response, _) = homepage(Homepage::Disabled).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// A websocket connection to `/sub` offering the subprotocols `protocols`.
+    async fn connect_with_protocol(
+        addr: SocketAddr,
+        protocols: &str,
+    ) -> Result<
+        (
+            Socket,
+            tokio_tungstenite::tungstenite::handshake::client::Response,
+        ),
+        tokio_tungstenite::tungstenite::Error,
+    > {
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let mut request = format!("ws://{addr}/sub").into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert(header::SEC_WEBSOCKET_PROTOCOL, protocols.parse().unwrap());
+        tokio_tungstenite::connect_async(request).await
+    }
+
+    #[tokio::test]
+    async fn formats_messages_for_the_negotiated_subprotocol() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            raw_delivery: Some(true),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let (mut json_socket, response) =
+            connect_with_protocol(addr, "isimud.v1.json").await.unwrap();
+        assert_eq!(
+            response.headers()[header::SEC_WEBSOCKET_PROTOCOL],
+            "isimud.v1.json"
+        );
+        assert!(next_json(&mut json_socket).await.get("conn_id").is_some());
+        send_json(&mut json_socket, json!({ "topic": "a" })).await;
+        let confirmation = barrier(&mut json_socket).await;
+        assert_eq!(confirmation[0]["type"], "subscribed");
+        // Raw delivery sends nothing but message data, not even a hello, but
+        // catches what is published while the subscription is on its way.
+        let (mut raw_socket, response) = connect_with_protocol(addr, "unknown.v2, isimud.v1.raw")
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()[header::SEC_WEBSOCKET_PROTOCOL],
+            "isimud.v1.raw"
+        );
+        send_json(&mut raw_socket, json!({ "topic": "a" })).await;
+        // Catching starts along with counting the connection.
+        while metric(addr, "isimud_active_subscribers").await != Some(2.0) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        publish(addr, with_password("secret"), "a", "hello").await;
+        let envelope = next_json(&mut json_socket).await;
+        assert_eq!(
+            (&envelope["topic"], &envelope["data"]),
+            (&json!("a"), &json!("hello"))
+        );
+        let raw = raw_socket.next().await.unwrap().unwrap();
+        assert_eq!(raw, WsMessage::Text(String::from("hello")));
+
+        let rejected = connect_with_protocol(addr, "unknown.v2").await;
+        let Err(tokio_tungstenite::tungstenite::Error::Http(response)) = rejected else {
+            panic!("an unknown subprotocol was accepted");
+        };
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn keeps_messages_published_while_subscribing() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        for _ in 0..5 {
+            let mut socket = connect(addr).await;
+            let publisher = tokio::spawn(async move {
+                for i in 0..50 {
+                    publish(addr, with_password("secret"), "a", &i.to_string()).await;
+                }
+            });
+            tokio::task::yield_now().await;
+            send_json(&mut socket, json!({ "topic": "a" })).await;
+            publisher.await.unwrap();
+
+            let received: Vec<_> = barrier(&mut socket)
+                .await
+                .into_iter()
+                .map(|msg| msg["data"].as_str().unwrap().to_string())
+                .collect();
+            let published: Vec<_> = (0..50).map(|i| i.to_string()).collect();
+            assert_eq!(received, published);
+        }
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn counts_the_messages_per_topic() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            metrics_max_topics: Some(2),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        for topic in ["a", "b", "a", "c", "d"] {
+            publish(addr, with_password("secret"), topic, "hello").await;
+        }
+
+        let series = |topic: &str| format!("isimud_messages_total{{topic=\"{topic}\"}}");
+        assert_eq!(metric(addr, &series("a")).await, Some(2.0));
+        assert_eq!(metric(addr, &series("b")).await, Some(1.0));
+        // Topics beyond METRICS_MAX_TOPICS share a counter.
+        assert_eq!(metric(addr, &series("c")).await, None);
+        assert_eq!(metric(addr, &series("other")).await, Some(2.0));
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn sends_the_connection_id_in_the_hello_frame() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut conn_ids = Vec::new();
+        for _ in 0..2 {
+            let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/sub"))
+                .await
+                .unwrap();
+            let hello = next_json(&mut socket).await;
+            let conn_id = hello["conn_id"].as_str().expect("no conn_id in the hello");
+            conn_ids.push(uuid::Uuid::parse_str(conn_id).unwrap());
+        }
+        assert_ne!(conn_ids[0], conn_ids[1]);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn accepts_publishes_nobody_received_when_waiting_for_a_subscriber() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let publish_waiting = |query: &'static str| async move {
+            let response = reqwest::Client::new()
+                .post(format!("http://{addr}/pub{query}"))
+                .basic_auth("p", Some("secret"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(json!({ "topic": "a", "data": "hello" }).to_string())
+                .send()
+                .await
+                .unwrap();
+            (response.status(), json_body(response).await)
+        };
+        let waiting = "?wait_for_subscriber=true";
+
+        let (status, body) = publish_waiting(waiting).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["delivered_to"], 0);
+        assert_eq!(publish_waiting("").await.0, StatusCode::OK);
+
+        let _socket = subscribe(addr, "a").await;
+        let (status, body) = publish_waiting(waiting).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["delivered_to"], 1);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn is_not_ready_while_the_auth_service_is_down() {
+        let auth = AuthServer::spawn(StatusCode::OK, "", Duration::ZERO);
+        let config = Config {
+            password: Some(String::from("secret")),
+            auth_url: Some(auth.url.clone()),
+            ..Config::default()
+        };
+        let state = Arc::new(SharedState::new(&config).unwrap());
+        let (addr, handle) = spawn_app(&config, state.clone()).unwrap();
+        let ready = || async {
+            reqwest::get(format!("http://{addr}/ready"))
+                .await
+                .unwrap()
+                .status()
+        };
+        assert_eq!(ready().await, StatusCode::SERVICE_UNAVAILABLE);
+        mark_ready(state.clone()).await;
+
+        assert_eq!(ready().await, StatusCode::OK);
+        assert_eq!(ready().await, StatusCode::OK);
+        // Starting up and one probe, whose outcome the second check reused.
+        assert_eq!(auth.requests(), 2);
+
+        auth.handle.shutdown();
+        let mut status = StatusCode::OK;
+        for _ in 0..50 {
+            // Stands in for the probe outcome expiring.
+            *state.auth_probe.lock().await = None;
+            status = ready().await;
+            if status != StatusCode::OK {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn tells_an_unavailable_auth_service_from_a_rejected_token() {
+        let rejection = |status: StatusCode, delay: Duration| async move {
+            let auth = AuthServer::spawn(status, "", delay);
+            let config = Config {
+                password: Some(String::from("secret")),
+                auth_url: Some(auth.url.clone()),
+                auth_request_timeout: Some(1),
+                ..Config::default()
+            };
+            let (addr, handle) = spawn_server(config).await.unwrap();
+            let rejected = connect_with_token(addr, "token").await;
+            handle.shutdown();
+            auth.handle.shutdown();
+            let Err(tokio_tungstenite::tungstenite::Error::Http(response)) = rejected else {
+                panic!("the connection was accepted");
+            };
+            let body: serde_json::Value =
+                serde_json::from_slice(response.body().as_deref().unwrap()).unwrap();
+            (response.status(), body["code"].clone())
+        };
+
+        assert_eq!(
+            rejection(StatusCode::OK, Duration::from_secs(3)).await,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!("AUTH_BACKEND_UNAVAILABLE")
+            )
+        );
+        assert_eq!(
+            rejection(StatusCode::UNAUTHORIZED, Duration::ZERO).await,
+            (StatusCode::UNAUTHORIZED, json!("WRONG_CREDENTIALS"))
+        );
+    }
+
+    /// A subscriber of `topic` on `addr` that falls so far behind that messages
+    /// are dropped before it receives them, as configured with a broadcast
+    /// capacity of 2.
+    async fn lagging_subscriber(addr: SocketAddr, topic: &str) -> Socket {
+        let socket = subscribe(addr, topic).await;
+        // Not reading fills the socket buffers, which holds up later messages.
+        let filler = "x".repeat(2 << 20);
+        for _ in 0..4 {
+            publish(addr, with_password("secret"), topic, &filler).await;
+        }
+        for i in 0..10 {
+            publish(addr, with_password("secret"), topic, &i.to_string()).await;
+        }
+        socket
+    }
+
+    #[tokio::test]
+    async fn applies_the_slow_subscriber_policy() {
+        let config = |policy| Config {
+            password: Some(String::from("secret")),
+            max_payload_bytes: Some(4 << 20),
+            broadcast_capacity: Some(2),
+            slow_subscriber_policy: Some(policy),
+            ..Config::default()
+        };
+
+        let (addr, handle) = spawn_server(config(SlowSubscriberPolicy::Skip))
+            .await
+            .unwrap();
+        let mut socket = lagging_subscriber(addr, "a").await;
+        publish(addr, with_password("secret"), "a", "after").await;
+        let received = barrier(&mut socket).await;
+        // Leaves out the lag notice.
+        let data: Vec<_> = received
+            .iter()
+            .filter_map(|msg| msg["data"].as_str())
+            .collect();
+        assert!(data.len() < 15, "nothing was skipped");
+        assert_eq!(data.last(), Some(&"after"));
+        assert!(metric(addr, "isimud_lag_events_total").await >= Some(1.0));
+        handle.shutdown();
+
+        let (addr, handle) = spawn_server(config(SlowSubscriberPolicy::Disconnect))
+            .await
+            .unwrap();
+        let mut socket = lagging_subscriber(addr, "a").await;
+        let frame = closed(&mut socket).await.expect("no close frame");
+        assert_eq!(u16::from(frame.code), 4000);
+        let reason: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+        assert_eq!(reason["error"], "too slow");
+        assert!(reason["retry_after_ms"].is_u64());
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn publishes_without_credentials_in_dev_mode_on_loopback_only() {
+        let dev_mode = |ip: Option<Vec<IpAddr>>| Config {
+            dev_mode: Some(true),
+            ip,
+            ..Config::default()
+        };
+        for ip in ["0.0.0.0", "::", "192.168.1.10"] {
+            let ips = vec![Ipv4Addr::LOCALHOST.into(), ip.parse().unwrap()];
+            assert!(SharedState::new(&dev_mode(Some(ips))).is_err(), "{ip}");
+        }
+        let ips = vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()];
+        assert!(SharedState::new(&dev_mode(Some(ips))).is_ok());
+
+        let (addr, handle) = spawn_server(dev_mode(None)).await.unwrap();
+        let mut socket = subscribe(addr, "a").await;
+        let response = publish(addr, |request| request, "a", "hello").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = publish(
+            addr,
+            |request| request.basic_auth("alice", Some("")),
+            "a",
+            "hi",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let publishers: Vec<_> = barrier(&mut socket)
+            .await
+            .into_iter()
+            .map(|msg| msg["publisher"].clone())
+            .collect();
+        assert_eq!(publishers, ["anonymous", "alice"]);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn reports_the_latest_sequence_number_in_the_hello_frame() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let hello_seq = || async {
+            let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/sub"))
+                .await
+                .unwrap();
+            next_json(&mut socket).await["seq"].clone()
+        };
+        // Nothing was published yet.
+        assert_eq!(hello_seq().await, json!(null));
+
+        let (mut socket, _) = subscribe_with(addr, json!({ "topic": "#", "since_seq": 0 })).await;
+        for topic in ["a", "b", "a"] {
+            publish(addr, with_password("secret"), topic, "hello").await;
+        }
+        // The sequence number of the last message, which a client compares with
+        // the last one it saw to tell what it missed.
+        assert_eq!(hello_seq().await, 2);
+        let seqs: Vec<_> = barrier(&mut socket)
+            .await
+            .into_iter()
+            .map(|msg| msg["seq"].clone())
+            .collect();
+        assert_eq!(seqs, [0, 1, 2]);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn publishes_a_form_body() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "forms").await;
+        let post = |content_type: &'static str, body: &'static str| {
+            reqwest::Client::new()
+                .post(format!("http://{addr}/pub"))
+                .basic_auth("p", Some("secret"))
+                .header(header::CONTENT_TYPE, content_type)
+                .body(body)
+                .send()
+        };
+
+        let response = post(
+            "application/x-www-form-urlencoded",
+            "topic=forms&data=hello+world%21",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(next_json(&mut socket).await["data"], "hello world!");
+
+        let response = post("text/plain", "hello").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn counts_the_compressed_frames() {
+        use std::io::Read;
+
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let subscription = json!({ "topic": "a", "compress": true });
+        let (mut socket, _) = subscribe_with(addr, subscription).await;
+
+        publish(addr, with_password("secret"), "a", "small").await;
+        assert_eq!(next_json(&mut socket).await["data"], "small");
+        assert_eq!(
+            metric(addr, "isimud_compressed_frames_total").await,
+            Some(0.0)
+        );
+
+        let large = "hello ".repeat(1000);
+        publish(addr, with_password("secret"), "a", &large).await;
+        let Some(Ok(WsMessage::Binary(compressed))) = socket.next().await else {
+            panic!("the large message was not compressed");
+        };
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut text)
+            .unwrap();
+        let msg: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(msg["data"], large);
+        assert_eq!(
+            metric(addr, "isimud_compressed_frames_total").await,
+            Some(1.0)
+        );
+        let saved = (text.len() - compressed.len()) as f64;
+        assert_eq!(
+            metric(addr, "isimud_compression_saved_bytes_total").await,
+            Some(saved)
+        );
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn keeps_the_order_of_each_key_under_concurrent_publishes() {
+        let config = Config {
+            credentials: Some(HashMap::from([
+                (String::from("p"), String::from("secret")),
+                (String::from("q"), String::from("secret")),
+            ])),
+            shard_by_publisher: Some(true),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        // Messages reach this subscriber over the topic, the publisher and the
+        // wildcard channel.
+        let (mut socket, _) = subscribe_with(addr, json!({ "topic": ["orders", "#"] })).await;
+        send_json(&mut socket, json!({ "publisher": "q", "topic": "orders" })).await;
+        barrier(&mut socket).await;
+
+        let publishers: Vec<_> = ["p", "q"]
+            .into_iter()
+            .flat_map(|publisher| ["k1", "k2"].map(|key| (publisher, key)))
+            .map(|(publisher, key)| {
+                tokio::spawn(async move {
+                    let client = reqwest::Client::new();
+                    for i in 0..20 {
+                        let msg = json!({ "topic": "orders", "data": i.to_string(), "key": key });
+                        client
+                            .post(format!("http://{addr}/pub"))
+                            .basic_auth(publisher, Some("secret"))
+                            .header(header::CONTENT_TYPE, "application/json")
+                            .body(msg.to_string())
+                            .send()
+                            .await
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for publisher in publishers {
+            publisher.await.unwrap();
+        }
+
+        let mut per_key: HashMap<_, Vec<_>> = HashMap::new();
+        for msg in barrier(&mut socket).await {
+            let key = (msg["publisher"].to_string(), msg["key"].to_string());
+            per_key
+                .entry(key)
+                .or_default()
+                .push(msg["data"].as_str().unwrap().to_string());
+        }
+        let published: Vec<_> = (0..20).map(|i| i.to_string()).collect();
+        assert_eq!(per_key.len(), 4);
+        for (key, received) in per_key {
+            assert_eq!(received, published, "{key:?}");
+        }
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn resumes_after_the_given_sequence_number() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            history_size: Some(3),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        for i in 0..5 {
+            publish(addr, with_password("secret"), "a", &i.to_string()).await;
+        }
+
+        // Seq 1 was evicted from the history of seqs 2 to 4.
+        let mut sockets = Vec::new();
+        for (since_seq, gap, resumed) in [
+            (3, false, vec![4]),
+            (1, false, vec![2, 3, 4]),
+            (0, true, vec![2, 3, 4]),
+        ] {
+            let subscription = json!({ "topic": "a", "since_seq": since_seq });
+            let (socket, mut received) = subscribe_with(addr, subscription).await;
+            if gap {
+                assert_eq!(
+                    received.remove(0),
+                    json!({ "gap": { "since_seq": since_seq } })
+                );
+            }
+            let seqs: Vec<_> = received.iter().map(|msg| msg["seq"].clone()).collect();
+            assert_eq!(seqs, resumed, "since_seq {since_seq}");
+            let data: Vec<_> = received.iter().map(|msg| msg["data"].clone()).collect();
+            let expected: Vec<_> = resumed.iter().map(|seq| json!(seq.to_string())).collect();
+            assert_eq!(data, expected);
+            sockets.push(socket);
+        }
+        // Then each carries on live.
+        publish(addr, with_password("secret"), "a", "live").await;
+        for socket in &mut sockets {
+            assert_eq!(next_json(socket).await["data"], "live");
+        }
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn stays_connected_after_unsubscribing_from_everything() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let (mut socket, _) = subscribe_with(addr, json!({ "topic": ["a", "b/#"] })).await;
+
+        send_json(&mut socket, json!({ "action": "unsubscribe_all" })).await;
+        assert_eq!(barrier(&mut socket).await, Vec::<serde_json::Value>::new());
+        for topic in ["a", "b/c"] {
+            publish(addr, with_password("secret"), topic, "idle").await;
+        }
+        assert_eq!(barrier(&mut socket).await, Vec::<serde_json::Value>::new());
+
+        send_json(&mut socket, json!({ "topic": "b/#" })).await;
+        assert_eq!(barrier(&mut socket).await, Vec::<serde_json::Value>::new());
+        for topic in ["a", "b/c"] {
+            publish(addr, with_password("secret"), topic, topic).await;
+        }
+        assert_eq!(sorted_data(&barrier(&mut socket).await), ["b/c"]);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn limits_the_topics_per_connection() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            max_topics_per_conn: Some(2),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let too_many = json!({ "error": "Too many topics", "code": "TOO_MANY_TOPICS" });
+
+        let (mut socket, received) =
+            subscribe_with(addr, json!({ "topic": ["a", "b", "c"] })).await;
+        assert_eq!(received, std::slice::from_ref(&too_many));
+        send_json(&mut socket, json!({ "topic": ["a", "d"] })).await;
+        assert_eq!(barrier(&mut socket).await, [too_many]);
+        for topic in ["a", "b", "c", "d"] {
+            publish(addr, with_password("secret"), topic, topic).await;
+        }
+        assert_eq!(sorted_data(&barrier(&mut socket).await), ["a", "b"]);
+
+        // Unsubscribing makes room again.
+        send_json(
+            &mut socket,
+            json!({ "action": "unsubscribe", "topic": "a" }),
+        )
+        .await;
+        send_json(&mut socket, json!({ "topic": "d" })).await;
+        assert_eq!(barrier(&mut socket).await, Vec::<serde_json::Value>::new());
+        for topic in ["a", "b", "c", "d"] {
+            publish(addr, with_password("secret"), topic, topic).await;
+        }
+        assert_eq!(sorted_data(&barrier(&mut socket).await), ["b", "d"]);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn observes_the_publish_and_delivery_latencies() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let count = |name: &str| format!("isimud_{name}_seconds_count");
+        assert_eq!(metric(addr, &count("publish_duration")).await, Some(0.0));
+        assert_eq!(metric(addr, &count("delivery_latency")).await, Some(0.0));
+
+        let mut socket = subscribe(addr, "a").await;
+        for _ in 0..3 {
+            publish(addr, with_password("secret"), "a", "hello").await;
+        }
+        assert_eq!(barrier(&mut socket).await.len(), 3);
+        assert_eq!(metric(addr, &count("publish_duration")).await, Some(3.0));
+        let bucket = "isimud_publish_duration_seconds_bucket{le=\"+Inf\"}";
+        assert_eq!(metric(addr, bucket).await, Some(3.0));
+        // Deliveries are sampled at most once per LATENCY_SAMPLE_INTERVAL.
+        assert_eq!(metric(addr, &count("delivery_latency")).await, Some(1.0));
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn isolates_subscribers_from_a_flooding_publisher() {
+        let config = Config {
+            credentials: Some(HashMap::from([
+                (String::from("a"), String::from("secret")),
+                (String::from("b"), String::from("secret")),
+            ])),
+            max_payload_bytes: Some(4 << 20),
+            broadcast_capacity: Some(8),
+            shard_by_publisher: Some(true),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let as_publisher = |name: &'static str| {
+            move |request: reqwest::RequestBuilder| request.basic_auth(name, Some("secret"))
+        };
+        let (mut socket, _) = subscribe_with(addr, json!({ "publisher": "b", "topic": "t" })).await;
+
+        // Not reading fills the socket buffers, which holds up later messages.
+        let filler = "x".repeat(2 << 20);
+        for _ in 0..4 {
+            publish(addr, as_publisher("b"), "t", &filler).await;
+        }
+        publish(addr, as_publisher("b"), "t", "b1").await;
+        for i in 0..20 {
+            publish(addr, as_publisher("a"), "t", &i.to_string()).await;
+        }
+        publish(addr, as_publisher("b"), "t", "b2").await;
+
+        let received: Vec<_> = barrier(&mut socket)
+            .await
+            .into_iter()
+            .map(|msg| match msg["data"].as_str().unwrap() {
+                data if data == filler => String::from("filler"),
+                data => data.to_string(),
+            })
+            .collect();
+        assert_eq!(
+            received,
+            ["filler", "filler", "filler", "filler", "b1", "b2"]
+        );
+        assert_eq!(metric(addr, "isimud_lag_events_total").await, Some(0.0));
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn validates_a_dry_run_without_delivering_it() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            acl: Some(HashMap::from([(
+                String::from("p"),
+                vec![String::from("a")],
+            )])),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "#").await;
+        let dry_run = |password: &'static str, topic: &'static str| {
+            reqwest::Client::new()
+                .post(format!("http://{addr}/pub?dry_run=true"))
+                .basic_auth("p", Some(password))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(json!({ "topic": topic, "data": "hello" }).to_string())
+                .send()
+        };
+
+        let response = dry_run("secret", "a").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(response).await["would_deliver_to"], 1);
+        assert_eq!(
+            dry_run("wrong", "a").await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            dry_run("secret", "b").await.unwrap().status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(barrier(&mut socket).await, Vec::<serde_json::Value>::new());
+        assert_eq!(
+            metric(addr, "isimud_published_messages_total").await,
+            Some(0.0)
+        );
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn confirms_the_subscription_before_any_data() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let (mut socket, _) = connect_with_protocol(addr, "isimud.v1.json").await.unwrap();
+        assert!(next_json(&mut socket).await.get("conn_id").is_some());
+
+        // Published while the subscription is on its way, so delivered right
+        // after it is in place.
+        send_json(
+            &mut socket,
+            json!({ "publisher": "p", "topic": ["a", "b"] }),
+        )
+        .await;
+        publish(addr, with_password("secret"), "a", "hello").await;
+        assert_eq!(
+            next_json(&mut socket).await,
+            json!({ "type": "subscribed", "publisher": "p", "topic": ["a", "b"] })
+        );
+        assert_eq!(next_json(&mut socket).await["data"], "hello");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn tells_subscribers_the_server_is_going_away() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let state = Arc::new(SharedState::new(&config).unwrap());
+        let (addr, handle) = spawn_app(&config, state.clone()).unwrap();
+        // Whether or not they subscribed yet.
+        let mut sockets = vec![subscribe(addr, "a").await, connect(addr).await];
+
+        state.shutting_down.send_replace(true);
+        for socket in &mut sockets {
+            let frame = closed(socket).await.expect("no close frame");
+            assert_eq!(u16::from(frame.code), 1001);
+            let reason: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+            assert_eq!(reason["error"], "server shutting down");
+            assert!(reason["retry_after_ms"].is_u64());
+        }
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn takes_the_token_from_the_query_string() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            jwt_secret: Some(String::from("key")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let claims = json!({ "sub": "alice", "exp": 4102444800u64, "topics": ["a/#"] });
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(b"key"),
+        )
+        .unwrap();
+
+        // Browsers cannot set headers on websocket connections.
+        let (mut socket, _) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/sub?token={token}"))
+                .await
+                .unwrap();
+        assert!(next_json(&mut socket).await.get("conn_id").is_some());
+        send_json(&mut socket, json!({ "topic": "a/b" })).await;
+        assert_eq!(barrier(&mut socket).await, Vec::<serde_json::Value>::new());
+        publish(addr, with_password("secret"), "a/b", "hello").await;
+        assert_eq!(next_json(&mut socket).await["data"], "hello");
+
+        for (query, status) in [
+            ("?token=forged", StatusCode::UNAUTHORIZED),
+            ("", StatusCode::BAD_REQUEST),
+        ] {
+            let rejected =
+                tokio_tungstenite::connect_async(format!("ws://{addr}/sub{query}")).await;
+            let Err(tokio_tungstenite::tungstenite::Error::Http(response)) = rejected else {
+                panic!("connected with {query:?}");
+            };
+            assert_eq!(response.status(), status, "{query:?}");
+        }
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn acknowledges_a_barrier_after_the_messages_before_it() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = subscribe(addr, "a").await;
+        /// The next `count` frames, messages shown by their data.
+        async fn received(socket: &mut Socket, count: usize) -> Vec<serde_json::Value> {
+            let mut frames = Vec::new();
+            for _ in 0..count {
+                let frame = next_json(socket).await;
+                frames.push(frame.get("data").cloned().unwrap_or(frame));
+            }
+            frames
+        }
+
+        for data in ["1", "2", "3"] {
+            publish(addr, with_password("secret"), "a", data).await;
+        }
+        send_json(&mut socket, json!({ "action": "barrier", "id": "first" })).await;
+        assert_eq!(
+            received(&mut socket, 4).await,
+            [
+                json!("1"),
+                json!("2"),
+                json!("3"),
+                json!({ "type": "barrier_ack", "id": "first" }),
+            ]
+        );
+        send_json(&mut socket, json!({ "action": "barrier", "id": 2 })).await;
+        assert_eq!(
+            received(&mut socket, 1).await,
+            [json!({ "type": "barrier_ack", "id": 2 })]
+        );
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_stalled_auth_service() {
+        let auth = AuthServer::spawn(StatusCode::OK, "", Duration::from_secs(60));
+        let vars = |timeout: &str| {
+            HashMap::from([
+                (String::from("PASSWORD"), String::from("secret")),
+                (String::from("AUTH_URL"), auth.url.clone()),
+                (String::from("AUTH_REQUEST_TIMEOUT"), timeout.to_string()),
+            ])
+        };
+        let config = Config::load_from(&vars("0")).unwrap();
+        assert!(SharedState::new(&config).is_err());
+        let config = Config::load_from(&vars("1")).unwrap();
+        let (addr, handle) = spawn_server(config).await.unwrap();
+
+        let started = tokio::time::Instant::now();
+        let rejected = connect_with_token(addr, "token").await;
+        let elapsed = started.elapsed();
+        let Err(tokio_tungstenite::tungstenite::Error::Http(response)) = rejected else {
+            panic!("the connection was accepted");
+        };
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(
+            (Duration::from_secs(1)..Duration::from_secs(3)).contains(&elapsed),
+            "{elapsed:?}"
+        );
+        assert_eq!(auth.requests(), 1);
+
+        handle.shutdown();
+        auth.handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn counts_the_connections_drained_on_shutdown() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let state = Arc::new(SharedState::new(&config).unwrap());
+        let (addr, handle) = spawn_app(&config, state.clone()).unwrap();
+        let mut sockets = Vec::new();
+        for topic in ["a", "b", "c"] {
+            sockets.push(subscribe(addr, topic).await);
+        }
+        // Gone before the shutdown, so not drained.
+        disconnect(subscribe(addr, "d").await).await;
+
+        state.shutting_down.send_replace(true);
+        for socket in &mut sockets {
+            assert!(closed(socket).await.is_some());
+        }
+        assert_eq!(state.metrics.drained_connections.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            metric(addr, "isimud_drained_connections_total").await,
+            Some(3.0)
+        );
+        assert_eq!(
+            metric(addr, "isimud_drain_dropped_messages_total").await,
+            Some(0.0)
+        );
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn coalesces_rapid_publishes_to_the_latest() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let (mut coalesced, _) =
+            subscribe_with(addr, json!({ "topic": ["a", "b"], "coalesce_ms": 500 })).await;
+        let (mut every, _) = subscribe_with(addr, json!({ "topic": ["a", "b"] })).await;
+
+        for data in ["1", "2", "3", "4", "5"] {
+            publish(addr, with_password("secret"), "a", data).await;
+        }
+        publish(addr, with_password("secret"), "b", "6").await;
+        assert_eq!(sorted_data(&barrier(&mut coalesced).await), ["5", "6"]);
+        assert_eq!(
+            sorted_data(&barrier(&mut every).await),
+            ["1", "2", "3", "4", "5", "6"]
+        );
+
+        // The window starts over with the next message.
+        publish(addr, with_password("secret"), "a", "7").await;
+        assert_eq!(sorted_data(&barrier(&mut coalesced).await), ["7"]);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn answers_a_request_with_its_reply() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut responder = subscribe(addr, "rpc").await;
+        let request = |query: &'static str| {
+            reqwest::Client::new()
+                .post(format!("http://{addr}/request{query}"))
+                .basic_auth("p", Some("secret"))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(json!({ "topic": "rpc", "data": "ping" }).to_string())
+                .send()
+        };
+
+        let response = tokio::spawn(request(""));
+        let received = next_json(&mut responder).await;
+        assert_eq!(received["data"], "ping");
+        let reply_to = received["reply_to"].as_str().unwrap();
+        assert!(reply_to.starts_with("_reply/"), "{reply_to}");
+        let correlation_id = received["correlation_id"].as_str().unwrap();
+        // Only the reply with the request's correlation ID answers it.
+        for (id, data) in [("other", "wrong"), (correlation_id, "pong")] {
+            publish_json(
+                addr,
+                json!({ "topic": reply_to, "data": data, "correlation_id": id }),
+            )
+            .await;
+        }
+        let response = response.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let reply = json_body(response).await;
+        assert_eq!(reply["data"], "pong");
+        assert_eq!(reply["topic"], reply_to);
+        assert_eq!(reply["correlation_id"], correlation_id);
+
+        // Nobody answers this one.
+        let response = request("?timeout=1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(json_body(response).await["code"], "NO_REPLY");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn closes_connections_sending_oversized_frames() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ws_max_message_bytes: Some(1024),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let oversized = json!({ "topic": "a".repeat(2048) });
+        let mut unsubscribed = connect(addr).await;
+        send_json(&mut unsubscribed, oversized.clone()).await;
+        // Also once subscribed.
+        let mut subscribed = subscribe(addr, "a").await;
+        send_json(&mut subscribed, oversized).await;
+
+        for socket in [&mut unsubscribed, &mut subscribed] {
+            let frame = closed(socket).await.expect("no close frame");
+            assert_eq!(u16::from(frame.code), 1009);
+            let reason: serde_json::Value = serde_json::from_str(&frame.reason).unwrap();
+            assert_eq!(reason["error"], "message too big");
+        }
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn picks_the_earlier_messages_sent_by_mode() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            history_size: Some(10),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        publish(addr, with_password("secret"), "a", "1").await;
+        publish_json(addr, json!({ "topic": "a", "data": "2", "retain": true })).await;
+        publish(addr, with_password("secret"), "a", "3").await;
+
+        let mut sockets = Vec::new();
+        for (mode, expected) in [
+            // The history already holds the retained message.
+            (None, vec!["1", "2", "3"]),
+            (Some("live"), vec![]),
+            (Some("replay"), vec!["1", "2", "3"]),
+            (Some("latest"), vec!["2"]),
+        ] {
+            let subscription = match mode {
+                Some(mode) => json!({ "topic": "a", "mode": mode }),
+                None => json!({ "topic": "a" }),
+            };
+            let (socket, received) = subscribe_with(addr, subscription).await;
+            let data: Vec<_> = received.iter().map(|frame| &frame["data"]).collect();
+            assert_eq!(data, expected, "{mode:?}");
+            sockets.push(socket);
+        }
+        // Live messages follow in every mode.
+        publish(addr, with_password("secret"), "a", "4").await;
+        for socket in &mut sockets {
+            assert_eq!(next_json(socket).await["data"], "4");
+        }
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn tells_a_lagging_subscriber_what_it_skipped() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            max_payload_bytes: Some(4 << 20),
+            broadcast_capacity: Some(2),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut socket = lagging_subscriber(addr, "a").await;
+
+        let received = barrier(&mut socket).await;
+        let notice = received
+            .iter()
+            .find(|frame| frame.get("lagged").is_some())
+            .expect("no lag notice");
+        assert!(notice["lagged"]["skipped"].as_u64() >= Some(1));
+        // Still subscribed afterwards.
+        publish(addr, with_password("secret"), "a", "after").await;
+        assert_eq!(next_json(&mut socket).await["data"], "after");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn sends_the_retained_value_to_late_subscribers() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let retain =
+            |topic: &str, data: &str| json!({ "topic": topic, "data": data, "retain": true });
+        publish_json(addr, retain("a", "1")).await;
+        publish_json(addr, retain("a", "2")).await;
+        // Neither replaces the retained value of `a`.
+        publish(addr, with_password("secret"), "a", "3").await;
+        publish_json(addr, retain("b", "4")).await;
+
+        let (_socket, received) = subscribe_with(addr, json!({ "topic": "a" })).await;
+        assert_eq!(sorted_data(&received), ["2"]);
+        assert_eq!(received[0]["publisher"], "p");
+
+        publish_json(addr, retain("a", "")).await;
+        let (_socket, received) = subscribe_with(addr, json!({ "topic": ["a", "b"] })).await;
+        assert_eq!(sorted_data(&received), ["4"]);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn streams_messages_as_server_sent_events() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut response = reqwest::get(format!("http://{addr}/sse?publisher=p&topic=a"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+
+        publish(addr, with_password("secret"), "a", "hello").await;
+        publish(addr, with_password("secret"), "b", "other topic").await;
+        publish(
+            addr,
+            |request| request.basic_auth("q", Some("secret")),
+            "a",
+            "other publisher",
+        )
+        .await;
+        publish(addr, with_password("secret"), "a", "bye").await;
+        let mut body = String::new();
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), response.chunk())
+                .await
+                .expect("no event in time")
+                .unwrap()
+                .expect("stream ended");
+            body.push_str(std::str::from_utf8(&chunk).unwrap());
+            while let Some(end) = body.find("\n\n") {
+                let event: String = body.drain(..end + 2).collect();
+                if let Some(data) = event.lines().find_map(|line| line.strip_prefix("data:")) {
+                    events.push(serde_json::from_str::<serde_json::Value>(data.trim()).unwrap());
+                }
+            }
+        }
+        assert_eq!(events[0]["publisher"], "p");
+        assert_eq!(events[0]["topic"], "a");
+        assert_eq!(events[0]["data"], "hello");
+        assert_eq!(events[1]["data"], "bye");
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn answers_a_long_poll_with_the_next_message() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let (addr, handle) = spawn_server(config).await.unwrap();
+        let mut poll = tokio::spawn(reqwest::get(format!(
+            "http://{addr}/poll?publisher=p&topic=a&timeout=5"
+        )));
+        // The poll may not be waiting yet, so this goes on until it returns.
+        let response = loop {
+            publish(addr, with_password("secret"), "b", "other topic").await;
+            publish(addr, with_password("secret"), "a", "hello").await;
+            if let Ok(response) = tokio::time::timeout(Duration::from_millis(50), &mut poll).await {
+                break response.unwrap().unwrap();
+            }
+        };
+        assert_eq!(response.status(), StatusCode::OK);
+        let msg = json_body(response).await;
+        assert_eq!(msg["publisher"], "p");
+        assert_eq!(msg["topic"], "a");
+        assert_eq!(msg["data"], "hello");
+
+        let response = reqwest::get(format!("http://{addr}/poll?topic=a&timeout=1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        handle.shutdown();
+    }
+
+    #[tokio::test]
+    async fn reports_health_and_readiness() {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        let state = Arc::new(SharedState::new(&config).unwrap());
+        let (addr, handle) = spawn_app(&config, state.clone()).unwrap();
+        let status = |path: &'static str| async move {
+            reqwest::get(format!("http://{addr}{path}"))
+                .await
+                .unwrap()
+                .status()
+        };
+
+        // Alive before it is ready.
+        assert_eq!(status("/health").await, StatusCode::OK);
+        assert_eq!(status("/ready").await, StatusCode::SERVICE_UNAVAILABLE);
+        mark_ready(state).await;
+        assert_eq!(status("/health").await, StatusCode::OK);
+        assert_eq!(status("/ready").await, StatusCode::OK);
+
+        handle.shutdown();
+    }
+}
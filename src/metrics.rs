@@ -0,0 +1,289 @@
+//! Counters and histograms served by `/metrics` in the Prometheus format.
+
+use dashmap::DashMap;
+use std::{
+    fmt::Write as _,
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::auth::AuthError;
+
+/// How often each websocket connection records the end-to-end latency of a
+/// message it sends, to keep the cost per message down.
+pub(crate) const LATENCY_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+/// Counters exported in the Prometheus text format at `/metrics`.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) published: AtomicU64,
+    pub(crate) active_subscribers: AtomicI64,
+    wrong_credentials: AtomicU64,
+    missing_credentials: AtomicU64,
+    auth_backend_errors: AtomicU64,
+    forbidden: AtomicU64,
+    pub(crate) lag_events: AtomicU64,
+    compressed_frames: AtomicU64,
+    /// Bytes the compressed frames are smaller than they would have been.
+    compression_saved_bytes: AtomicU64,
+    /// Published messages per topic, for at most `max_topics` topics.
+    topics: DashMap<String, AtomicU64>,
+    /// Published messages to topics beyond the first `max_topics`.
+    other_topics: AtomicU64,
+    max_topics: usize,
+    /// Time `/pub` takes to hand a message to the subscribers' channels.
+    pub(crate) publish_duration: Histogram,
+    /// Time from publishing to sending, sampled per connection.
+    pub(crate) delivery_latency: Histogram,
+    /// Websocket subscribers closed for shutdown.
+    pub(crate) drained_connections: AtomicU64,
+    /// Messages still queued for the subscribers closed for shutdown.
+    pub(crate) drain_dropped_messages: AtomicU64,
+    /// Messages not posted to a webhook since its queue was full.
+    pub(crate) webhook_dropped_messages: AtomicU64,
+}
+
+/// A Prometheus histogram over [`LATENCY_BUCKETS`].
+#[derive(Default)]
+pub(crate) struct Histogram {
+    /// Observations per bucket, not cumulative, with the last one for those
+    /// above every bound.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        metric_header(out, name, "histogram", help);
+        let mut count = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            count += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        count += self.buckets[LATENCY_BUCKETS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+impl Metrics {
+    /// Metrics counting messages per topic for at most `max_topics` topics.
+    pub(crate) fn new(max_topics: usize) -> Self {
+        Self {
+            max_topics,
+            ..Self::default()
+        }
+    }
+
+    /// Counts a frame of `original` bytes sent compressed to `compressed` bytes.
+    pub(crate) fn frame_compressed(&self, original: usize, compressed: usize) {
+        self.compressed_frames.fetch_add(1, Ordering::Relaxed);
+        let saved = original.saturating_sub(compressed) as u64;
+        self.compression_saved_bytes
+            .fetch_add(saved, Ordering::Relaxed);
+    }
+
+    /// Counts a message published to `topic`. Topics seen after the first
+    /// `max_topics` share one counter to keep the number of series bounded.
+    pub(crate) fn topic_published(&self, topic: &str) {
+        if let Some(counter) = self.topics.get(topic) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        } else if self.topics.len() < self.max_topics {
+            self.topics
+                .entry(topic.to_string())
+                .or_default()
+                .fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.other_topics.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a failed authentication attempt and hands the error back.
+    pub(crate) fn auth_failure(&self, error: AuthError) -> AuthError {
+        let counter = match error {
+            AuthError::WrongCredentials => &self.wrong_credentials,
+            AuthError::MissingCredentials => &self.missing_credentials,
+            AuthError::AuthBackendUnavailable => &self.auth_backend_errors,
+            AuthError::Forbidden => &self.forbidden,
+            AuthError::MissingTopic
+            | AuthError::RateLimited { .. }
+            | AuthError::TooManyConnections
+            | AuthError::TooManyConnectionsPerIp
+            | AuthError::UnsupportedSubprotocol
+            | AuthError::TooManyTopics
+            | AuthError::InvalidTopic
+            | AuthError::InvalidPayload
+            | AuthError::NoReply
+            | AuthError::StorageFailed => return error,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        error
+    }
+
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        metric_header(
+            &mut out,
+            "isimud_published_messages_total",
+            "counter",
+            "Total number of published messages.",
+        );
+        let _ = writeln!(
+            out,
+            "isimud_published_messages_total {}",
+            self.published.load(Ordering::Relaxed)
+        );
+        metric_header(
+            &mut out,
+            "isimud_active_subscribers",
+            "gauge",
+            "Number of connected websocket subscribers.",
+        );
+        let _ = writeln!(
+            out,
+            "isimud_active_subscribers {}",
+            self.active_subscribers.load(Ordering::Relaxed)
+        );
+        metric_header(
+            &mut out,
+            "isimud_auth_failures_total",
+            "counter",
+            "Total number of failed authentication attempts.",
+        );
+        for (reason, counter) in [
+            ("wrong_credentials", &self.wrong_credentials),
+            ("missing_credentials", &self.missing_credentials),
+            ("auth_backend_error", &self.auth_backend_errors),
+            ("forbidden", &self.forbidden),
+        ] {
+            let _ = writeln!(
+                out,
+                "isimud_auth_failures_total{{reason=\"{reason}\"}} {}",
+                counter.load(Ordering::Relaxed)
+            );
+        }
+        metric_header(
+            &mut out,
+            "isimud_lag_events_total",
+            "counter",
+            "Total number of times a subscriber lagged behind the broadcast channel.",
+        );
+        let _ = writeln!(
+            out,
+            "isimud_lag_events_total {}",
+            self.lag_events.load(Ordering::Relaxed)
+        );
+        metric_header(
+            &mut out,
+            "isimud_compressed_frames_total",
+            "counter",
+            "Total number of websocket frames sent compressed.",
+        );
+        let _ = writeln!(
+            out,
+            "isimud_compressed_frames_total {}",
+            self.compressed_frames.load(Ordering::Relaxed)
+        );
+        metric_header(
+            &mut out,
+            "isimud_compression_saved_bytes_total",
+            "counter",
+            "Total number of bytes saved by compressing websocket frames.",
+        );
+        let _ = writeln!(
+            out,
+            "isimud_compression_saved_bytes_total {}",
+            self.compression_saved_bytes.load(Ordering::Relaxed)
+        );
+        metric_header(
+            &mut out,
+            "isimud_messages_total",
+            "counter",
+            "Total number of published messages per topic.",
+        );
+        let mut topics: Vec<_> = self
+            .topics
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().load(Ordering::Relaxed)))
+            .collect();
+        topics.sort_unstable();
+        for (topic, count) in topics {
+            let topic = topic
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            let _ = writeln!(out, "isimud_messages_total{{topic=\"{topic}\"}} {count}");
+        }
+        let other = self.other_topics.load(Ordering::Relaxed);
+        if other > 0 {
+            let _ = writeln!(out, "isimud_messages_total{{topic=\"other\"}} {other}");
+        }
+        self.publish_duration.render(
+            &mut out,
+            "isimud_publish_duration_seconds",
+            "Time from receiving a publish on /pub to handing it to subscribers.",
+        );
+        self.delivery_latency.render(
+            &mut out,
+            "isimud_delivery_latency_seconds",
+            "Time from publishing a message to sending it to a websocket subscriber, sampled.",
+        );
+        metric_header(
+            &mut out,
+            "isimud_drained_connections_total",
+            "counter",
+            "Total number of websocket subscribers closed for shutdown.",
+        );
+        let _ = writeln!(
+            out,
+            "isimud_drained_connections_total {}",
+            self.drained_connections.load(Ordering::Relaxed)
+        );
+        metric_header(
+            &mut out,
+            "isimud_drain_dropped_messages_total",
+            "counter",
+            "Total number of messages left unsent to websocket subscribers closed for shutdown.",
+        );
+        let _ = writeln!(
+            out,
+            "isimud_drain_dropped_messages_total {}",
+            self.drain_dropped_messages.load(Ordering::Relaxed)
+        );
+        metric_header(
+            &mut out,
+            "isimud_webhook_dropped_messages_total",
+            "counter",
+            "Total number of messages not posted to a webhook since too many were waiting for it.",
+        );
+        let _ = writeln!(
+            out,
+            "isimud_webhook_dropped_messages_total {}",
+            self.webhook_dropped_messages.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+fn metric_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
@@ -0,0 +1,2077 @@
+//! The messages, subscriptions and channels of the broker, and the state they
+//! are shared through.
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use axum::{extract::ws::Message, http::HeaderMap};
+use dashmap::{mapref::entry::Entry, DashMap};
+use flate2::write::GzEncoder;
+use futures::{
+    future::{ready, Either},
+    FutureExt, StreamExt,
+};
+use ipnet::IpNet;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{
+    broadcast::{self, error::TryRecvError, Receiver, Sender},
+    watch, Semaphore,
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamMap,
+};
+use uuid::Uuid;
+
+use crate::{
+    auth::{
+        forwarded_hops, AuthCache, AuthError, Credentials, JwtVerifier, RateLimiter, AUTH_PROBE_TTL,
+    },
+    bridge::{MqttBridge, RedisRelay, WebhookQueue},
+    config::{Config, Homepage, SlowSubscriberPolicy},
+    journal::Journal,
+    metrics::Metrics,
+};
+
+/// Smallest frame, in bytes, that is compressed for subscribers asking for it.
+/// Smaller frames would barely shrink or even grow.
+pub(crate) const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Longest topic or topic pattern accepted, in bytes.
+const MAX_TOPIC_BYTES: usize = 256;
+
+/// A message as published over HTTP or a websocket.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PublisherMsg {
+    pub(crate) topic: String,
+    pub(crate) data: Payload,
+    /// Keeps this message as the last value of its topic for subscribers that
+    /// join later. An empty `data` clears the retained value instead.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) retain: bool,
+    /// Milliseconds after publishing during which the message may be delivered.
+    /// Expired messages are skipped, also in history and retained messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) ttl_ms: Option<u64>,
+    /// Partition key passed on to subscribers. Messages to one topic share a
+    /// channel and are delivered in publish order, so messages with the same
+    /// key and topic always reach a subscriber in the order they were published.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) key: Option<String>,
+    /// Topic a responder to this message should publish its reply to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reply_to: Option<String>,
+    /// Ties a reply to the request it answers, passed on to subscribers like
+    /// `reply_to`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) correlation_id: Option<String>,
+}
+
+impl PublisherMsg {
+    /// A text message to `topic`, for example to publish with
+    /// [`client::Publisher`](crate::client).
+    pub fn new(topic: impl Into<String>, data: impl Into<String>) -> Self {
+        Self {
+            topic: topic.into(),
+            data: Payload::Text(data.into().into()),
+            retain: false,
+            ttl_ms: None,
+            key: None,
+            reply_to: None,
+            correlation_id: None,
+        }
+    }
+
+    pub fn with_retain(mut self, retain: bool) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    pub fn with_ttl_ms(mut self, ttl_ms: u64) -> Self {
+        self.ttl_ms = Some(ttl_ms);
+        self
+    }
+
+    pub fn with_key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    pub fn with_reply_to(mut self, reply_to: impl Into<String>) -> Self {
+        self.reply_to = Some(reply_to.into());
+        self
+    }
+
+    pub fn with_correlation_id(mut self, correlation_id: impl Into<String>) -> Self {
+        self.correlation_id = Some(correlation_id.into());
+        self
+    }
+}
+
+/// Changes the text of messages after they pass authentication, the ACL and
+/// the rate limit, and before they reach anybody, e.g. to redact personal data
+/// or tag them with where they came from. Set with
+/// [`SharedState::with_transform`]; by default messages are delivered as
+/// published.
+///
+/// Only the data can change, since the topic has already been authorized by
+/// then. Binary messages and the parts of streamed publishes are not
+/// transformed, the latter since a transform could not make sense of them one
+/// at a time.
+pub trait MessageTransform: Send + Sync {
+    /// The text to deliver in place of `text`, which `publisher` published to
+    /// `topic`.
+    fn transform(&self, publisher: &str, topic: &str, text: Arc<str>) -> Arc<str> {
+        let _ = (publisher, topic);
+        text
+    }
+}
+
+/// Delivers messages as published.
+pub struct NoTransform;
+
+impl MessageTransform for NoTransform {}
+
+/// A published payload. JSON publishes always carry text, while `/pub/binary`
+/// and MessagePack `bin` data carry raw bytes that are delivered to websocket
+/// subscribers as binary frames. Both are reference counted so that fanning a
+/// message out does not copy it.
+#[derive(Debug, Clone)]
+pub(crate) enum Payload {
+    Text(Arc<str>),
+    Binary(Arc<[u8]>),
+}
+
+impl<'de> Deserialize<'de> for Payload {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct PayloadVisitor;
+
+        impl serde::de::Visitor<'_> for PayloadVisitor {
+            type Value = Payload;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a string or bytes")
+            }
+
+            fn visit_str<E>(self, text: &str) -> Result<Payload, E> {
+                Ok(Payload::Text(text.into()))
+            }
+
+            fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Payload, E> {
+                Ok(Payload::Binary(bytes.into()))
+            }
+        }
+
+        deserializer.deserialize_any(PayloadVisitor)
+    }
+}
+
+impl Serialize for Payload {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Self::Text(text) => serializer.serialize_str(text),
+            Self::Binary(bytes) => serializer.serialize_bytes(bytes),
+        }
+    }
+}
+
+impl Payload {
+    fn is_empty(&self) -> bool {
+        match self {
+            Self::Text(text) => text.is_empty(),
+            Self::Binary(bytes) => bytes.is_empty(),
+        }
+    }
+}
+
+/// The state shared by every handler: the channels messages are delivered on,
+/// the settings and the metrics.
+pub struct SharedState {
+    /// The broadcast channels subscriptions listen on, created by the first
+    /// subscriber of each.
+    pub(crate) channels: DashMap<Channel, Sender<PubSubMsg>>,
+    /// Gives subscriptions to one publisher channels of their own, see
+    /// [`Channel`].
+    shard_by_publisher: bool,
+    /// Carries every message, for subscribers with wildcard patterns.
+    wildcard_tx: Sender<PubSubMsg>,
+    transform: Box<dyn MessageTransform>,
+    /// The settings reloaded on `SIGHUP`, see [`SharedState::reload`].
+    pub(crate) settings: ArcSwap<Settings>,
+    /// Networks publishers may connect from, if restricted.
+    pub_allow_cidrs: Option<Vec<IpNet>>,
+    /// Networks of the proxies whose `Forwarded` and `X-Forwarded-For` headers
+    /// are believed, if `TRUST_PROXY` is enabled.
+    trusted_proxies: Option<Vec<IpNet>>,
+    pub(crate) auth_cache: AuthCache,
+    pub(crate) jwt: Option<JwtVerifier>,
+    pub(crate) ping_interval: Duration,
+    /// How long an SSE stream may go without events before a keepalive comment.
+    pub(crate) sse_keepalive: Duration,
+    pub(crate) ping_timeout: Duration,
+    pub(crate) subscribe_timeout: Duration,
+    pub(crate) max_conn_lifetime: Option<Duration>,
+    pub(crate) slow_subscriber_policy: SlowSubscriberPolicy,
+    /// Slots for concurrent websocket connections, if limited.
+    pub(crate) connection_slots: Option<Arc<Semaphore>>,
+    /// Concurrent websocket connections allowed from one IP, if limited.
+    max_conn_per_ip: Option<usize>,
+    /// Open websocket connections per client IP, if limited.
+    connections_per_ip: DashMap<IpAddr, usize>,
+    pub(crate) auth_url: Option<Url>,
+    /// Field of the `AUTH_URL` response body naming the subscriber, if any.
+    pub(crate) auth_identity_field: Option<String>,
+    pub(crate) client: Client,
+    pub(crate) broadcast_capacity: usize,
+    /// Largest publish, in bytes, accepted over HTTP.
+    pub(crate) max_payload_bytes: usize,
+    /// Largest message, in bytes, accepted from a websocket client.
+    pub(crate) ws_max_message_bytes: usize,
+    /// Largest part, in bytes, a streamed publish is delivered in.
+    pub(crate) stream_chunk_bytes: usize,
+    pub(crate) raw_delivery: bool,
+    /// The last retained message per `(publisher, topic)`.
+    pub(crate) retained: DashMap<(String, String), PubSubMsg>,
+    /// When each topic was last published to, listed by `/topics`.
+    active_topics: DashMap<String, Instant>,
+    history_size: usize,
+    history: Mutex<History>,
+    pub(crate) journal: Option<Journal>,
+    pub(crate) redis: Option<RedisRelay>,
+    mqtt: Option<MqttBridge>,
+    webhooks: Vec<WebhookQueue>,
+    pub(crate) admin_password: Option<String>,
+    /// Connected websocket subscribers, listed by `/admin/subscriptions`.
+    pub(crate) subscribers: DashMap<u64, Subscriber>,
+    pub(crate) next_subscriber_id: AtomicU64,
+    /// The subscriptions of every websocket, SSE and long-poll subscriber, for
+    /// [`SharedState::matched_subscribers`].
+    filters: DashMap<u64, Arc<Mutex<Subscriptions>>>,
+    /// Sessions of acknowledging subscribers, keyed by identity and session id.
+    pub(crate) sessions: DashMap<(Option<String>, String), Arc<Mutex<Session>>>,
+    /// Unacknowledged messages kept per session.
+    pub(crate) ack_buffer_size: usize,
+    pub(crate) reconnect_backoff: ReconnectBackoff,
+    /// Publisher and topic pairs a websocket connection may subscribe to.
+    pub(crate) max_topics_per_conn: usize,
+    pub(crate) metrics: Metrics,
+    pub(crate) ready: AtomicBool,
+    /// Becomes `true` once the server starts shutting down. The broadcast
+    /// channels live as long as the state, so subscribers learn about it here
+    /// rather than from their channels closing.
+    pub(crate) shutting_down: watch::Sender<bool>,
+    /// When `AUTH_URL` was last probed by `/ready` and whether it answered.
+    pub(crate) auth_probe: tokio::sync::Mutex<Option<(Instant, bool)>>,
+}
+
+/// Recent messages per topic, replayed to subscribers when they subscribe.
+#[derive(Default)]
+struct History {
+    next_seq: u64,
+    topics: HashMap<String, VecDeque<PubSubMsg>>,
+    /// Sequence number of the newest message per topic that is no longer
+    /// buffered, to tell resuming subscribers they missed something.
+    evicted: HashMap<String, u64>,
+    /// The oldest sequence number restored from the journal, as the messages
+    /// before it are gone.
+    first_seq: u64,
+}
+
+/// The settings that can change without a restart: who may publish, to what and
+/// how often, and what `/` shows.
+pub(crate) struct Settings {
+    pub(crate) credentials: Credentials,
+    /// Bearer tokens publishers may authenticate with instead of basic auth,
+    /// by publisher name.
+    pub(crate) publisher_tokens: HashMap<String, String>,
+    /// Topic patterns each publisher may publish to, if restricted.
+    acl: Option<HashMap<String, Vec<String>>>,
+    pub(crate) rate_limiter: Option<RateLimiter>,
+    pub(crate) homepage: Homepage,
+    /// Where `/` redirects to.
+    pub(crate) homepage_url: Url,
+}
+
+impl Settings {
+    fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let credentials = Credentials::from_config(config)?;
+        let rate_limiter = match config.pub_rate_per_sec {
+            Some(rate) => {
+                anyhow::ensure!(rate > 0.0, "PUB_RATE_PER_SEC must be greater than 0");
+                let burst = config.pub_burst.unwrap_or(rate.ceil());
+                anyhow::ensure!(burst >= 1.0, "PUB_BURST must be at least 1");
+                Some(RateLimiter::new(rate, burst))
+            }
+            None => None,
+        };
+        let acl = match (&config.acl, &config.acl_path) {
+            (Some(acl), _) => Some(acl.clone()),
+            (None, Some(path)) => {
+                let file = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read ACL from `{path}`"))?;
+                Some(
+                    serde_json::from_str(&file)
+                        .with_context(|| format!("failed to parse ACL in `{path}`"))?,
+                )
+            }
+            (None, None) => None,
+        };
+        let homepage_url = config
+            .homepage_url
+            .as_deref()
+            .unwrap_or("https://github.com/tropicbliss/isimud/")
+            .parse()
+            .context("invalid HOMEPAGE_URL")?;
+        Ok(Self {
+            credentials,
+            publisher_tokens: config.publisher_tokens.clone().unwrap_or_default(),
+            acl,
+            rate_limiter,
+            homepage: config.homepage.unwrap_or(Homepage::Redirect),
+            homepage_url,
+        })
+    }
+}
+
+impl SharedState {
+    /// How long a topic without retained messages is listed by `/topics`
+    /// after it was last published to.
+    pub(crate) const ACTIVE_TOPIC_TTL: Duration = Duration::from_secs(300);
+
+    /// The state for `config`, with the messages of the journal restored.
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        let settings = Settings::from_config(config)?;
+        let webhooks = match (&config.webhooks, &config.webhooks_path) {
+            (Some(webhooks), _) => webhooks.clone(),
+            (None, Some(path)) => {
+                let file = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read webhooks from `{path}`"))?;
+                serde_json::from_str(&file)
+                    .with_context(|| format!("failed to parse webhooks in `{path}`"))?
+            }
+            (None, None) => Vec::new(),
+        };
+        for webhook in &webhooks {
+            webhook
+                .url
+                .parse::<Url>()
+                .with_context(|| format!("invalid webhook URL `{}`", webhook.url))?;
+        }
+        let auth_url: Option<Url> = if let Some(url) = &config.auth_url {
+            Some(url.parse()?)
+        } else {
+            None
+        };
+        let auth_cache_ttl = config.auth_cache_ttl.unwrap_or(60);
+        let auth_cache = AuthCache::new(Duration::from_secs(auth_cache_ttl));
+        let jwt = JwtVerifier::from_config(config)?;
+        let ping_interval = config.ping_interval.unwrap_or(30);
+        let ping_timeout = config.ping_timeout.unwrap_or(10);
+        let subscribe_timeout = config.subscribe_timeout.unwrap_or(30);
+        let max_conn_lifetime = config.max_conn_lifetime.map(Duration::from_secs);
+        let max_conn_per_ip = config.max_conn_per_ip;
+        anyhow::ensure!(
+            max_conn_per_ip != Some(0),
+            "MAX_CONN_PER_IP must be greater than 0"
+        );
+        let auth_request_timeout = config.auth_request_timeout.unwrap_or(5);
+        anyhow::ensure!(
+            auth_request_timeout > 0,
+            "AUTH_REQUEST_TIMEOUT must be greater than 0"
+        );
+        // Webhooks and readiness probes set timeouts of their own.
+        let client = Client::builder()
+            .connect_timeout(Duration::from_secs(5))
+            .timeout(Duration::from_secs(auth_request_timeout))
+            .build()?;
+        let webhooks = webhooks
+            .into_iter()
+            .map(|webhook| WebhookQueue::spawn(client.clone(), webhook))
+            .collect();
+        let broadcast_capacity = config.broadcast_capacity.unwrap_or(16);
+        anyhow::ensure!(
+            broadcast_capacity > 0,
+            "BROADCAST_CAPACITY must be greater than 0"
+        );
+        let max_payload_bytes = config.max_payload_bytes.unwrap_or(1048576);
+        let stream_chunk_bytes = config.stream_chunk_bytes.unwrap_or(65536);
+        // Any character fits into a part.
+        anyhow::ensure!(
+            stream_chunk_bytes >= 4,
+            "STREAM_CHUNK_BYTES must be at least 4"
+        );
+        let raw_delivery = config.raw_delivery.unwrap_or(false);
+        let history_size = config.history_size.unwrap_or(0);
+        let ack_buffer_size = config.ack_buffer_size.unwrap_or(100);
+        anyhow::ensure!(
+            ack_buffer_size > 0,
+            "ACK_BUFFER_SIZE must be greater than 0"
+        );
+        let (wildcard_tx, _) = broadcast::channel(broadcast_capacity);
+        let (journal, restored) = match Journal::open(config)? {
+            Some((journal, next_seq, msgs)) => (Some(journal), Some((next_seq, msgs))),
+            None => (None, None),
+        };
+        let state = Self {
+            channels: DashMap::new(),
+            shard_by_publisher: config.shard_by_publisher.unwrap_or(false),
+            wildcard_tx,
+            transform: Box::new(NoTransform),
+            settings: ArcSwap::from_pointee(settings),
+            pub_allow_cidrs: config.pub_allow_cidrs.clone(),
+            trusted_proxies: config.trust_proxy.unwrap_or(false).then(|| {
+                config.trusted_proxy_cidrs.clone().unwrap_or_else(|| {
+                    vec![
+                        IpNet::from(IpAddr::from(Ipv4Addr::LOCALHOST)),
+                        IpNet::from(IpAddr::from(Ipv6Addr::LOCALHOST)),
+                    ]
+                })
+            }),
+            auth_cache,
+            jwt,
+            ping_interval: Duration::from_secs(ping_interval),
+            sse_keepalive: Duration::from_secs(config.sse_keepalive.unwrap_or(15)),
+            ping_timeout: Duration::from_secs(ping_timeout),
+            subscribe_timeout: Duration::from_secs(subscribe_timeout),
+            max_conn_lifetime,
+            slow_subscriber_policy: config
+                .slow_subscriber_policy
+                .unwrap_or(SlowSubscriberPolicy::Skip),
+            connection_slots: config
+                .max_connections
+                .map(|max_connections| Arc::new(Semaphore::new(max_connections))),
+            max_conn_per_ip,
+            connections_per_ip: DashMap::new(),
+            auth_url,
+            auth_identity_field: config.auth_identity_field.clone(),
+            client,
+            broadcast_capacity,
+            max_payload_bytes,
+            ws_max_message_bytes: config.ws_max_message_bytes.unwrap_or(max_payload_bytes),
+            stream_chunk_bytes,
+            raw_delivery,
+            retained: DashMap::new(),
+            active_topics: DashMap::new(),
+            history_size,
+            history: Mutex::default(),
+            journal,
+            redis: RedisRelay::from_config(config)?,
+            mqtt: MqttBridge::from_config(config)?,
+            webhooks,
+            admin_password: config.admin_password.clone(),
+            subscribers: DashMap::new(),
+            next_subscriber_id: AtomicU64::new(0),
+            filters: DashMap::new(),
+            sessions: DashMap::new(),
+            ack_buffer_size,
+            reconnect_backoff: ReconnectBackoff::new(),
+            max_topics_per_conn: config.max_topics_per_conn.unwrap_or(64),
+            metrics: Metrics::new(config.metrics_max_topics.unwrap_or(100)),
+            ready: AtomicBool::new(false),
+            shutting_down: watch::channel(false).0,
+            auth_probe: tokio::sync::Mutex::new(None),
+        };
+        if let Some((next_seq, msgs)) = restored {
+            state.restore(next_seq, msgs);
+        }
+        Ok(state)
+    }
+
+    /// Puts the messages read back from the journal into the history and the
+    /// retained messages as if they had just been delivered, and carries on
+    /// with their sequence numbers.
+    fn restore(&self, next_seq: u64, msgs: Vec<PubSubMsg>) {
+        let mut history = self.history.lock().unwrap();
+        history.next_seq = next_seq;
+        history.first_seq = msgs.first().map_or(next_seq, |msg| msg.seq);
+        tracing::debug!(
+            restored = msgs.len(),
+            next_seq,
+            "restored messages from journal"
+        );
+        for msg in msgs {
+            if msg.msg.retain {
+                self.retain(&msg);
+            }
+            self.record(&mut history, &msg);
+        }
+    }
+
+    /// The address of the client behind `peer`. If `peer` is a trusted proxy,
+    /// this is the rightmost address in its forwarding header that is not a
+    /// trusted proxy itself, since anything to the left of it may be forged.
+    /// The port stays that of `peer`, as proxies rarely forward it.
+    pub(crate) fn client_addr(&self, peer: SocketAddr, headers: &HeaderMap) -> SocketAddr {
+        let is_trusted = |addr| self.is_trusted_proxy(addr);
+        if !is_trusted(peer.ip()) {
+            return peer;
+        }
+        let mut client = peer.ip();
+        for hop in forwarded_hops(headers).into_iter().rev() {
+            // An unreadable hop ends the chain at the proxy that added it.
+            let Some(hop) = hop else {
+                break;
+            };
+            client = hop;
+            if !is_trusted(hop) {
+                break;
+            }
+        }
+        SocketAddr::new(client, peer.port())
+    }
+
+    /// Whether `addr` is one of the proxies trusted by `TRUST_PROXY`.
+    pub(crate) fn is_trusted_proxy(&self, addr: IpAddr) -> bool {
+        let Some(trusted_proxies) = &self.trusted_proxies else {
+            return false;
+        };
+        let addr = addr.to_canonical();
+        trusted_proxies.iter().any(|cidr| cidr.contains(&addr))
+    }
+
+    /// Checks that publishing from `addr` is allowed by `PUB_ALLOW_CIDRS`.
+    pub(crate) fn authorize_source(&self, addr: IpAddr) -> Result<(), AuthError> {
+        let Some(cidrs) = &self.pub_allow_cidrs else {
+            return Ok(());
+        };
+        // IPv4 clients of a listener on `::` show up as IPv4-mapped IPv6 addresses.
+        let addr = addr.to_canonical();
+        if cidrs.iter().any(|cidr| cidr.contains(&addr)) {
+            Ok(())
+        } else {
+            Err(self.metrics.auth_failure(AuthError::Forbidden))
+        }
+    }
+
+    /// Replaces the settings with those of `config`, or keeps them if `config` is
+    /// invalid. Connections and publishes already under way carry on with the
+    /// settings they started with.
+    pub(crate) fn reload(&self, config: &Config) -> anyhow::Result<()> {
+        self.settings
+            .store(Arc::new(Settings::from_config(config)?));
+        Ok(())
+    }
+
+    /// Checks that `publisher` may publish to `topic` according to `ACL_PATH`.
+    pub(crate) fn authorize_topic(&self, publisher: &str, topic: &str) -> Result<(), AuthError> {
+        let settings = self.settings.load();
+        let Some(acl) = &settings.acl else {
+            return Ok(());
+        };
+        let allowed = acl
+            .get(publisher)
+            .into_iter()
+            .flatten()
+            .any(|pattern| topic_matches(pattern, topic));
+        if allowed {
+            Ok(())
+        } else {
+            Err(self.metrics.auth_failure(AuthError::Forbidden))
+        }
+    }
+
+    pub(crate) fn check_rate_limit(&self, publisher: &str) -> Result<(), AuthError> {
+        match &self.settings.load().rate_limiter {
+            Some(rate_limiter) => rate_limiter
+                .acquire(publisher)
+                .map_err(|retry_after| AuthError::RateLimited { retry_after }),
+            None => Ok(()),
+        }
+    }
+
+    /// Has `transform` change every message before it is delivered.
+    pub fn with_transform(mut self, transform: impl MessageTransform + 'static) -> Self {
+        self.transform = Box::new(transform);
+        self
+    }
+
+    /// Transforms `msg`, then delivers it to local subscribers, relays it to
+    /// the other instances sharing `REDIS_URL`, mirrors it to `MQTT_BROKER_URL`
+    /// and posts it to matching webhooks, if configured. Returns the number of
+    /// local subscribers the message was handed to.
+    pub(crate) async fn publish(self: &Arc<Self>, mut msg: PubSubMsg) -> Result<usize, AuthError> {
+        if let Payload::Text(text) = &msg.msg.data {
+            let text = self
+                .transform
+                .transform(&msg.name, &msg.msg.topic, text.clone());
+            msg.msg.data = Payload::Text(text);
+        }
+        // Delivering comes first, so that a message the journal failed to
+        // store goes nowhere.
+        let delivered_to = self.deliver(msg.clone()).await?;
+        self.post_to_webhooks(&msg);
+        if let Some(redis) = &self.redis {
+            redis.relay(&msg);
+        }
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.mirror(&msg);
+        }
+        Ok(delivered_to)
+    }
+
+    /// Queues the envelope of a text message for every matching webhook,
+    /// dropping it for those whose queue is full.
+    fn post_to_webhooks(&self, msg: &PubSubMsg) {
+        let webhooks: Vec<_> = self
+            .webhooks
+            .iter()
+            .filter(|queue| queue.webhook.matches(msg))
+            .collect();
+        if webhooks.is_empty() {
+            return;
+        }
+        let Some(delivered) = msg.to_delivered() else {
+            return;
+        };
+        let body: Arc<str> = serde_json::to_string(&delivered)
+            .expect("envelope is always serializable")
+            .into();
+        for queue in webhooks {
+            if !queue.post(body.clone()) {
+                tracing::warn!(
+                    url = %queue.webhook.url,
+                    event = "webhook_dropped",
+                    "webhook queue full, dropping message",
+                );
+                self.metrics
+                    .webhook_dropped_messages
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Numbers `msg` and hands it to local subscribers. With `DB_PATH`, this is
+    /// done by the journal's writer once the message is stored, and the message
+    /// is not delivered if that fails. Either way, delivery happens in the order
+    /// of the calls, even if the returned future is dropped without waiting.
+    /// Resolves to the number of subscribers the message was handed to, before
+    /// their subscriptions are matched against it.
+    pub(crate) fn deliver(
+        self: &Arc<Self>,
+        mut msg: PubSubMsg,
+    ) -> impl Future<Output = Result<usize, AuthError>> {
+        let Some(journal) = &self.journal else {
+            let mut history = self.history.lock().unwrap();
+            msg.seq = history.next_seq;
+            return Either::Left(ready(Ok(self.deliver_locked(&mut history, msg))));
+        };
+        let delivered = journal.deliver(self, msg);
+        Either::Right(async { delivered.await.unwrap_or(Err(AuthError::StorageFailed)) })
+    }
+
+    /// Delivers `msg`, which already has its sequence number, as the journal's
+    /// writer does once it is stored.
+    pub(crate) fn deliver_numbered(&self, msg: PubSubMsg) -> usize {
+        let mut history = self.history.lock().unwrap();
+        self.deliver_locked(&mut history, msg)
+    }
+
+    /// Records `msg` in the history buffer and broadcasts it. Both happen under
+    /// the history lock so that [`SharedState::subscribe`] never sees a message
+    /// twice or misses one during the handoff from history to live delivery.
+    ///
+    /// Parts of a publish streamed through `/pub/stream` are only broadcast.
+    /// Unlike other messages, they are not kept in history, journaled, relayed,
+    /// mirrored or posted to webhooks, since a part is of no use without the
+    /// others. The stream is counted as one published message.
+    fn deliver_locked(&self, history: &mut History, msg: PubSubMsg) -> usize {
+        history.next_seq = msg.seq + 1;
+        if let Some(stream) = &msg.stream {
+            if stream.part == Part::Begin {
+                self.count_published(&msg.msg.topic);
+            }
+            return self.broadcast(&msg);
+        }
+        if msg.msg.retain {
+            self.retain(&msg);
+        }
+        self.record(history, &msg);
+        let delivered_to = self.broadcast(&msg);
+        tracing::debug!(
+            publisher = %msg.name,
+            topic = %msg.msg.topic,
+            event = "publish",
+            delivered_to,
+            "published message"
+        );
+        self.count_published(&msg.msg.topic);
+        delivered_to
+    }
+
+    /// Sends `msg` on every channel carrying it and returns the number of
+    /// receivers it was handed to.
+    fn broadcast(&self, msg: &PubSubMsg) -> usize {
+        let mut delivered_to = 0;
+        for channel in self.channels_carrying(msg) {
+            if let Some(tx) = self.channels.get(&channel) {
+                delivered_to += tx.send(msg.clone()).unwrap_or(0);
+            }
+        }
+        delivered_to + self.wildcard_tx.send(msg.clone()).unwrap_or(0)
+    }
+
+    fn count_published(&self, topic: &str) {
+        self.metrics.published.fetch_add(1, Ordering::Relaxed);
+        self.metrics.topic_published(topic);
+        match self.active_topics.get_mut(topic) {
+            Some(mut published_at) => *published_at = Instant::now(),
+            None => {
+                self.active_topics.insert(topic.to_string(), Instant::now());
+            }
+        }
+    }
+
+    /// Adds `msg` to the history buffer of its topic, if history is kept.
+    fn record(&self, history: &mut History, msg: &PubSubMsg) {
+        if self.history_size > 0 {
+            let buffer = history.topics.entry(msg.msg.topic.clone()).or_default();
+            let evicted = if buffer.len() == self.history_size {
+                buffer.pop_front().map(|evicted| evicted.seq)
+            } else {
+                None
+            };
+            buffer.push_back(msg.clone());
+            if let Some(seq) = evicted {
+                history.evicted.insert(msg.msg.topic.clone(), seq);
+            }
+        } else {
+            history.evicted.insert(msg.msg.topic.clone(), msg.seq);
+        }
+    }
+
+    /// The channels besides the one carrying every message that `msg` is sent
+    /// on, if anybody listens on them.
+    fn channels_carrying(&self, msg: &PubSubMsg) -> Vec<Channel> {
+        let mut channels = vec![Channel::Topic(None, msg.msg.topic.clone())];
+        if self.shard_by_publisher {
+            channels.push(Channel::Topic(
+                Some(msg.name.clone()),
+                msg.msg.topic.clone(),
+            ));
+            channels.push(Channel::Publisher(msg.name.clone()));
+        }
+        channels
+    }
+
+    /// The number of subscribers [`SharedState::deliver`] would hand `msg` to,
+    /// without publishing it.
+    pub(crate) fn receiver_count(&self, msg: &PubSubMsg) -> usize {
+        let channels: usize = self
+            .channels_carrying(msg)
+            .iter()
+            .filter_map(|channel| self.channels.get(channel))
+            .map(|tx| tx.receiver_count())
+            .sum();
+        channels + self.wildcard_tx.receiver_count()
+    }
+
+    /// The topics starting with `prefix` that have a retained message or were
+    /// published to within [`SharedState::ACTIVE_TOPIC_TTL`], in order.
+    pub(crate) fn active_topics(&self, prefix: &str) -> BTreeSet<String> {
+        let retained = self
+            .retained
+            .iter()
+            .filter(|entry| !entry.value().is_expired())
+            .map(|entry| entry.key().1.clone());
+        let published = self
+            .active_topics
+            .iter()
+            .filter(|entry| entry.value().elapsed() < Self::ACTIVE_TOPIC_TTL)
+            .map(|entry| entry.key().clone());
+        retained
+            .chain(published)
+            .filter(|topic| topic.starts_with(prefix))
+            .collect()
+    }
+
+    /// Forgets the topics that are no longer listed as active.
+    pub(crate) fn prune_active_topics(&self) {
+        self.active_topics
+            .retain(|_, published_at| published_at.elapsed() < Self::ACTIVE_TOPIC_TTL);
+    }
+
+    /// Counts a websocket connection from `ip` against `MAX_CONN_PER_IP` for as
+    /// long as the returned slot lives, if connections per IP are limited.
+    pub(crate) fn acquire_ip_slot(
+        self: &Arc<Self>,
+        ip: IpAddr,
+    ) -> Result<Option<IpSlot>, AuthError> {
+        let Some(max_conn_per_ip) = self.max_conn_per_ip else {
+            return Ok(None);
+        };
+        let mut connections = self.connections_per_ip.entry(ip).or_insert(0);
+        if *connections >= max_conn_per_ip {
+            return Err(AuthError::TooManyConnectionsPerIp);
+        }
+        *connections += 1;
+        Ok(Some(IpSlot {
+            state: self.clone(),
+            ip,
+        }))
+    }
+
+    /// Counts `subscriptions` in [`SharedState::matched_subscribers`] for as
+    /// long as the returned registration lives.
+    pub(crate) fn register_filters(
+        self: &Arc<Self>,
+        subscriptions: Arc<Mutex<Subscriptions>>,
+    ) -> FilterRegistration {
+        let id = self.next_subscriber_id.fetch_add(1, Ordering::Relaxed);
+        self.filters.insert(id, subscriptions);
+        FilterRegistration {
+            state: self.clone(),
+            id,
+        }
+    }
+
+    /// The number of subscribers whose subscriptions match `msg` by publisher,
+    /// topic and filter, unlike [`SharedState::receiver_count`]. This goes
+    /// through every subscriber, so it is only done where asked for.
+    pub(crate) fn matched_subscribers(&self, msg: &PubSubMsg) -> usize {
+        self.filters
+            .iter()
+            .filter(|subscriptions| subscriptions.lock().unwrap().matches(msg))
+            .count()
+    }
+
+    /// Starts receiving every message for a connection that has yet to
+    /// subscribe, and returns the sequence number of the last message delivered
+    /// before, if any. Every later message reaches the receiver.
+    pub(crate) fn watch(&self) -> (Receiver<PubSubMsg>, Option<u64>) {
+        let history = self.history.lock().unwrap();
+        (
+            self.wildcard_tx.subscribe(),
+            history.next_seq.checked_sub(1),
+        )
+    }
+
+    /// Attaches broadcast receivers and returns them together with the earlier
+    /// messages matching `subscriptions`, oldest first: the buffered history, if
+    /// `with_history`, and those `pending` received since the connection opened. Publishing holds the
+    /// history lock, so nothing slips in between them and the new receivers.
+    ///
+    /// A subscriber resuming after `since_seq` only gets the later messages, and
+    /// the returned flag tells whether some of them may be gone already.
+    pub(crate) fn subscribe(
+        &self,
+        subscriptions: &Subscriptions,
+        mut pending: Receiver<PubSubMsg>,
+        since_seq: Option<u64>,
+        with_history: bool,
+    ) -> (Receivers, Vec<PubSubMsg>, bool) {
+        let history = self.history.lock().unwrap();
+        let receivers = self.receivers(subscriptions);
+        let mut replay: Vec<_> = if with_history {
+            history.topics.values().flatten().cloned().collect()
+        } else {
+            Vec::new()
+        };
+        // A sequence number this instance has not handed out yet comes from
+        // before a restart, as do those older than what the journal kept.
+        let mut gap = since_seq.is_some_and(|since_seq| {
+            since_seq >= history.next_seq
+                || since_seq + 1 < history.first_seq
+                || history.evicted.iter().any(|(topic, &evicted)| {
+                    evicted > since_seq && subscriptions.may_match_topic(topic)
+                })
+        });
+        loop {
+            match pending.try_recv() {
+                Ok(msg) => replay.push(msg),
+                Err(TryRecvError::Lagged(skipped)) => {
+                    tracing::warn!(
+                        event = "lag",
+                        skipped,
+                        "client lagged behind before subscribing"
+                    );
+                    self.metrics.lag_events.fetch_add(1, Ordering::Relaxed);
+                    gap |= since_seq.is_some();
+                }
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            }
+        }
+        replay.retain(|msg| {
+            subscriptions.matches(msg)
+                && !msg.is_expired()
+                && since_seq.is_none_or(|since_seq| msg.seq > since_seq)
+        });
+        replay.sort_by_key(|msg| msg.seq);
+        replay.dedup_by_key(|msg| msg.seq);
+        (receivers, replay, gap)
+    }
+
+    /// Creates a receiver for each channel carrying messages `subscriptions`
+    /// may match.
+    pub(crate) fn receivers(&self, subscriptions: &Subscriptions) -> Receivers {
+        subscriptions
+            .channels(self.shard_by_publisher)
+            .into_iter()
+            .map(|channel| {
+                let receiver = match &channel {
+                    Channel::All => self.wildcard_tx.subscribe(),
+                    _ => self
+                        .channels
+                        .entry(channel.clone())
+                        .or_insert_with(|| broadcast::channel(self.broadcast_capacity).0)
+                        .subscribe(),
+                };
+                (channel, receiver)
+            })
+            .collect()
+    }
+
+    /// Whether the auth service at `AUTH_URL`, if any, answers. Concurrent
+    /// callers wait for a single probe, whose outcome is reused for
+    /// [`AUTH_PROBE_TTL`].
+    pub(crate) async fn auth_reachable(&self) -> bool {
+        let Some(auth_url) = &self.auth_url else {
+            return true;
+        };
+        let mut probe = self.auth_probe.lock().await;
+        if let Some((probed_at, reachable)) = *probe {
+            if probed_at.elapsed() < AUTH_PROBE_TTL {
+                return reachable;
+            }
+        }
+        let reachable = match self
+            .client
+            .get(auth_url.as_str())
+            .timeout(Duration::from_secs(2))
+            .send()
+            .await
+        {
+            Ok(_) => true,
+            Err(error) => {
+                tracing::warn!("auth service at {auth_url} is unreachable: {error}");
+                false
+            }
+        };
+        *probe = Some((Instant::now(), reachable));
+        reachable
+    }
+
+    /// Joins the session `id` of `identity`, creating it if it does not exist.
+    pub(crate) fn attach_session(
+        &self,
+        identity: Option<String>,
+        id: String,
+    ) -> Arc<Mutex<Session>> {
+        let session = self
+            .sessions
+            .entry((identity, id))
+            .or_insert_with(|| Arc::new(Mutex::new(Session::new())))
+            .clone();
+        session.lock().unwrap().connections += 1;
+        session
+    }
+
+    /// Leaves a session, which is forgotten right away if every message sent
+    /// in it was acknowledged, and after [`Session::TTL`] otherwise.
+    pub(crate) fn detach_session(
+        &self,
+        identity: Option<String>,
+        id: String,
+        session: &Mutex<Session>,
+    ) {
+        let mut session = session.lock().unwrap();
+        session.connections -= 1;
+        session.last_seen = Instant::now();
+        if session.connections == 0 && session.outstanding.is_empty() {
+            drop(session);
+            self.sessions.remove_if(&(identity, id), |_, session| {
+                session.lock().unwrap().is_idle()
+            });
+        }
+    }
+
+    fn retain(&self, msg: &PubSubMsg) {
+        let key = (msg.name.clone(), msg.msg.topic.clone());
+        if msg.msg.data.is_empty() {
+            self.retained.remove(&key);
+        } else {
+            self.retained.insert(key, msg.clone());
+        }
+    }
+
+    pub(crate) fn retained_matching(&self, subscriptions: &Subscriptions) -> Vec<PubSubMsg> {
+        self.retained
+            .iter()
+            .filter(|entry| subscriptions.matches(entry.value()) && !entry.value().is_expired())
+            .map(|entry| entry.value().clone())
+            .collect()
+    }
+}
+
+/// A subscription to topics, as sent by websocket subscribers.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SubscriberMsg {
+    /// Matches every publisher when absent or `*`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) publisher: Option<String>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub(crate) topic: Vec<String>,
+    /// Turns on acknowledgements when given in the first subscription of a
+    /// websocket connection. Unacknowledged messages of an earlier connection
+    /// with the same session are sent again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) session: Option<String>,
+    /// Only delivers messages whose data matches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) filter: Option<Filter>,
+    /// Compresses large frames when given in the first subscription of a
+    /// websocket connection.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) compress: bool,
+    /// Resumes after this sequence number when given in the first subscription
+    /// of a websocket connection: only later messages are replayed from
+    /// history, and every message carries its sequence number.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) since_seq: Option<u64>,
+    /// Sends only the latest message per publisher and topic out of those
+    /// arriving within this many milliseconds, when given in the first
+    /// subscription of a websocket connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) coalesce_ms: Option<u64>,
+    /// What the first subscription of a websocket connection receives before
+    /// live messages: both history and retained messages if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mode: Option<Mode>,
+}
+
+impl SubscriberMsg {
+    /// Subscribes to `topic` from every publisher, for example with
+    /// [`client::Subscriber`](crate::client). Sessions, filters and compression
+    /// are left out, since the client does not handle them.
+    pub fn new(topic: impl Into<String>) -> Self {
+        Self {
+            publisher: None,
+            topic: vec![topic.into()],
+            session: None,
+            filter: None,
+            compress: false,
+            since_seq: None,
+            coalesce_ms: None,
+            mode: None,
+        }
+    }
+
+    /// Also subscribes to `topic`.
+    pub fn with_topic(mut self, topic: impl Into<String>) -> Self {
+        self.topic.push(topic.into());
+        self
+    }
+
+    pub fn with_publisher(mut self, publisher: impl Into<String>) -> Self {
+        self.publisher = Some(publisher.into());
+        self
+    }
+
+    pub fn with_since_seq(mut self, since_seq: u64) -> Self {
+        self.since_seq = Some(since_seq);
+        self
+    }
+
+    pub fn with_coalesce_ms(mut self, coalesce_ms: u64) -> Self {
+        self.coalesce_ms = Some(coalesce_ms);
+        self
+    }
+
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+}
+
+/// What a new subscriber receives before live messages.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Only messages published from now on.
+    Live,
+    /// The history, then live messages.
+    Replay,
+    /// The retained messages, then live messages.
+    Latest,
+}
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(topic) => vec![topic],
+        OneOrMany::Many(topics) => topics,
+    })
+}
+
+/// Control messages a subscriber may send at any point during the lifetime of
+/// its connection. A bare [`SubscriberMsg`] without an `action` is treated as a
+/// subscribe for backward compatibility.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum ClientMsg {
+    Control(ControlMsg),
+    Subscribe(SubscriberMsg),
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub(crate) enum ControlMsg {
+    Subscribe(SubscriberMsg),
+    Unsubscribe(SubscriberMsg),
+    /// Removes every subscription, leaving the connection open and idle until
+    /// the next subscribe.
+    UnsubscribeAll,
+    Authenticate {
+        username: String,
+        password: String,
+    },
+    Publish(PublisherMsg),
+    Ack {
+        seq: u64,
+    },
+    /// Asks for a `barrier_ack` with the same `id` once everything published
+    /// before it has been forwarded.
+    Barrier {
+        id: serde_json::Value,
+    },
+}
+
+impl SubscriberMsg {
+    fn into_pairs(self) -> impl Iterator<Item = (Option<String>, String)> {
+        let publisher = self.publisher.filter(|publisher| publisher != "*");
+        self.topic
+            .into_iter()
+            .map(move |topic| (publisher.clone(), topic))
+    }
+}
+
+/// What a subscriber was authorized as: its identity, from the JWT `sub` claim
+/// or the `AUTH_URL` response, and the topic patterns it may subscribe to, taken
+/// from the `topics` claim of its JWT. `None` allows every topic.
+#[derive(Clone, Default)]
+pub(crate) struct Scope {
+    pub(crate) identity: Option<String>,
+    pub(crate) topics: Option<Vec<String>>,
+}
+
+impl Scope {
+    pub(crate) fn allows(&self, pattern: &str) -> bool {
+        match &self.topics {
+            Some(scope) => scope.iter().any(|allowed| pattern_covers(allowed, pattern)),
+            None => true,
+        }
+    }
+}
+
+/// Messages sent to an acknowledging subscriber that it has not acknowledged
+/// yet, kept across reconnects so that they can be sent again.
+pub(crate) struct Session {
+    pub(crate) outstanding: BTreeMap<u64, PubSubMsg>,
+    pub(crate) connections: usize,
+    pub(crate) last_seen: Instant,
+}
+
+impl Session {
+    /// How long a session with unacknowledged messages outlives its last
+    /// connection.
+    pub(crate) const TTL: Duration = Duration::from_secs(300);
+
+    fn new() -> Self {
+        Self {
+            outstanding: BTreeMap::new(),
+            connections: 0,
+            last_seen: Instant::now(),
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.connections == 0 && self.outstanding.is_empty()
+    }
+}
+
+/// How long websocket clients the server disconnects are told to wait before
+/// reconnecting, doubling for each disconnect of the same IP in a row.
+pub(crate) struct ReconnectBackoff {
+    strikes: DashMap<IpAddr, Strikes>,
+}
+
+struct Strikes {
+    count: u32,
+    last: Instant,
+}
+
+impl ReconnectBackoff {
+    const BASE: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(60);
+    /// How long an IP has to go without being disconnected to start over.
+    const RESET_AFTER: Duration = Duration::from_secs(300);
+
+    fn new() -> Self {
+        Self {
+            strikes: DashMap::new(),
+        }
+    }
+
+    /// Counts a disconnect of `ip` and returns the delay to suggest to it,
+    /// somewhere between half of its current backoff and all of it so that
+    /// clients disconnected together do not all come back together.
+    pub(crate) fn retry_after(&self, ip: IpAddr) -> Duration {
+        let now = Instant::now();
+        let mut strikes = self.strikes.entry(ip).or_insert(Strikes {
+            count: 0,
+            last: now,
+        });
+        if now.duration_since(strikes.last) >= Self::RESET_AFTER {
+            strikes.count = 0;
+        }
+        strikes.count = strikes.count.saturating_add(1);
+        strikes.last = now;
+        let backoff = Self::BASE
+            .saturating_mul(1 << (strikes.count - 1).min(16))
+            .min(Self::MAX);
+        let half = backoff.as_millis() as u64 / 2;
+        // The random bits of a v4 UUID are as good a source as any for jitter.
+        let jitter = Uuid::new_v4().as_u128() as u64 % (half + 1);
+        backoff - Duration::from_millis(jitter)
+    }
+
+    /// Forgets the IPs that would start over anyway.
+    pub(crate) fn prune(&self) {
+        self.strikes
+            .retain(|_, strikes| strikes.last.elapsed() < Self::RESET_AFTER);
+    }
+}
+
+pub(crate) fn gzip(text: &str) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder
+        .write_all(text.as_bytes())
+        .and_then(|()| encoder.finish())
+        .expect("writing to a Vec cannot fail")
+}
+
+/// A connected websocket subscriber, as listed by `/admin/subscriptions`.
+pub(crate) struct Subscriber {
+    pub(crate) conn_id: Uuid,
+    pub(crate) addr: SocketAddr,
+    pub(crate) user_agent: String,
+    pub(crate) identity: Option<String>,
+    pub(crate) subscriptions: Arc<Mutex<Subscriptions>>,
+}
+
+/// A websocket connection counted by [`SharedState::acquire_ip_slot`] until
+/// dropped.
+pub(crate) struct IpSlot {
+    state: Arc<SharedState>,
+    ip: IpAddr,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        if let Entry::Occupied(mut connections) = self.state.connections_per_ip.entry(self.ip) {
+            *connections.get_mut() -= 1;
+            if *connections.get() == 0 {
+                connections.remove();
+            }
+        }
+    }
+}
+
+/// Keeps the subscriptions given to [`SharedState::register_filters`] counted
+/// until dropped.
+pub(crate) struct FilterRegistration {
+    pub(crate) state: Arc<SharedState>,
+    id: u64,
+}
+
+impl Drop for FilterRegistration {
+    fn drop(&mut self) {
+        self.state.filters.remove(&self.id);
+    }
+}
+
+/// The set of `(publisher, topic pattern)` pairs a connection is subscribed to,
+/// where a publisher of `None` matches every publisher, each with an optional
+/// filter on the message data.
+pub(crate) struct Subscriptions {
+    pub(crate) entries: HashMap<(Option<String>, String), Option<Filter>>,
+    scope: Scope,
+}
+
+impl Subscriptions {
+    pub(crate) fn new(scope: Scope) -> Self {
+        Self {
+            entries: HashMap::new(),
+            scope,
+        }
+    }
+
+    /// Applies a control message and returns the topics that were not subscribed
+    /// to because the subscriber's scope does not cover them.
+    pub(crate) fn apply(&mut self, msg: ClientMsg) -> Vec<String> {
+        let mut rejected = Vec::new();
+        match msg {
+            ClientMsg::Subscribe(mut sub) | ClientMsg::Control(ControlMsg::Subscribe(mut sub)) => {
+                let filter = sub.filter.take();
+                for (publisher, topic) in sub.into_pairs() {
+                    if self.scope.allows(&topic) {
+                        self.entries.insert((publisher, topic), filter.clone());
+                    } else {
+                        rejected.push(topic);
+                    }
+                }
+            }
+            ClientMsg::Control(ControlMsg::Unsubscribe(sub)) => {
+                for pair in sub.into_pairs() {
+                    self.entries.remove(&pair);
+                }
+            }
+            ClientMsg::Control(ControlMsg::UnsubscribeAll) => self.entries.clear(),
+            ClientMsg::Control(
+                ControlMsg::Authenticate { .. }
+                | ControlMsg::Publish(_)
+                | ControlMsg::Ack { .. }
+                | ControlMsg::Barrier { .. },
+            ) => {}
+        }
+        rejected
+    }
+
+    /// Leaves out the topics of a subscribe that would take these subscriptions
+    /// beyond `max` publisher and topic pairs, and returns them.
+    pub(crate) fn limit(&self, msg: &mut ClientMsg, max: usize) -> Vec<String> {
+        let (ClientMsg::Subscribe(sub) | ClientMsg::Control(ControlMsg::Subscribe(sub))) = msg
+        else {
+            return Vec::new();
+        };
+        let publisher = sub.publisher.clone().filter(|publisher| publisher != "*");
+        let mut added = HashSet::new();
+        let mut dropped = Vec::new();
+        sub.topic.retain(|topic| {
+            let pair = (publisher.clone(), topic.clone());
+            if self.entries.contains_key(&pair) || added.contains(&pair) {
+                true
+            } else if self.entries.len() + added.len() < max {
+                added.insert(pair);
+                true
+            } else {
+                dropped.push(topic.clone());
+                false
+            }
+        });
+        dropped
+    }
+
+    /// The channels carrying every message these subscriptions may match. A
+    /// wildcard pattern needs the channel carrying every message, which makes
+    /// the per-topic channels redundant. With `by_publisher`, subscriptions to
+    /// one publisher use that publisher's channels instead.
+    ///
+    /// No message is carried by two of the returned channels, so that it is
+    /// not delivered twice. Where that cannot be helped otherwise, the channel
+    /// carrying every message is used alone.
+    fn channels(&self, by_publisher: bool) -> HashSet<Channel> {
+        let mut topics = HashSet::new();
+        let mut publishers = HashSet::new();
+        let mut publisher_topics = HashSet::new();
+        for (publisher, pattern) in self.entries.keys() {
+            let wildcard = pattern.split('/').any(|level| level == "+" || level == "#");
+            match publisher.as_ref().filter(|_| by_publisher) {
+                Some(publisher) if wildcard => {
+                    publishers.insert(publisher.clone());
+                }
+                Some(publisher) if !pattern.is_empty() => {
+                    publisher_topics.insert((publisher.clone(), pattern.clone()));
+                }
+                None if wildcard => return HashSet::from([Channel::All]),
+                None if !pattern.is_empty() => {
+                    topics.insert(pattern.clone());
+                }
+                Some(_) | None => {}
+            }
+        }
+        // A publisher's channel also carries its messages to topics every
+        // publisher is subscribed to.
+        if !publishers.is_empty() && !topics.is_empty() {
+            return HashSet::from([Channel::All]);
+        }
+        publisher_topics.retain(|(publisher, topic)| {
+            !publishers.contains(publisher) && !topics.contains(topic)
+        });
+        let topics = topics.into_iter().map(|topic| Channel::Topic(None, topic));
+        let publishers = publishers.into_iter().map(Channel::Publisher);
+        let publisher_topics = publisher_topics
+            .into_iter()
+            .map(|(publisher, topic)| Channel::Topic(Some(publisher), topic));
+        topics.chain(publishers).chain(publisher_topics).collect()
+    }
+
+    /// Whether messages to `topic` could match, from some publisher and with
+    /// some data.
+    fn may_match_topic(&self, topic: &str) -> bool {
+        self.entries
+            .keys()
+            .any(|(_, pattern)| topic_matches(pattern, topic))
+    }
+
+    pub(crate) fn matches(&self, data: &PubSubMsg) -> bool {
+        self.entries.iter().any(|((publisher, pattern), filter)| {
+            let publisher_matches = match publisher {
+                Some(publisher) => publisher == &data.name,
+                None => true,
+            };
+            // The parts of a streamed publish are delivered all or none, so
+            // filters, which only see one part, do not apply to them.
+            publisher_matches
+                && topic_matches(pattern, &data.msg.topic)
+                && filter
+                    .as_ref()
+                    .is_none_or(|filter| data.stream.is_some() || filter.matches(&data.msg.data))
+        })
+    }
+}
+
+/// A condition on the data of text messages, for subscribers that only want
+/// some of the messages on a topic. Anything more involved than this is left
+/// to the client.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged, deny_unknown_fields)]
+pub(crate) enum Filter {
+    /// The data contains this substring.
+    Contains { contains: String },
+    /// The data is JSON whose value at the dot-separated `field` path, e.g.
+    /// `reading.unit` or `readings.0`, equals `equals`.
+    Equals {
+        field: String,
+        equals: serde_json::Value,
+    },
+}
+
+impl Filter {
+    fn matches(&self, data: &Payload) -> bool {
+        let Payload::Text(text) = data else {
+            return false;
+        };
+        match self {
+            Filter::Contains { contains } => text.contains(contains.as_str()),
+            Filter::Equals { field, equals } => {
+                let path = format!("/{}", field.replace('.', "/"));
+                serde_json::from_str::<serde_json::Value>(text)
+                    .is_ok_and(|value| value.pointer(&path) == Some(equals))
+            }
+        }
+    }
+}
+
+/// A broadcast channel messages are delivered on.
+///
+/// Publishers share every channel unless `SHARD_BY_PUBLISHER` is enabled, in
+/// which case subscriptions to one publisher listen on channels carrying only
+/// that publisher's messages, so that a burst from another publisher cannot
+/// make them lag. Subscriptions to every publisher still share channels, since
+/// they receive every burst anyway.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub(crate) enum Channel {
+    /// Messages to one topic, from one publisher if given.
+    Topic(Option<String>, String),
+    /// Every message of one publisher.
+    Publisher(String),
+    /// Every message.
+    All,
+}
+
+/// Receivers for the broadcast channels a subscriber listens on.
+type Receivers = Vec<(Channel, Receiver<PubSubMsg>)>;
+
+type FeedItem = (Channel, Result<PubSubMsg, BroadcastStreamRecvError>);
+
+/// The messages of the channels a subscriber listens on, as they arrive.
+#[derive(Default)]
+pub(crate) struct Feed {
+    streams: StreamMap<Channel, BroadcastStream<PubSubMsg>>,
+    /// Messages that were waiting in the receivers of channels the feed
+    /// stopped listening on, which come before the others.
+    carried: VecDeque<FeedItem>,
+}
+
+impl Feed {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.streams.is_empty() && self.carried.is_empty()
+    }
+}
+
+impl futures::Stream for Feed {
+    type Item = FeedItem;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<FeedItem>> {
+        if let Some(item) = self.carried.pop_front() {
+            return std::task::Poll::Ready(Some(item));
+        }
+        self.streams.poll_next_unpin(cx)
+    }
+}
+
+/// Makes `feed` listen on exactly the channels of `receivers`, keeping the
+/// receivers it already has so that no buffered message is lost.
+///
+/// When the feed switches between channels that carry the same messages, such
+/// as from per-topic channels to [`Channel::All`], the messages waiting in the
+/// channels it leaves are kept too. They may also have reached the new
+/// receivers, so the messages waiting in both are merged by `seq` to deliver
+/// each of them once and in order. The old receivers are emptied and dropped
+/// before the new ones, so that whatever the new receivers get afterwards was
+/// never in the old ones.
+pub(crate) fn update_feed(feed: &mut Feed, receivers: Receivers) {
+    let channels: HashSet<_> = receivers.iter().map(|(channel, _)| channel).collect();
+    let stale: Vec<_> = feed
+        .streams
+        .keys()
+        .filter(|channel| !channels.contains(channel))
+        .cloned()
+        .collect();
+    let mut carried = Vec::new();
+    for channel in &stale {
+        if let Some(mut stream) = feed.streams.remove(channel) {
+            while let Some(Some(data)) = stream.next().now_or_never() {
+                carried.push((channel.clone(), data));
+            }
+        }
+    }
+    for (channel, mut receiver) in receivers {
+        if feed.streams.contains_key(&channel) {
+            continue;
+        }
+        if !stale.is_empty() {
+            loop {
+                let data = match receiver.try_recv() {
+                    Ok(data) => Ok(data),
+                    Err(TryRecvError::Lagged(skipped)) => {
+                        Err(BroadcastStreamRecvError::Lagged(skipped))
+                    }
+                    Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+                };
+                carried.push((channel.clone(), data));
+            }
+        }
+        feed.streams.insert(channel, BroadcastStream::new(receiver));
+    }
+    // Lag notices first, since the messages they stand for came earlier.
+    carried.sort_by_key(|(_, data)| data.as_ref().ok().map(|data| data.seq));
+    carried.dedup_by(|(_, data), (_, earlier)| match (data, earlier) {
+        (Ok(data), Ok(earlier)) => data.seq == earlier.seq,
+        _ => false,
+    });
+    feed.carried.extend(carried);
+}
+
+/// Matches a topic against an MQTT-style pattern, where `+` matches exactly one
+/// level and a trailing `#` matches any number of remaining levels.
+pub(crate) fn topic_matches(pattern: &str, topic: &str) -> bool {
+    if pattern.is_empty() {
+        return false;
+    }
+    let mut pattern = pattern.split('/');
+    let mut topic = topic.split('/');
+    loop {
+        match (pattern.next(), topic.next()) {
+            (Some("#"), _) => return pattern.next().is_none(),
+            (Some("+"), Some(_)) => {}
+            (Some(p), Some(t)) if p == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Normalizes a topic or topic pattern by trimming it and collapsing repeated
+/// `/`, and rejects it if it is then empty, longer than [`MAX_TOPIC_BYTES`] or
+/// contains control characters.
+pub(crate) fn validate_topic(topic: &str) -> Result<String, AuthError> {
+    let mut normalized = String::with_capacity(topic.len());
+    for c in topic.trim().chars() {
+        if c.is_control() {
+            return Err(AuthError::InvalidTopic);
+        }
+        if !(c == '/' && normalized.ends_with('/')) {
+            normalized.push(c);
+        }
+    }
+    if normalized.is_empty() {
+        return Err(AuthError::MissingTopic);
+    }
+    if normalized.len() > MAX_TOPIC_BYTES {
+        return Err(AuthError::InvalidTopic);
+    }
+    Ok(normalized)
+}
+
+/// Whether every topic matched by `pattern` is also matched by `allowed`.
+fn pattern_covers(allowed: &str, pattern: &str) -> bool {
+    if allowed.is_empty() || pattern.is_empty() {
+        return false;
+    }
+    let mut allowed = allowed.split('/');
+    let mut pattern = pattern.split('/');
+    loop {
+        match (allowed.next(), pattern.next()) {
+            (Some("#"), _) => return allowed.next().is_none(),
+            (_, Some("#")) => return false,
+            (Some("+"), Some(_)) => {}
+            (Some(a), Some(p)) if a == p && p != "+" => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// A gzip compressed frame and the size of the frame it replaces.
+type CompressedFrame = (Vec<u8>, usize);
+
+#[derive(Clone)]
+pub(crate) struct PubSubMsg {
+    pub(crate) name: String,
+    pub(crate) msg: PublisherMsg,
+    /// Unix time in milliseconds at which the message was published.
+    pub(crate) timestamp: u64,
+    /// Position in the order messages were delivered on this instance, assigned
+    /// by [`SharedState::deliver`].
+    pub(crate) seq: u64,
+    /// Where the message belongs if it is part of a streamed publish.
+    pub(crate) stream: Option<StreamPart>,
+    /// The gzip compressed envelope and raw frames with their uncompressed
+    /// size, if they are large enough to be worth it, shared by every
+    /// subscriber asking for compression.
+    pub(crate) compressed: Arc<[OnceLock<Option<CompressedFrame>>; 2]>,
+}
+
+impl PubSubMsg {
+    pub(crate) fn new(msg: PublisherMsg, name: String) -> Self {
+        Self {
+            name,
+            msg,
+            timestamp: unix_millis(),
+            seq: 0,
+            stream: None,
+            compressed: Arc::default(),
+        }
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        self.msg
+            .ttl_ms
+            .is_some_and(|ttl_ms| unix_millis() >= self.timestamp.saturating_add(ttl_ms))
+    }
+
+    pub(crate) fn to_message(&self, raw: bool) -> Message {
+        match &self.msg.data {
+            Payload::Binary(bytes) => Message::Binary(bytes.to_vec()),
+            Payload::Text(text) if raw => Message::Text(text.to_string()),
+            Payload::Text(_) => Message::Text(
+                serde_json::to_string(&self.to_delivered())
+                    .expect("envelope is always serializable"),
+            ),
+        }
+    }
+
+    /// Like [`PubSubMsg::to_message`], but as a compressed binary frame for
+    /// large text messages. The compression happens once per message.
+    pub(crate) fn to_compressed_message(&self, raw: bool, metrics: &Metrics) -> Message {
+        let compressed =
+            self.compressed[usize::from(raw)].get_or_init(|| match self.to_message(raw) {
+                Message::Text(text) if text.len() >= COMPRESSION_THRESHOLD => {
+                    Some((gzip(&text), text.len()))
+                }
+                _ => None,
+            });
+        match compressed {
+            Some((bytes, original)) => {
+                metrics.frame_compressed(*original, bytes.len());
+                Message::Binary(bytes.clone())
+            }
+            None => self.to_message(raw),
+        }
+    }
+
+    /// The envelope as MessagePack, with `seq` to acknowledge if tracked.
+    pub(crate) fn to_msgpack(&self, seq: Option<u64>) -> Vec<u8> {
+        rmp_serde::to_vec_named(&PackedMsg {
+            publisher: &self.name,
+            topic: &self.msg.topic,
+            data: &self.msg.data,
+            timestamp: self.timestamp,
+            key: self.msg.key.as_deref(),
+            reply_to: self.msg.reply_to.as_deref(),
+            correlation_id: self.msg.correlation_id.as_deref(),
+            seq,
+            stream: self.stream.as_ref(),
+        })
+        .expect("envelope is always serializable")
+    }
+
+    /// Builds the JSON envelope for this message, which only exists for text
+    /// payloads.
+    pub(crate) fn to_delivered(&self) -> Option<DeliveredMsg> {
+        match &self.msg.data {
+            Payload::Text(text) => Some(DeliveredMsg {
+                publisher: self.name.clone(),
+                topic: self.msg.topic.clone(),
+                data: text.clone(),
+                timestamp: self.timestamp,
+                key: self.msg.key.clone(),
+                reply_to: self.msg.reply_to.clone(),
+                correlation_id: self.msg.correlation_id.clone(),
+                seq: None,
+                stream: self.stream.clone(),
+            }),
+            Payload::Binary(_) => None,
+        }
+    }
+}
+
+/// The envelope subscribers receive for every message unless `RAW_DELIVERY` is
+/// enabled.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct DeliveredMsg {
+    publisher: String,
+    topic: String,
+    data: Arc<str>,
+    timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply_to: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<String>,
+    /// Sequence number to acknowledge, only sent to subscribers with a session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) seq: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stream: Option<StreamPart>,
+}
+
+/// Where a message belongs in a publish streamed through `/pub/stream`. A
+/// stream reaches subscribers as a `begin` message, `continue` messages with
+/// the data in order and an `end` message, or an `abort` message if the
+/// publisher failed to send all of it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct StreamPart {
+    pub(crate) id: Uuid,
+    pub(crate) part: Part,
+    /// Position of the message in the stream, counting from 0 for `begin`.
+    pub(crate) index: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Part {
+    Begin,
+    Continue,
+    End,
+    Abort,
+}
+
+/// The envelope subscribers of the `isimud.v1.msgpack` subprotocol receive,
+/// with binary data as MessagePack `bin`.
+#[derive(Serialize)]
+struct PackedMsg<'a> {
+    publisher: &'a str,
+    topic: &'a str,
+    data: &'a Payload,
+    timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<&'a StreamPart>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> Arc<SharedState> {
+        let config = Config {
+            password: Some(String::from("secret")),
+            ..Config::default()
+        };
+        Arc::new(SharedState::new(&config).unwrap())
+    }
+
+    async fn publish(state: &Arc<SharedState>, topic: &str, data: &str) -> usize {
+        let msg = PubSubMsg::new(PublisherMsg::new(topic, data), String::from("p"));
+        state.publish(msg).await.unwrap()
+    }
+
+    fn subscribe(topic: &str) -> ClientMsg {
+        ClientMsg::Subscribe(SubscriberMsg::new(topic))
+    }
+
+    fn unsubscribe(topic: &str) -> ClientMsg {
+        ClientMsg::Control(ControlMsg::Unsubscribe(SubscriberMsg::new(topic)))
+    }
+
+    /// The `topic:data` of every message waiting in `feed` that `subscriptions`
+    /// match.
+    fn drain(feed: &mut Feed, subscriptions: &Subscriptions) -> Vec<String> {
+        let mut received = Vec::new();
+        while let Some(Some((_, data))) = feed.next().now_or_never() {
+            let data = data.unwrap();
+            if let (true, Payload::Text(text)) = (subscriptions.matches(&data), &data.msg.data) {
+                received.push(format!("{}:{text}", data.msg.topic));
+            }
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn fans_out_to_wildcard_and_topic_subscribers() {
+        let state = state();
+        let mut feeds: Vec<_> = [["#", "#"], ["a", "b"], ["b", "b"]]
+            .into_iter()
+            .map(|topics| {
+                let mut subscriptions = Subscriptions::new(Scope::default());
+                for topic in topics {
+                    subscriptions.apply(subscribe(topic));
+                }
+                let mut feed = Feed::new();
+                update_feed(&mut feed, state.receivers(&subscriptions));
+                (feed, subscriptions)
+            })
+            .collect();
+
+        assert_eq!(publish(&state, "a", "1").await, 2);
+        assert_eq!(publish(&state, "b", "2").await, 3);
+        assert_eq!(publish(&state, "c/d", "3").await, 1);
+        let mut received: Vec<_> = feeds
+            .iter_mut()
+            .map(|(feed, subscriptions)| drain(feed, subscriptions))
+            .collect();
+        // Only messages of one channel arrive in order.
+        received[1].sort();
+        assert_eq!(received[0], ["a:1", "b:2", "c/d:3"]);
+        assert_eq!(received[1], ["a:1", "b:2"]);
+        assert_eq!(received[2], ["b:2"]);
+    }
+
+    #[tokio::test]
+    async fn shares_one_payload_between_subscribers() {
+        let state = state();
+        let mut feeds: Vec<_> = ["a", "a", "#"]
+            .into_iter()
+            .map(|topic| {
+                let mut subscriptions = Subscriptions::new(Scope::default());
+                subscriptions.apply(subscribe(topic));
+                let mut feed = Feed::new();
+                update_feed(&mut feed, state.receivers(&subscriptions));
+                feed
+            })
+            .collect();
+
+        assert_eq!(publish(&state, "a", "hello").await, 3);
+        let payloads: Vec<_> = feeds
+            .iter_mut()
+            .map(|feed| {
+                let (_, data) = feed.next().now_or_never().unwrap().unwrap();
+                match data.unwrap().msg.data {
+                    Payload::Text(text) => text,
+                    Payload::Binary(_) => panic!("published text"),
+                }
+            })
+            .collect();
+        assert_eq!(&*payloads[0], "hello");
+        for payload in &payloads[1..] {
+            assert!(Arc::ptr_eq(&payloads[0], payload));
+        }
+    }
+
+    #[tokio::test]
+    async fn carries_waiting_messages_over_to_the_wildcard_channel() {
+        let state = state();
+        let mut subscriptions = Subscriptions::new(Scope::default());
+        subscriptions.apply(subscribe("a"));
+        let mut feed = Feed::new();
+        update_feed(&mut feed, state.receivers(&subscriptions));
+        publish(&state, "a", "1").await;
+
+        subscriptions.apply(subscribe("b/#"));
+        let receivers = state.receivers(&subscriptions);
+        // Reaches both the old and the new receivers.
+        publish(&state, "a", "2").await;
+        update_feed(&mut feed, receivers);
+        publish(&state, "b/c", "3").await;
+        assert_eq!(drain(&mut feed, &subscriptions), ["a:1", "a:2", "b/c:3"]);
+    }
+
+    #[tokio::test]
+    async fn carries_waiting_messages_over_from_the_wildcard_channel() {
+        let state = state();
+        let mut subscriptions = Subscriptions::new(Scope::default());
+        subscriptions.apply(subscribe("#"));
+        let mut feed = Feed::new();
+        update_feed(&mut feed, state.receivers(&subscriptions));
+        publish(&state, "a", "1").await;
+
+        subscriptions.apply(unsubscribe("#"));
+        subscriptions.apply(subscribe("a"));
+        subscriptions.apply(subscribe("b"));
+        let receivers = state.receivers(&subscriptions);
+        publish(&state, "b", "2").await;
+        update_feed(&mut feed, receivers);
+        publish(&state, "a", "3").await;
+        assert_eq!(drain(&mut feed, &subscriptions), ["a:1", "b:2", "a:3"]);
+    }
+
+    #[test]
+    fn matches_topics_against_patterns() {
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(!topic_matches("a/+/c", "a/b/d"));
+        assert!(!topic_matches("a/+/c", "a/b/x/c"));
+        assert!(!topic_matches("a/+/c", "a/c"));
+
+        assert!(topic_matches("a/#", "a/b"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(topic_matches("a/#", "a"));
+        assert!(!topic_matches("a/#", "b/c"));
+
+        assert!(topic_matches("#", "a"));
+        assert!(topic_matches("#", "a/b/c"));
+
+        assert!(topic_matches("a/b", "a/b"));
+        assert!(!topic_matches("a/b", "a/c"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+
+        assert!(!topic_matches("", ""));
+        assert!(!topic_matches("", "a"));
+    }
+
+    #[test]
+    fn validates_topics() {
+        assert_eq!(validate_topic("sensors/room1").unwrap(), "sensors/room1");
+        assert_eq!(validate_topic("  sensors/a  ").unwrap(), "sensors/a");
+    }
+
+    #[test]
+    fn rejects_empty_topics() {
+        assert!(matches!(validate_topic(""), Err(AuthError::MissingTopic)));
+        assert!(matches!(
+            validate_topic(" \t "),
+            Err(AuthError::MissingTopic)
+        ));
+    }
+
+    #[test]
+    fn rejects_overlong_topics() {
+        let longest = "a".repeat(MAX_TOPIC_BYTES);
+        assert_eq!(validate_topic(&longest).unwrap(), longest);
+        assert!(matches!(
+            validate_topic(&format!("{longest}a")),
+            Err(AuthError::InvalidTopic)
+        ));
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        assert!(matches!(
+            validate_topic("a\nb"),
+            Err(AuthError::InvalidTopic)
+        ));
+        assert!(matches!(
+            validate_topic("a/\0"),
+            Err(AuthError::InvalidTopic)
+        ));
+        assert!(matches!(
+            validate_topic("\u{7f}"),
+            Err(AuthError::InvalidTopic)
+        ));
+    }
+
+    #[test]
+    fn collapses_repeated_slashes() {
+        assert_eq!(validate_topic("a//b///c").unwrap(), "a/b/c");
+        assert_eq!(validate_topic("//a").unwrap(), "/a");
+    }
+
+    #[test]
+    fn round_trips_the_delivered_envelope() {
+        let msg = PublisherMsg::new("sensors/a", "21.5")
+            .with_key("room1")
+            .with_reply_to("replies")
+            .with_correlation_id("42");
+        let mut msg = PubSubMsg::new(msg, String::from("p"));
+        msg.timestamp = 1000;
+        let mut delivered = msg.to_delivered().unwrap();
+        delivered.seq = Some(7);
+
+        let json = serde_json::to_value(&delivered).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "publisher": "p",
+                "topic": "sensors/a",
+                "data": "21.5",
+                "timestamp": 1000,
+                "key": "room1",
+                "reply_to": "replies",
+                "correlation_id": "42",
+                "seq": 7,
+            })
+        );
+        let parsed: DeliveredMsg = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), json);
+
+        // The optional fields are left out rather than sent as `null`.
+        let plain = PubSubMsg::new(PublisherMsg::new("a", "b"), String::from("p"));
+        let json = serde_json::to_value(plain.to_delivered().unwrap()).unwrap();
+        let mut fields: Vec<_> = json.as_object().unwrap().keys().collect();
+        fields.sort();
+        assert_eq!(fields, ["data", "publisher", "timestamp", "topic"]);
+        let parsed: DeliveredMsg = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), json);
+    }
+}
@@ -0,0 +1,123 @@
+//! Runs the `isimud` binary for the tests in this directory.
+
+#![allow(dead_code)]
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, TcpListener},
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+pub type Socket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// An `isimud` server on a free port of localhost, killed when dropped.
+pub struct Server {
+    child: Child,
+    pub addr: SocketAddr,
+}
+
+impl Server {
+    /// Starts the server with `envs` and nothing else from the environment,
+    /// and waits until it accepts connections.
+    pub async fn start(envs: &[(&str, &str)]) -> Self {
+        // Another process could take the port between dropping the listener and
+        // the server binding it, which is unlikely enough for tests.
+        let addr = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_isimud"))
+            .env_clear()
+            .env("IP", "127.0.0.1")
+            .env("PORT", addr.port().to_string())
+            .envs(envs.iter().copied())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut server = Self { child, addr };
+        for _ in 0..250 {
+            if tokio::net::TcpStream::connect(addr).await.is_ok() {
+                return server;
+            }
+            if let Some(status) = server.child.try_wait().unwrap() {
+                panic!("server exited with {status}");
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("server did not start listening in time");
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.addr)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A websocket subscriber sending `subscription` to `server`, given a moment
+/// to be put in place.
+pub async fn subscribe(server: &Server, subscription: serde_json::Value) -> Socket {
+    let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/sub", server.addr))
+        .await
+        .unwrap();
+    socket
+        .send(Message::Text(subscription.to_string()))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    socket
+}
+
+/// The next frame of `socket`.
+pub async fn next_frame<S>(socket: &mut WebSocketStream<S>) -> Message
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .expect("no frame in time")
+        .expect("socket closed")
+        .expect("socket failed")
+}
+
+/// The next text frame of `socket` carrying a message, as JSON.
+pub async fn next_message<S>(socket: &mut WebSocketStream<S>) -> serde_json::Value
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        if let Message::Text(text) = next_frame(socket).await {
+            let frame: serde_json::Value = serde_json::from_str(&text).unwrap();
+            if frame.get("data").is_some() {
+                return frame;
+            }
+        }
+    }
+}
+
+/// Publishes `msg` to `server` as `publisher`.
+pub async fn publish(
+    server: &Server,
+    publisher: &str,
+    password: &str,
+    msg: serde_json::Value,
+) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(server.url("/pub"))
+        .basic_auth(publisher, Some(password))
+        .header("content-type", "application/json")
+        .body(msg.to_string())
+        .send()
+        .await
+        .unwrap()
+}
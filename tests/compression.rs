@@ -0,0 +1,38 @@
+mod common;
+
+use std::io::Read;
+
+use common::Server;
+use serde_json::json;
+use tokio_tungstenite::tungstenite::Message;
+
+#[tokio::test]
+async fn compresses_large_frames_for_subscribers_asking_for_it() {
+    let server = Server::start(&[("PASSWORD", "secret")]).await;
+    let mut socket = common::subscribe(&server, json!({ "topic": "big", "compress": true })).await;
+
+    let data = "a".repeat(1024);
+    let response = common::publish(
+        &server,
+        "p",
+        "secret",
+        json!({ "topic": "big", "data": data }),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+
+    let compressed = loop {
+        if let Message::Binary(compressed) = common::next_frame(&mut socket).await {
+            break compressed;
+        }
+    };
+    assert!(compressed.len() < data.len());
+    let mut envelope = String::new();
+    flate2::read::GzDecoder::new(compressed.as_slice())
+        .read_to_string(&mut envelope)
+        .unwrap();
+    let msg: serde_json::Value = serde_json::from_str(&envelope).unwrap();
+    assert_eq!(msg["publisher"], "p");
+    assert_eq!(msg["topic"], "big");
+    assert_eq!(msg["data"], data);
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use std::time::Duration;
+
+use common::Server;
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Reads an MQTT control packet, returning its first byte and the rest of it.
+async fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let header = stream.read_u8().await.unwrap();
+    let mut length = 0;
+    for shift in (0..28).step_by(7) {
+        let byte = stream.read_u8().await.unwrap();
+        length |= usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await.unwrap();
+    (header, body)
+}
+
+#[tokio::test]
+async fn mirrors_publishes_to_an_mqtt_broker() {
+    let broker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let broker_url = format!("mqtt://{}", broker.local_addr().unwrap());
+    let server = Server::start(&[("PASSWORD", "secret"), ("MQTT_BROKER_URL", &broker_url)]).await;
+    let (mut stream, _) = tokio::time::timeout(Duration::from_secs(5), broker.accept())
+        .await
+        .expect("no connection to the broker in time")
+        .unwrap();
+    let (header, _) = read_packet(&mut stream).await;
+    assert_eq!(header >> 4, 1, "expected CONNECT");
+    stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+
+    for (data, retain) in [("first", false), ("second", true)] {
+        let msg = json!({ "topic": "sensors/a", "data": data, "retain": retain });
+        let response = common::publish(&server, "p", "secret", msg).await;
+        assert_eq!(response.status(), 200);
+
+        let (header, body) = loop {
+            let (header, body) =
+                tokio::time::timeout(Duration::from_secs(5), read_packet(&mut stream))
+                    .await
+                    .expect("no PUBLISH in time");
+            // Skips keepalive pings.
+            if header >> 4 == 3 {
+                break (header, body);
+            }
+        };
+        let topic_len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+        let topic = std::str::from_utf8(&body[2..2 + topic_len]).unwrap();
+        assert_eq!(topic, "p/sensors/a");
+        assert_eq!(header & 0x01 == 1, retain);
+        assert_eq!((header >> 1) & 0x03, 1, "expected QoS 1");
+        let packet_id = &body[2 + topic_len..4 + topic_len];
+        assert_eq!(&body[4 + topic_len..], data.as_bytes());
+        stream
+            .write_all(&[0x40, 0x02, packet_id[0], packet_id[1]])
+            .await
+            .unwrap();
+    }
+}
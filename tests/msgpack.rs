@@ -0,0 +1,45 @@
+mod common;
+
+use common::Server;
+use futures::SinkExt;
+use serde_json::json;
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http::HeaderValue, Message};
+
+#[tokio::test]
+async fn round_trips_messagepack() {
+    let server = Server::start(&[("PASSWORD", "secret")]).await;
+    let mut request = format!("ws://{}/sub", server.addr)
+        .into_client_request()
+        .unwrap();
+    request.headers_mut().insert(
+        "sec-websocket-protocol",
+        HeaderValue::from_static("isimud.v1.msgpack"),
+    );
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+    socket
+        .send(Message::Text(json!({ "topic": "greetings" }).to_string()))
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    let body = rmp_serde::to_vec_named(&json!({ "topic": "greetings", "data": "hello" })).unwrap();
+    let response = reqwest::Client::new()
+        .post(server.url("/pub"))
+        .basic_auth("p", Some("secret"))
+        .header("content-type", "application/msgpack")
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+
+    let bytes = loop {
+        if let Message::Binary(bytes) = common::next_frame(&mut socket).await {
+            break bytes;
+        }
+    };
+    let msg: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+    assert_eq!(msg["publisher"], "p");
+    assert_eq!(msg["topic"], "greetings");
+    assert_eq!(msg["data"], "hello");
+}
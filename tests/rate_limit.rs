@@ -0,0 +1,32 @@
+mod common;
+
+use common::Server;
+use serde_json::json;
+
+#[tokio::test]
+async fn rejects_bursts_past_the_rate_limit() {
+    let server = Server::start(&[
+        ("PASSWORD", "secret"),
+        ("PUB_RATE_PER_SEC", "0.1"),
+        ("PUB_BURST", "3"),
+    ])
+    .await;
+    let msg = json!({ "topic": "greetings", "data": "hello" });
+
+    for _ in 0..3 {
+        let response = common::publish(&server, "p", "secret", msg.clone()).await;
+        assert_eq!(response.status(), 200);
+    }
+    let response = common::publish(&server, "p", "secret", msg.clone()).await;
+    assert_eq!(response.status(), 429);
+    let retry_after: u64 = response.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=10).contains(&retry_after), "{retry_after}");
+
+    // Other publishers have buckets of their own.
+    let response = common::publish(&server, "q", "secret", msg).await;
+    assert_eq!(response.status(), 200);
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use std::time::Duration;
+
+use common::Server;
+use futures::StreamExt;
+use serde_json::json;
+
+/// Two instances sharing a Redis channel, at `REDIS_URL` or on localhost.
+/// Run with `cargo test -- --ignored` while Redis is up.
+#[tokio::test]
+#[ignore = "needs a Redis server"]
+async fn relays_between_instances_over_redis_once() {
+    let redis_url =
+        std::env::var("REDIS_URL").unwrap_or_else(|_| String::from("redis://127.0.0.1"));
+    let channel = format!("isimud-test-{}", std::process::id());
+    let envs = [
+        ("PASSWORD", "secret"),
+        ("REDIS_URL", &redis_url),
+        ("REDIS_CHANNEL", &channel),
+    ];
+    let first = Server::start(&envs).await;
+    let second = Server::start(&envs).await;
+    // Gives both instances time to subscribe to the Redis channel.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let mut local = common::subscribe(&first, json!({ "topic": "greetings" })).await;
+    let mut remote = common::subscribe(&second, json!({ "topic": "greetings" })).await;
+
+    let msg = json!({ "topic": "greetings", "data": "hello" });
+    let response = common::publish(&first, "p", "secret", msg).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(common::next_message(&mut local).await["data"], "hello");
+    assert_eq!(common::next_message(&mut remote).await["data"], "hello");
+
+    // Neither the publishing instance nor the other one delivers the message
+    // again when it comes back from Redis.
+    for socket in [&mut local, &mut remote] {
+        let next = tokio::time::timeout(Duration::from_millis(500), socket.next()).await;
+        assert!(next.is_err(), "delivered twice: {next:?}");
+    }
+}
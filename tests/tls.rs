@@ -0,0 +1,129 @@
+mod common;
+
+use std::sync::Arc;
+
+use common::Server;
+use futures::SinkExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_rustls::{client::TlsStream, rustls, TlsConnector};
+use tokio_tungstenite::tungstenite::Message;
+
+/// A self-signed certificate for `localhost`, made with `openssl req -x509
+/// -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -days 36500 -subj
+/// /CN=localhost -addext subjectAltName=DNS:localhost,IP:127.0.0.1 -addext
+/// basicConstraints=critical,CA:FALSE`.
+const CERT_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/localhost.crt");
+const KEY_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/localhost.key");
+
+/// A client trusting only the certificate at `CERT_PATH`, offering `alpn`.
+fn connector(alpn: &[&[u8]]) -> TlsConnector {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut std::fs::read(CERT_PATH).unwrap().as_slice()).unwrap() {
+        roots.add(&rustls::Certificate(cert)).unwrap();
+    }
+    let mut config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    TlsConnector::from(Arc::new(config))
+}
+
+async fn connect(server: &Server) -> TlsStream<tokio::net::TcpStream> {
+    connect_with_alpn(server, &[]).await
+}
+
+async fn connect_with_alpn(server: &Server, alpn: &[&[u8]]) -> TlsStream<tokio::net::TcpStream> {
+    let tcp = tokio::net::TcpStream::connect(server.addr).await.unwrap();
+    let server_name = rustls::ServerName::try_from("localhost").unwrap();
+    connector(alpn).connect(server_name, tcp).await.unwrap()
+}
+
+/// Sends `request` over a connection of its own and returns the response.
+async fn request(server: &Server, request: &str) -> String {
+    let mut stream = connect(server).await;
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn serves_http_and_websockets_over_tls() {
+    let server = Server::start(&[
+        ("PASSWORD", "secret"),
+        ("TLS_CERT_PATH", CERT_PATH),
+        ("TLS_KEY_PATH", KEY_PATH),
+    ])
+    .await;
+
+    let response = request(
+        &server,
+        "GET /health HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+    let (mut socket, _) =
+        tokio_tungstenite::client_async("wss://localhost/sub", connect(&server).await)
+            .await
+            .unwrap();
+    socket
+        .send(Message::Text(r#"{"topic": "greetings"}"#.into()))
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    let body = r#"{"topic": "greetings", "data": "hello"}"#;
+    let response = request(
+        &server,
+        &format!(
+            "POST /pub HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\
+             authorization: Basic cDpzZWNyZXQ=\r\ncontent-type: application/json\r\n\
+             content-length: {}\r\n\r\n{body}",
+            body.len(),
+        ),
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert_eq!(common::next_message(&mut socket).await["data"], "hello");
+}
+
+#[tokio::test]
+async fn negotiates_http2_with_alpn() {
+    let server = Server::start(&[
+        ("PASSWORD", "secret"),
+        ("TLS_CERT_PATH", CERT_PATH),
+        ("TLS_KEY_PATH", KEY_PATH),
+    ])
+    .await;
+    let (mut socket, _) =
+        tokio_tungstenite::client_async("wss://localhost/sub", connect(&server).await)
+            .await
+            .unwrap();
+    socket
+        .send(Message::Text(r#"{"topic": "greetings"}"#.into()))
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    let stream = connect_with_alpn(&server, &[b"h2", b"http/1.1"]).await;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+    let (mut sender, connection) = hyper::client::conn::Builder::new()
+        .http2_only(true)
+        .handshake(stream)
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+    let request = hyper::Request::post("https://localhost/pub")
+        .header("authorization", "Basic cDpzZWNyZXQ=")
+        .header("content-type", "application/json")
+        .body(hyper::Body::from(
+            r#"{"topic": "greetings", "data": "hello"}"#,
+        ))
+        .unwrap();
+    let response = sender.send_request(request).await.unwrap();
+    assert_eq!(response.version(), hyper::Version::HTTP_2);
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+    assert_eq!(common::next_message(&mut socket).await["data"], "hello");
+}
@@ -0,0 +1,94 @@
+mod common;
+
+use std::{collections::HashMap, time::Duration};
+
+use common::Server;
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+/// Answers requests to `/ok` with 200, to `/reject` with 400 and to `/flaky`
+/// with 503 the first time and 200 after, reporting the path and body of each.
+async fn receive(listener: TcpListener, hits: mpsc::UnboundedSender<(String, String)>) {
+    let mut attempts = HashMap::<String, usize>::new();
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        let mut request_line = String::new();
+        stream.read_line(&mut request_line).await.unwrap();
+        let path = request_line.split(' ').nth(1).unwrap().to_string();
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        stream.read_exact(&mut body).await.unwrap();
+        let attempt = attempts.entry(path.clone()).or_default();
+        *attempt += 1;
+        let status = match (path.as_str(), *attempt) {
+            ("/ok", _) => "200 OK",
+            ("/flaky", 1) => "503 Service Unavailable",
+            ("/flaky", _) => "200 OK",
+            _ => "400 Bad Request",
+        };
+        hits.send((path, String::from_utf8(body).unwrap())).unwrap();
+        let response =
+            format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+        stream.write_all(response.as_bytes()).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn posts_to_webhooks_retrying_only_server_errors() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let receiver = listener.local_addr().unwrap();
+    let (hits_tx, mut hits) = mpsc::unbounded_channel();
+    tokio::spawn(receive(listener, hits_tx));
+
+    let webhooks = ["ok", "flaky", "reject"].map(|name| {
+        json!({ "topic": format!("hooks/{name}"), "url": format!("http://{receiver}/{name}") })
+    });
+    let webhooks_path =
+        std::env::temp_dir().join(format!("isimud-webhooks-{}.json", std::process::id()));
+    std::fs::write(&webhooks_path, json!(webhooks).to_string()).unwrap();
+    let server = Server::start(&[
+        ("PASSWORD", "secret"),
+        ("WEBHOOKS_PATH", webhooks_path.to_str().unwrap()),
+    ])
+    .await;
+    for name in ["ok", "flaky", "reject"] {
+        let msg = json!({ "topic": format!("hooks/{name}"), "data": name });
+        let response = common::publish(&server, "p", "secret", msg).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    // `/flaky` is retried a second after its first attempt, which leaves
+    // plenty of time for a retry of `/reject` to show up, were there one.
+    let mut received = HashMap::<String, Vec<String>>::new();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(3);
+    while let Ok(Some((path, body))) = tokio::time::timeout_at(deadline, hits.recv()).await {
+        received.entry(path).or_default().push(body);
+    }
+    std::fs::remove_file(&webhooks_path).unwrap();
+    let count = |path: &str| received.get(path).map_or(0, Vec::len);
+    assert_eq!(count("/ok"), 1);
+    assert_eq!(count("/flaky"), 2);
+    assert_eq!(count("/reject"), 1);
+    let envelope: serde_json::Value = serde_json::from_str(&received["/ok"][0]).unwrap();
+    assert_eq!(envelope["publisher"], "p");
+    assert_eq!(envelope["topic"], "hooks/ok");
+    assert_eq!(envelope["data"], "ok");
+}